@@ -0,0 +1,421 @@
+use std::any::Any;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+use smoltcp::phy;
+use smoltcp::phy::{Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::Result;
+
+use batch::FrameBuf;
+use capture::Capture;
+use device_pair::{self, PairedDevice};
+use health::to_smoltcp_error;
+use impair::Impaired;
+#[cfg(feature = "netmap")]
+use netmap::{self, Netmap};
+use pcap_device::{self, PcapDevice};
+use raw_socket::{self, RawSocket};
+use tap_interface::{self, TapInterface};
+use unixdomainsocket::{self, UnixDomainSocket};
+use wait::wait;
+#[cfg(feature = "xdp")]
+use xdp::{self, XdpSocket};
+use {DeviceState, Stats};
+
+/// Operations which all devices support besides smoltcp's `Device` trait.
+pub trait DeviceOps: AsRawFd {
+    /// The maximum transmission unit as reported in the capabilities.
+    fn mtu(&self) -> usize;
+    /// Makes frames sent with the TX tokens visible to the peer, a no-op for
+    /// devices which do that on each send.
+    fn tx_flush(&mut self) -> Result<()>;
+    fn stats(&self) -> Stats;
+    fn state(&self) -> DeviceState;
+    fn last_error(&self) -> Option<io::Error>;
+    fn clear_error(&mut self);
+
+    /// Whether a frame can be received without waiting for the file
+    /// descriptor, e.g. one staged by an earlier batch.
+    fn rx_pending(&self) -> bool {
+        false
+    }
+
+    /// Called by `wait` before it polls the file descriptor.
+    #[doc(hidden)]
+    fn prepare_poll(&mut self) {}
+
+    /// Blocks until a frame can be received or `timeout` expired, and returns
+    /// whether the device became ready, see the function `wait` for waiting
+    /// on several devices.
+    fn wait(&mut self, timeout: Option<Duration>) -> io::Result<bool>
+    where
+        Self: Sized,
+    {
+        let mut devices: [&mut dyn DeviceOps; 1] = [self];
+        Ok(!wait(&mut devices, timeout)?.is_empty())
+    }
+}
+
+macro_rules! impl_device_ops {
+    ($device:ty, $dev:ident => $flush:expr $(; $($extra:tt)*)?) => {
+        impl DeviceOps for $device {
+            fn mtu(&self) -> usize {
+                self.capabilities().max_transmission_unit
+            }
+
+            fn tx_flush(&mut self) -> Result<()> {
+                let $dev = self;
+                $flush
+            }
+
+            fn stats(&self) -> Stats {
+                <$device>::stats(self)
+            }
+
+            fn state(&self) -> DeviceState {
+                <$device>::state(self)
+            }
+
+            fn last_error(&self) -> Option<io::Error> {
+                <$device>::last_error(self)
+            }
+
+            fn clear_error(&mut self) {
+                <$device>::clear_error(self)
+            }
+
+            $($($extra)*)?
+        }
+    };
+}
+
+#[cfg(feature = "netmap")]
+impl_device_ops!(Netmap, dev => Netmap::tx_flush(dev);
+    fn rx_pending(&self) -> bool {
+        Netmap::rx_pending(self)
+    }
+
+    fn prepare_poll(&mut self) {
+        Netmap::prepare_poll(self)
+    }
+);
+#[cfg(feature = "xdp")]
+impl_device_ops!(XdpSocket, dev => XdpSocket::tx_flush(dev);
+    fn prepare_poll(&mut self) {
+        XdpSocket::prepare_poll(self)
+    }
+);
+impl_device_ops!(PcapDevice, dev => dev.flush().map_err(|err| to_smoltcp_error(&err)));
+impl_device_ops!(TapInterface, _dev => Ok(()));
+impl_device_ops!(RawSocket, dev => RawSocket::tx_flush(dev);
+    fn rx_pending(&self) -> bool {
+        RawSocket::rx_pending(self)
+    }
+);
+impl_device_ops!(UnixDomainSocket, dev => UnixDomainSocket::tx_flush(dev);
+    fn rx_pending(&self) -> bool {
+        UnixDomainSocket::rx_pending(self)
+    }
+);
+impl_device_ops!(PairedDevice, _dev => Ok(());
+    fn rx_pending(&self) -> bool {
+        PairedDevice::rx_pending(self)
+    }
+);
+
+impl<D> DeviceOps for Capture<D>
+where
+    D: DeviceOps,
+{
+    fn mtu(&self) -> usize {
+        self.inner().mtu()
+    }
+
+    fn tx_flush(&mut self) -> Result<()> {
+        self.inner_mut().tx_flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.inner().stats()
+    }
+
+    fn state(&self) -> DeviceState {
+        self.inner().state()
+    }
+
+    fn last_error(&self) -> Option<io::Error> {
+        self.inner().last_error()
+    }
+
+    fn clear_error(&mut self) {
+        self.inner_mut().clear_error()
+    }
+
+    fn rx_pending(&self) -> bool {
+        self.inner().rx_pending()
+    }
+
+    fn prepare_poll(&mut self) {
+        self.inner_mut().prepare_poll()
+    }
+}
+
+impl<D> DeviceOps for Impaired<D>
+where
+    D: DeviceOps + for<'a> Device<'a>,
+{
+    fn mtu(&self) -> usize {
+        self.inner().mtu()
+    }
+
+    /// Passes the due delayed frames to the wrapped device before flushing it.
+    fn tx_flush(&mut self) -> Result<()> {
+        self.flush();
+        self.inner_mut().tx_flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.inner().stats()
+    }
+
+    fn state(&self) -> DeviceState {
+        self.inner().state()
+    }
+
+    fn last_error(&self) -> Option<io::Error> {
+        self.inner().last_error()
+    }
+
+    fn clear_error(&mut self) {
+        self.inner_mut().clear_error()
+    }
+
+    fn rx_pending(&self) -> bool {
+        self.inner().rx_pending()
+    }
+
+    fn prepare_poll(&mut self) {
+        self.inner_mut().prepare_poll()
+    }
+}
+
+/// Any of the devices of this crate, e.g. for choosing the backend at runtime.
+#[derive(Debug)]
+pub enum AnyDevice {
+    #[cfg(feature = "netmap")]
+    Netmap(Netmap),
+    Tap(TapInterface),
+    Raw(RawSocket),
+    Uds(UnixDomainSocket),
+    #[cfg(feature = "xdp")]
+    Xdp(XdpSocket),
+    Pcap(PcapDevice),
+    Pair(PairedDevice),
+}
+
+/// Matches all variants of `AnyDevice` or its tokens, binding the inner value.
+macro_rules! dispatch {
+    ($kind:ident, $value:expr, $inner:ident => $body:expr) => {
+        match $value {
+            #[cfg(feature = "netmap")]
+            $kind::Netmap($inner) => $body,
+            $kind::Tap($inner) => $body,
+            $kind::Raw($inner) => $body,
+            $kind::Uds($inner) => $body,
+            #[cfg(feature = "xdp")]
+            $kind::Xdp($inner) => $body,
+            $kind::Pcap($inner) => $body,
+            $kind::Pair($inner) => $body,
+        }
+    };
+}
+
+macro_rules! impl_from {
+    ($kind:ident, $variant:ident, $inner:ty) => {
+        impl From<$inner> for $kind {
+            fn from(inner: $inner) -> $kind {
+                $kind::$variant(inner)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "netmap")]
+impl_from!(AnyDevice, Netmap, Netmap);
+impl_from!(AnyDevice, Tap, TapInterface);
+impl_from!(AnyDevice, Raw, RawSocket);
+impl_from!(AnyDevice, Uds, UnixDomainSocket);
+#[cfg(feature = "xdp")]
+impl_from!(AnyDevice, Xdp, XdpSocket);
+impl_from!(AnyDevice, Pcap, PcapDevice);
+impl_from!(AnyDevice, Pair, PairedDevice);
+
+impl AnyDevice {
+    /// Receives up to `bufs.len()` frames with the batch API of the device,
+    /// which a `PcapDevice` does not have.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        match *self {
+            #[cfg(feature = "netmap")]
+            AnyDevice::Netmap(ref mut dev) => dev.recv_batch(bufs),
+            AnyDevice::Tap(ref mut dev) => dev.recv_batch(bufs),
+            AnyDevice::Raw(ref mut dev) => dev.recv_batch(bufs),
+            AnyDevice::Uds(ref mut dev) => dev.recv_batch(bufs),
+            #[cfg(feature = "xdp")]
+            AnyDevice::Xdp(ref mut dev) => dev.recv_batch(bufs),
+            AnyDevice::Pcap(_) => Err(no_batches()),
+            AnyDevice::Pair(ref mut dev) => dev.recv_batch(bufs),
+        }
+    }
+
+    /// Sends the frames with the batch API of the device and returns how many
+    /// of them were sent.
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        match *self {
+            #[cfg(feature = "netmap")]
+            AnyDevice::Netmap(ref mut dev) => dev.send_batch(frames),
+            AnyDevice::Tap(ref mut dev) => dev.send_batch(frames),
+            AnyDevice::Raw(ref mut dev) => dev.send_batch(frames),
+            AnyDevice::Uds(ref mut dev) => dev.send_batch(frames),
+            #[cfg(feature = "xdp")]
+            AnyDevice::Xdp(ref mut dev) => dev.send_batch(frames),
+            AnyDevice::Pcap(_) => Err(no_batches()),
+            AnyDevice::Pair(ref mut dev) => dev.send_batch(frames),
+        }
+    }
+}
+
+impl AnyDevice {
+    /// The device inside, e.g. for downcasting it to its type.
+    pub(crate) fn inner_mut(&mut self) -> &mut dyn Any {
+        dispatch!(AnyDevice, self, dev => dev as &mut dyn Any)
+    }
+}
+
+fn no_batches() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "pcap devices have no batch API")
+}
+
+impl AsRawFd for AnyDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        dispatch!(AnyDevice, self, dev => dev.as_raw_fd())
+    }
+}
+
+impl DeviceOps for AnyDevice {
+    fn mtu(&self) -> usize {
+        dispatch!(AnyDevice, self, dev => dev.mtu())
+    }
+
+    fn tx_flush(&mut self) -> Result<()> {
+        dispatch!(AnyDevice, self, dev => dev.tx_flush())
+    }
+
+    fn stats(&self) -> Stats {
+        dispatch!(AnyDevice, self, dev => DeviceOps::stats(dev))
+    }
+
+    fn state(&self) -> DeviceState {
+        dispatch!(AnyDevice, self, dev => DeviceOps::state(dev))
+    }
+
+    fn last_error(&self) -> Option<io::Error> {
+        dispatch!(AnyDevice, self, dev => DeviceOps::last_error(dev))
+    }
+
+    fn clear_error(&mut self) {
+        dispatch!(AnyDevice, self, dev => DeviceOps::clear_error(dev))
+    }
+
+    fn rx_pending(&self) -> bool {
+        dispatch!(AnyDevice, self, dev => DeviceOps::rx_pending(dev))
+    }
+
+    fn prepare_poll(&mut self) {
+        dispatch!(AnyDevice, self, dev => DeviceOps::prepare_poll(dev))
+    }
+}
+
+impl<'a> Device<'a> for AnyDevice {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        dispatch!(AnyDevice, self, dev => dev.capabilities())
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        dispatch!(AnyDevice, self, dev => dev
+            .receive()
+            .map(|(rx, tx)| (rx.into(), tx.into())))
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        dispatch!(AnyDevice, self, dev => dev.transmit().map(|tx| tx.into()))
+    }
+}
+
+#[doc(hidden)]
+pub enum RxToken {
+    #[cfg(feature = "netmap")]
+    Netmap(netmap::RxToken),
+    Tap(tap_interface::RxToken),
+    Raw(raw_socket::RxToken),
+    Uds(unixdomainsocket::RxToken),
+    #[cfg(feature = "xdp")]
+    Xdp(xdp::RxToken),
+    Pcap(pcap_device::RxToken),
+    Pair(device_pair::RxToken),
+}
+
+#[cfg(feature = "netmap")]
+impl_from!(RxToken, Netmap, netmap::RxToken);
+impl_from!(RxToken, Tap, tap_interface::RxToken);
+impl_from!(RxToken, Raw, raw_socket::RxToken);
+impl_from!(RxToken, Uds, unixdomainsocket::RxToken);
+#[cfg(feature = "xdp")]
+impl_from!(RxToken, Xdp, xdp::RxToken);
+impl_from!(RxToken, Pcap, pcap_device::RxToken);
+impl_from!(RxToken, Pair, device_pair::RxToken);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        dispatch!(RxToken, self, token => token.consume(timestamp, f))
+    }
+}
+
+#[doc(hidden)]
+pub enum TxToken {
+    #[cfg(feature = "netmap")]
+    Netmap(netmap::TxToken),
+    Tap(tap_interface::TxToken),
+    Raw(raw_socket::TxToken),
+    Uds(unixdomainsocket::TxToken),
+    #[cfg(feature = "xdp")]
+    Xdp(xdp::TxToken),
+    Pcap(pcap_device::TxToken),
+    Pair(device_pair::TxToken),
+}
+
+#[cfg(feature = "netmap")]
+impl_from!(TxToken, Netmap, netmap::TxToken);
+impl_from!(TxToken, Tap, tap_interface::TxToken);
+impl_from!(TxToken, Raw, raw_socket::TxToken);
+impl_from!(TxToken, Uds, unixdomainsocket::TxToken);
+#[cfg(feature = "xdp")]
+impl_from!(TxToken, Xdp, xdp::TxToken);
+impl_from!(TxToken, Pcap, pcap_device::TxToken);
+impl_from!(TxToken, Pair, device_pair::TxToken);
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        dispatch!(TxToken, self, token => token.consume(timestamp, len, f))
+    }
+}
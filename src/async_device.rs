@@ -0,0 +1,266 @@
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use smoltcp::phy;
+use smoltcp::phy::{Device, DeviceCapabilities};
+use smoltcp::Result;
+use tokio::io::unix::AsyncFd;
+
+use any_device::DeviceOps;
+use {DeviceState, Stats};
+
+/// Registers the file descriptor of a device with the reactor of the tokio
+/// runtime, so that a stack can wait for frames without a blocking thread.
+///
+/// The device is used through the `Device` implementation as before, e.g. by
+/// calling `Interface::poll` after `readable` resolved, or through
+/// `receive_async` and `transmit_async`. The readiness of the file descriptor
+/// is only cleared when the device had no frame to receive or no room to
+/// transmit, thus frames left over after a poll are not missed.
+/// The eventfd of a `DevicePair` only signals readability, a full queue of
+/// the peer does not wake up `writable`.
+#[derive(Debug)]
+pub struct AsyncDevice<D: AsRawFd> {
+    inner: AsyncFd<D>,
+}
+
+impl<D: AsRawFd> AsRawFd for AsyncDevice<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.get_ref().as_raw_fd()
+    }
+}
+
+impl<D: AsRawFd> AsyncDevice<D> {
+    /// Registers `device` with the current tokio runtime, which fails outside of
+    /// a runtime and for devices without a pollable file descriptor, i.e.
+    /// a `PcapDevice` or a `DevicePair` without eventfd.
+    pub fn new(device: D) -> io::Result<AsyncDevice<D>> {
+        Ok(AsyncDevice {
+            inner: AsyncFd::new(device)?,
+        })
+    }
+
+    pub fn get_ref(&self) -> &D {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut D {
+        self.inner.get_mut()
+    }
+
+    /// Deregisters the device from the runtime and returns it.
+    pub fn into_inner(self) -> D {
+        self.inner.into_inner()
+    }
+
+    /// Waits until a frame may be received.
+    pub fn readable(&mut self) -> Readable<'_, D> {
+        Readable { device: self }
+    }
+
+    /// Waits until a frame may be transmitted.
+    pub fn writable(&mut self) -> Writable<'_, D> {
+        Writable { device: self }
+    }
+
+    pub fn poll_readable(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.inner.poll_read_ready(cx).map_ok(|_| ())
+    }
+
+    pub fn poll_writable(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.inner.poll_write_ready(cx).map_ok(|_| ())
+    }
+
+    /// Waits for a frame and returns its tokens like `Device::receive`.
+    pub fn receive_async(&mut self) -> Receive<'_, D> {
+        Receive { device: self }
+    }
+
+    /// Waits until a frame can be sent and returns the token like `Device::transmit`.
+    pub fn transmit_async(&mut self) -> Transmit<'_, D> {
+        Transmit { device: self }
+    }
+}
+
+fn noop_raw_waker() -> RawWaker {
+    fn clone(_: *const ()) -> RawWaker {
+        noop_raw_waker()
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    RawWaker::new(ptr::null(), &VTABLE)
+}
+
+/// Runs `f` on the device and clears the readiness if it returns `None`.
+///
+/// The readiness is taken before `f` runs, so that a notification arriving
+/// in between is kept. The waker is not needed because a pending readiness
+/// only means that the device is used without waiting, as in a busy loop.
+fn with_readiness<D, T, F>(inner: &mut AsyncFd<D>, write: bool, f: F) -> Option<T>
+where
+    D: AsRawFd,
+    F: FnOnce(&mut D) -> Option<T>,
+{
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut cx = Context::from_waker(&waker);
+    let ready = if write {
+        inner.poll_write_ready_mut(&mut cx)
+    } else {
+        inner.poll_read_ready_mut(&mut cx)
+    };
+    match ready {
+        Poll::Ready(Ok(mut guard)) => {
+            let result = f(guard.get_inner_mut());
+            if result.is_none() {
+                guard.clear_ready();
+            }
+            result
+        }
+        _ => f(inner.get_mut()),
+    }
+}
+
+impl<'a, D, R, T> Device<'a> for AsyncDevice<D>
+where
+    D: AsRawFd + for<'b> Device<'b, RxToken = R, TxToken = T>,
+    R: phy::RxToken + 'a,
+    T: phy::TxToken + 'a,
+{
+    type RxToken = R;
+    type TxToken = T;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.get_ref().capabilities()
+    }
+
+    fn receive(&'a mut self) -> Option<(R, T)> {
+        with_readiness(&mut self.inner, false, |device| device.receive())
+    }
+
+    fn transmit(&'a mut self) -> Option<T> {
+        with_readiness(&mut self.inner, true, |device| device.transmit())
+    }
+}
+
+impl<D: DeviceOps> DeviceOps for AsyncDevice<D> {
+    fn mtu(&self) -> usize {
+        self.get_ref().mtu()
+    }
+
+    fn tx_flush(&mut self) -> Result<()> {
+        self.get_mut().tx_flush()
+    }
+
+    fn stats(&self) -> Stats {
+        self.get_ref().stats()
+    }
+
+    fn state(&self) -> DeviceState {
+        self.get_ref().state()
+    }
+
+    fn last_error(&self) -> Option<io::Error> {
+        self.get_ref().last_error()
+    }
+
+    fn clear_error(&mut self) {
+        self.get_mut().clear_error()
+    }
+
+    fn rx_pending(&self) -> bool {
+        self.get_ref().rx_pending()
+    }
+
+    fn prepare_poll(&mut self) {
+        self.get_mut().prepare_poll()
+    }
+}
+
+/// Future of `AsyncDevice::readable`.
+#[derive(Debug)]
+pub struct Readable<'a, D: AsRawFd + 'a> {
+    device: &'a mut AsyncDevice<D>,
+}
+
+impl<'a, D: AsRawFd> Future for Readable<'a, D> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.device.poll_readable(cx)
+    }
+}
+
+/// Future of `AsyncDevice::writable`.
+#[derive(Debug)]
+pub struct Writable<'a, D: AsRawFd + 'a> {
+    device: &'a mut AsyncDevice<D>,
+}
+
+impl<'a, D: AsRawFd> Future for Writable<'a, D> {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.device.poll_writable(cx)
+    }
+}
+
+/// Future of `AsyncDevice::receive_async`, available for all devices of this crate
+/// because their tokens do not borrow the device.
+#[derive(Debug)]
+pub struct Receive<'a, D: AsRawFd + 'a> {
+    device: &'a mut AsyncDevice<D>,
+}
+
+impl<'a, D, R, T> Future for Receive<'a, D>
+where
+    D: AsRawFd + for<'b> Device<'b, RxToken = R, TxToken = T>,
+    R: phy::RxToken,
+    T: phy::TxToken,
+{
+    type Output = io::Result<(R, T)>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<(R, T)>> {
+        loop {
+            match self.device.poll_readable(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+            if let Some(tokens) = Device::receive(&mut *self.device) {
+                return Poll::Ready(Ok(tokens));
+            }
+        }
+    }
+}
+
+/// Future of `AsyncDevice::transmit_async`.
+#[derive(Debug)]
+pub struct Transmit<'a, D: AsRawFd + 'a> {
+    device: &'a mut AsyncDevice<D>,
+}
+
+impl<'a, D, R, T> Future for Transmit<'a, D>
+where
+    D: AsRawFd + for<'b> Device<'b, RxToken = R, TxToken = T>,
+    R: phy::RxToken,
+    T: phy::TxToken,
+{
+    type Output = io::Result<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<T>> {
+        loop {
+            match self.device.poll_writable(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+            if let Some(token) = Device::transmit(&mut *self.device) {
+                return Poll::Ready(Ok(token));
+            }
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use libc;
+use std::collections::VecDeque;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use health::is_backpressure;
+use pool::BufferPool;
+use stats::Stats;
+
+/// Most frames passed to one `recvmmsg` or `sendmmsg` call, `recv_mmsg` and
+/// `send_mmsg` leave the rest of a larger batch to the next call.
+const MAX_MMSG: usize = 64;
+
+/// A reusable frame buffer for the batch APIs of the devices.
+///
+/// The capacity is the size of the allocated buffer, and the length is the
+/// size of the frame it currently holds. Buffers taken from a `BufferPool`
+/// return to it when dropped.
+#[derive(Debug, Clone, Default)]
+pub struct FrameBuf {
+    buffer: Vec<u8>,
+    len: usize,
+    pool: Option<BufferPool>,
+}
+
+impl PartialEq for FrameBuf {
+    fn eq(&self, other: &FrameBuf) -> bool {
+        self.as_slice() == other.as_slice()
+    }
+}
+
+impl Eq for FrameBuf {}
+
+impl Drop for FrameBuf {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.put(mem::take(&mut self.buffer));
+        }
+    }
+}
+
+impl FrameBuf {
+    /// Creates an empty frame buffer which can receive frames up to `capacity` bytes.
+    pub fn with_capacity(capacity: usize) -> FrameBuf {
+        FrameBuf {
+            buffer: vec![0; capacity],
+            len: 0,
+            pool: None,
+        }
+    }
+
+    /// Creates a frame buffer holding a copy of `frame`.
+    pub fn from_frame(frame: &[u8]) -> FrameBuf {
+        FrameBuf {
+            buffer: frame.to_vec(),
+            len: frame.len(),
+            pool: None,
+        }
+    }
+
+    pub(crate) fn pooled(buffer: Vec<u8>, pool: BufferPool) -> FrameBuf {
+        FrameBuf {
+            buffer,
+            len: 0,
+            pool: Some(pool),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Sets the length of the frame, e.g. after writing it with `buffer_mut`.
+    ///
+    /// Panics if `len` exceeds the capacity.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= self.capacity());
+        self.len = len;
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.buffer[..self.len]
+    }
+
+    /// The whole buffer regardless of the current frame length.
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer[..]
+    }
+
+    /// Copies `frame` into the buffer, which grows if needed.
+    pub fn copy_from(&mut self, frame: &[u8]) {
+        if self.buffer.len() < frame.len() {
+            self.buffer.resize(frame.len(), 0);
+        }
+        self.buffer[..frame.len()].copy_from_slice(frame);
+        self.len = frame.len();
+    }
+
+    /// Takes the frame out of the buffer, which then does not return to its pool.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        let mut buffer = mem::take(&mut self.buffer);
+        buffer.truncate(self.len);
+        buffer
+    }
+}
+
+/// Receives up to `bufs.len()` but at most `MAX_MMSG` datagrams with one
+/// `recvmmsg` call and returns their number, each buffer is set to the length
+/// of its frame.
+pub fn recv_mmsg(fd: RawFd, bufs: &mut [FrameBuf], stats: &mut Stats) -> io::Result<usize> {
+    // on the stack, so that a batch does not allocate
+    let mut iovecs: [libc::iovec; MAX_MMSG] = unsafe { mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; MAX_MMSG] = unsafe { mem::zeroed() };
+    let mut count = 0;
+    for ((buf, iov), msg) in bufs.iter_mut().zip(iovecs.iter_mut()).zip(msgs.iter_mut()) {
+        iov.iov_base = buf.buffer.as_mut_ptr() as *mut libc::c_void;
+        iov.iov_len = buf.buffer.len();
+        msg.msg_hdr.msg_iov = iov;
+        msg.msg_hdr.msg_iovlen = 1;
+        count += 1;
+    }
+    let res = unsafe {
+        libc::recvmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            count as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let received = res as usize;
+    for (buf, msg) in bufs.iter_mut().zip(msgs.iter()).take(received) {
+        if msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+            stats.rx_truncated += 1;
+        }
+        buf.len = msg.msg_len as usize;
+        stats.rx(buf.len);
+    }
+    Ok(received)
+}
+
+/// Sends up to `MAX_MMSG` of the frames with one `sendmmsg` call and returns
+/// how many were sent.
+pub fn send_mmsg(fd: RawFd, frames: &[FrameBuf], stats: &mut Stats) -> io::Result<usize> {
+    if frames.is_empty() {
+        return Ok(0);
+    }
+    let mut iovecs: [libc::iovec; MAX_MMSG] = unsafe { mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; MAX_MMSG] = unsafe { mem::zeroed() };
+    let mut count = 0;
+    for ((frame, iov), msg) in frames.iter().zip(iovecs.iter_mut()).zip(msgs.iter_mut()) {
+        iov.iov_base = frame.buffer.as_ptr() as *mut libc::c_void;
+        iov.iov_len = frame.len;
+        msg.msg_hdr.msg_iov = iov;
+        msg.msg_hdr.msg_iovlen = 1;
+        count += 1;
+    }
+    let res = unsafe {
+        libc::sendmmsg(
+            fd,
+            msgs.as_mut_ptr(),
+            count as libc::c_uint,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let sent = res as usize;
+    for frame in &frames[..sent] {
+        stats.tx(frame.len);
+    }
+    Ok(sent)
+}
+
+/// Receives frames one by one with `recv` until `bufs` is full or no frame
+/// is left, for backends without a batch syscall. An error is only returned
+/// if no frame was received.
+pub fn recv_each<F>(bufs: &mut [FrameBuf], mut recv: F) -> io::Result<usize>
+where
+    F: FnMut(&mut FrameBuf) -> io::Result<()>,
+{
+    let mut received = 0;
+    for buf in bufs.iter_mut() {
+        match recv(buf) {
+            Ok(()) => received += 1,
+            Err(err) => {
+                if received == 0 {
+                    return Err(err);
+                }
+                break;
+            }
+        }
+    }
+    Ok(received)
+}
+
+/// Sends the frames one by one with `send` until an error occurs, which is
+/// only returned if no frame was sent.
+pub fn send_each<F>(frames: &[FrameBuf], mut send: F) -> io::Result<usize>
+where
+    F: FnMut(&FrameBuf) -> io::Result<()>,
+{
+    let mut sent = 0;
+    for frame in frames {
+        match send(frame) {
+            Ok(()) => sent += 1,
+            Err(err) => {
+                if sent == 0 {
+                    return Err(err);
+                }
+                break;
+            }
+        }
+    }
+    Ok(sent)
+}
+
+/// Frames staged by the `Device` implementation of a socket based device, so
+/// that one `recvmmsg` or `sendmmsg` call serves several tokens.
+/// With batch sizes of 1 every token results in one syscall as before.
+#[derive(Debug)]
+pub struct Staging {
+    rx_batch: usize,
+    tx_batch: usize,
+    rx: VecDeque<FrameBuf>,
+    /// Empty buffers for the next refill of `rx`.
+    rx_bufs: Vec<FrameBuf>,
+    tx: Vec<FrameBuf>,
+}
+
+impl Default for Staging {
+    fn default() -> Staging {
+        Staging {
+            rx_batch: 1,
+            tx_batch: 1,
+            rx: VecDeque::new(),
+            rx_bufs: Vec::new(),
+            tx: Vec::new(),
+        }
+    }
+}
+
+impl Staging {
+    pub fn set_batch(&mut self, rx_batch: usize, tx_batch: usize) {
+        self.rx_batch = rx_batch.max(1);
+        self.tx_batch = tx_batch.max(1);
+    }
+
+    /// Returns the next received frame, refilling the queue with `recv_batch`
+    /// which receives into buffers of `mtu` bytes taken from `pool`.
+    pub fn recv<F>(&mut self, mtu: usize, pool: &BufferPool, recv_batch: F) -> io::Result<FrameBuf>
+    where
+        F: FnOnce(&mut [FrameBuf]) -> io::Result<usize>,
+    {
+        if let Some(frame) = self.rx.pop_front() {
+            return Ok(frame);
+        }
+        self.rx_bufs.truncate(self.rx_batch);
+        while self.rx_bufs.len() < self.rx_batch {
+            self.rx_bufs.push(pool.get(mtu));
+        }
+        let received = recv_batch(&mut self.rx_bufs[..])?;
+        self.rx.extend(self.rx_bufs.drain(..received));
+        self.rx
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "no frame received"))
+    }
+
+    /// Whether received frames are left from the last batch.
+    pub fn rx_staged(&self) -> bool {
+        !self.rx.is_empty()
+    }
+
+    /// Whether frames are staged for one `sendmmsg`, instead of sent by each token.
+    pub fn tx_batched(&self) -> bool {
+        self.tx_batch > 1
+    }
+
+    /// Whether `transmit` has to wait until the staged frames were sent.
+    pub fn tx_full(&self) -> bool {
+        self.tx.len() >= self.tx_batch
+    }
+
+    /// Stages `frame` and sends all staged frames once the TX batch is complete.
+    /// A frame which can not be sent because of backpressure stays staged.
+    pub fn send<F>(&mut self, frame: FrameBuf, send_batch: F) -> io::Result<()>
+    where
+        F: FnMut(&[FrameBuf]) -> io::Result<usize>,
+    {
+        self.tx.push(frame);
+        if self.tx_full() {
+            self.flush(send_batch)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends the staged frames until they are sent or an error occurs.
+    /// They are dropped on errors other than backpressure.
+    pub fn flush<F>(&mut self, mut send_batch: F) -> io::Result<()>
+    where
+        F: FnMut(&[FrameBuf]) -> io::Result<usize>,
+    {
+        while !self.tx.is_empty() {
+            match send_batch(&self.tx[..]) {
+                Ok(0) => break,
+                Ok(sent) => {
+                    self.tx.drain(..sent);
+                }
+                Err(err) => {
+                    if !is_backpressure(&err) {
+                        self.tx.clear();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+}
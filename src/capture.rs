@@ -0,0 +1,269 @@
+use std::io;
+#[cfg(any(feature = "netmap", feature = "xdp"))]
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use smoltcp::phy;
+use smoltcp::phy::{Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::Result;
+
+use pcapng::{Direction, InterfaceStatistics, PcapngSink};
+
+#[cfg(feature = "netmap")]
+use netmap::{nmreq, Netmap};
+#[cfg(feature = "xdp")]
+use xdp::XdpSocket;
+
+#[derive(Debug)]
+struct CaptureState {
+    sink: PcapngSink,
+    interface: u32,
+    stats: InterfaceStatistics,
+    dropped_since_last: u64,
+    /// The frame of a `zc_forward`, which has to be copied before the
+    /// forward and is only recorded after it succeeded.
+    #[cfg(any(feature = "netmap", feature = "xdp"))]
+    forwarded: Vec<u8>,
+}
+
+impl CaptureState {
+    fn record(&mut self, timestamp: Instant, direction: Direction, frame: &[u8]) {
+        if self.stats.start.is_none() {
+            self.stats.start = Some(timestamp);
+        }
+        if direction == Direction::Inbound {
+            self.stats.received += 1;
+        }
+        let res = self.sink.write_packet(
+            self.interface,
+            timestamp,
+            direction,
+            self.dropped_since_last,
+            frame,
+        );
+        match res {
+            Ok(_) => self.dropped_since_last = 0,
+            Err(_) => {
+                self.dropped_since_last += 1;
+                self.stats.dropped += 1;
+            }
+        }
+    }
+
+    #[cfg(any(feature = "netmap", feature = "xdp"))]
+    fn record_forwarded(&mut self) {
+        let frame = mem::take(&mut self.forwarded);
+        self.record(Instant::now(), Direction::Outbound, &frame);
+        self.forwarded = frame;
+    }
+}
+
+/// Records all frames of the wrapped device as a separate interface of a
+/// pcapng file, including frames sent with `zc_forward`.
+/// The file descriptor and the backend-specific operations remain available
+/// (see `inner` and `inner_mut` for those which are not forwarded).
+/// Errors of the capture never affect the device, frames which could not be
+/// written are counted as dropped instead.
+#[derive(Debug)]
+pub struct Capture<D> {
+    inner: D,
+    state: Arc<Mutex<CaptureState>>,
+}
+
+impl<D: AsRawFd> AsRawFd for Capture<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<D> Capture<D>
+where
+    D: for<'a> Device<'a>,
+{
+    /// Wraps `inner` and adds it with the interface name `name` to `sink`,
+    /// the link type follows from the medium of the device.
+    pub fn new(inner: D, sink: &PcapngSink, name: &str) -> io::Result<Capture<D>> {
+        let interface = sink.add_interface(name, inner.capabilities().medium)?;
+        Ok(Capture {
+            inner,
+            state: Arc::new(Mutex::new(CaptureState {
+                sink: sink.clone(),
+                interface,
+                stats: InterfaceStatistics::default(),
+                dropped_since_last: 0,
+                #[cfg(any(feature = "netmap", feature = "xdp"))]
+                forwarded: Vec::new(),
+            })),
+        })
+    }
+}
+
+impl<D> Capture<D> {
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    /// Returns the device after writing the statistics of the capture.
+    pub fn into_inner(self) -> D {
+        let _ = self.write_statistics();
+        self.inner
+    }
+
+    /// Writes an Interface Statistics Block with the number of received
+    /// frames and the number of frames missing in the capture.
+    pub fn write_statistics(&self) -> io::Result<()> {
+        let state = self.state.lock().unwrap();
+        state
+            .sink
+            .write_statistics(state.interface, Instant::now(), &state.stats)?;
+        state.sink.flush()
+    }
+}
+
+#[cfg(feature = "netmap")]
+impl Capture<Netmap> {
+    pub fn tx_flush(&mut self) -> Result<()> {
+        self.inner.tx_flush()
+    }
+
+    pub fn set_uses_wait(&mut self, uses_wait: bool) {
+        self.inner.set_uses_wait(uses_wait);
+    }
+
+    pub fn get_uses_wait(&self) -> bool {
+        self.inner.get_uses_wait()
+    }
+
+    pub fn get_nmreq(&self) -> nmreq {
+        self.inner.get_nmreq()
+    }
+
+    /// Forwards the frame last received on `from` like `Netmap::zc_forward`
+    /// and records it as sent on this interface.
+    pub fn zc_forward(&mut self, from: &mut Capture<Netmap>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.forwarded.clear();
+        from.inner
+            .with_last_rx(|frame| state.forwarded.extend_from_slice(frame));
+        self.inner.zc_forward(&mut from.inner)?;
+        state.record_forwarded();
+        Ok(())
+    }
+}
+
+#[cfg(feature = "xdp")]
+impl Capture<XdpSocket> {
+    pub fn tx_flush(&mut self) -> Result<()> {
+        self.inner.tx_flush()
+    }
+
+    pub fn set_uses_wait(&mut self, uses_wait: bool) {
+        self.inner.set_uses_wait(uses_wait);
+    }
+
+    pub fn get_uses_wait(&self) -> bool {
+        self.inner.get_uses_wait()
+    }
+
+    /// Forwards the frame last received on `from` like `XdpSocket::zc_forward`
+    /// and records it as sent on this interface.
+    pub fn zc_forward(&mut self, from: &mut Capture<XdpSocket>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.forwarded.clear();
+        from.inner
+            .with_last_rx(|frame| state.forwarded.extend_from_slice(frame));
+        self.inner.zc_forward(&mut from.inner)?;
+        state.record_forwarded();
+        Ok(())
+    }
+}
+
+impl<'a, D> Device<'a> for Capture<D>
+where
+    D: Device<'a>,
+{
+    type RxToken = RxToken<D::RxToken>;
+    type TxToken = TxToken<D::TxToken>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let state = &self.state;
+        self.inner.receive().map(|(rx, tx)| {
+            let rx = RxToken {
+                inner: rx,
+                state: state.clone(),
+            };
+            let tx = TxToken {
+                inner: tx,
+                state: state.clone(),
+            };
+            (rx, tx)
+        })
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        let state = &self.state;
+        self.inner.transmit().map(|tx| TxToken {
+            inner: tx,
+            state: state.clone(),
+        })
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken<T> {
+    inner: T,
+    state: Arc<Mutex<CaptureState>>,
+}
+
+impl<T: phy::RxToken> phy::RxToken for RxToken<T> {
+    fn consume<R, F>(self, timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let state = self.state;
+        self.inner.consume(timestamp, |buffer| {
+            state
+                .lock()
+                .unwrap()
+                .record(timestamp, Direction::Inbound, buffer);
+            f(buffer)
+        })
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken<T> {
+    inner: T,
+    state: Arc<Mutex<CaptureState>>,
+}
+
+impl<T: phy::TxToken> phy::TxToken for TxToken<T> {
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let state = self.state;
+        self.inner.consume(timestamp, len, |buffer| {
+            let result = f(buffer);
+            if result.is_ok() {
+                state
+                    .lock()
+                    .unwrap()
+                    .record(timestamp, Direction::Outbound, buffer);
+            }
+            result
+        })
+    }
+}
@@ -0,0 +1,290 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, RwLock};
+use std::vec::Vec;
+
+use batch::FrameBuf;
+use device_pair_sys;
+use health::to_smoltcp_error;
+use smoltcp::phy;
+use smoltcp::phy::{Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::Result;
+
+use {DeviceState, Stats, SMOLTCP_ETHERNET_HEADER};
+
+/// Two in-memory Ethernet devices connected to each other, e.g. for
+/// running a client and a server stack in one process without kernel devices.
+#[derive(Debug)]
+pub struct DevicePair {
+    pub a: PairedDevice,
+    pub b: PairedDevice,
+}
+
+impl DevicePair {
+    /// Creates both devices with the IP MTU `mtu`, each queueing up to
+    /// `queue_len` frames for the other.
+    ///
+    /// If `with_eventfd` is set, then `as_raw_fd` of each device returns an
+    /// eventfd which is readable while frames can be received, so that it can
+    /// be used with `poll` like the file descriptors of the other devices.
+    /// Otherwise `as_raw_fd` returns -1.
+    pub fn new(
+        mtu: usize,
+        queue_len: usize,
+        with_eventfd: bool,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<DevicePair> {
+        let (a, b) = device_pair_sys::DevicePairDesc::new_pair(queue_len, with_eventfd)?;
+        Ok(DevicePair {
+            a: PairedDevice::from_lower(a, mtu, reduce_mtu_by),
+            b: PairedDevice::from_lower(b, mtu, reduce_mtu_by),
+        })
+    }
+}
+
+/// One side of a `DevicePair`.
+#[derive(Debug)]
+pub struct PairedDevice {
+    lower: Arc<RwLock<device_pair_sys::DevicePairDesc>>,
+    mtu: usize,
+    reduce_mtu_by: Option<usize>,
+}
+
+impl AsRawFd for PairedDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.lower.read().unwrap().as_raw_fd()
+    }
+}
+
+impl PairedDevice {
+    fn from_lower(
+        lower: device_pair_sys::DevicePairDesc,
+        mtu: usize,
+        reduce_mtu_by: Option<usize>,
+    ) -> PairedDevice {
+        PairedDevice {
+            lower: Arc::new(RwLock::new(lower)),
+            mtu: mtu + SMOLTCP_ETHERNET_HEADER,
+            reduce_mtu_by,
+        }
+    }
+
+    /// Receives up to `bufs.len()` frames from the queue, each buffer is set to
+    /// the length of its frame.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        let mut lower = self.lower.write().unwrap();
+        lower.recv_batch(bufs).map_err(|err| {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                lower.stats_mut().rx_would_block += 1;
+            }
+            lower.health_mut().record(err)
+        })
+    }
+
+    /// Sends the frames and returns how many of them fit into the queue of the peer.
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        let mut lower = self.lower.write().unwrap();
+        lower.send_batch(frames).map_err(|err| {
+            lower.stats_mut().tx_error(&err);
+            lower.health_mut().record(err)
+        })
+    }
+
+    /// Returns the frame counters of the device.
+    pub fn stats(&self) -> Stats {
+        *self.lower.read().unwrap().stats()
+    }
+
+    /// Returns the health of the device, it is down once the peer was dropped.
+    pub fn state(&self) -> DeviceState {
+        self.lower.read().unwrap().health().state()
+    }
+
+    /// Returns the error which caused the current `DeviceState`.
+    pub fn last_error(&self) -> Option<io::Error> {
+        self.lower.read().unwrap().health().last_error()
+    }
+
+    /// Resets the state to `DeviceState::Up`.
+    pub fn clear_error(&mut self) {
+        self.lower.write().unwrap().health_mut().clear();
+    }
+
+    pub(crate) fn rx_pending(&self) -> bool {
+        self.lower.read().unwrap().rx_pending()
+    }
+}
+
+impl<'a> Device<'a> for PairedDevice {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = self.mtu - self.reduce_mtu_by.unwrap_or(0);
+        caps
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let mut lower = self.lower.write().unwrap();
+        match lower.recv() {
+            Ok(buffer) => {
+                let rx = RxToken { buffer };
+                let tx = TxToken {
+                    lower: self.lower.clone(),
+                };
+                Some((rx, tx))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                lower.stats_mut().rx_would_block += 1;
+                None
+            }
+            Err(err) => {
+                lower.health_mut().record(err);
+                None
+            }
+        }
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        let r = self.lower.read().unwrap().send_ready();
+        match r {
+            Ok(_) => Some(TxToken {
+                lower: self.lower.clone(),
+            }),
+            Err(err) => {
+                let mut lower = self.lower.write().unwrap();
+                lower.stats_mut().tx_error(&err);
+                lower.health_mut().record(err);
+                None
+            }
+        }
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken {
+    buffer: Vec<u8>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(mut self, _timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        f(&mut self.buffer[..])
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken {
+    lower: Arc<RwLock<device_pair_sys::DevicePairDesc>>,
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer)?;
+        let mut lower = self.lower.write().unwrap();
+        match lower.send(buffer) {
+            Ok(_) => Ok(result),
+            Err(err) => {
+                lower.stats_mut().tx_error(&err);
+                Err(to_smoltcp_error(&lower.health_mut().record(err)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+    use smoltcp::phy::{RxToken as _, TxToken as _};
+
+    fn send(device: &mut PairedDevice, frame: &[u8]) -> Option<Result<()>> {
+        device.transmit().map(|tx| {
+            tx.consume(Instant::from_millis(0), frame.len(), |buf| {
+                buf.copy_from_slice(frame);
+                Ok(())
+            })
+        })
+    }
+
+    fn recv(device: &mut PairedDevice) -> Option<Vec<u8>> {
+        device.receive().map(|(rx, _)| {
+            rx.consume(Instant::from_millis(0), |buf| Ok(buf.to_vec()))
+                .unwrap()
+        })
+    }
+
+    fn readable(fd: RawFd) -> bool {
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        assert!(unsafe { libc::poll(&mut pfd, 1, 0) } >= 0);
+        pfd.revents & libc::POLLIN != 0
+    }
+
+    #[test]
+    fn frames_cross_the_pair() {
+        let DevicePair { mut a, mut b } = DevicePair::new(1500, 2, false, None).unwrap();
+        assert_eq!(a.capabilities().max_transmission_unit, 1514);
+        assert_eq!(a.as_raw_fd(), -1);
+        assert_eq!(send(&mut a, &[1, 2, 3]), Some(Ok(())));
+        assert_eq!(send(&mut b, &[4]), Some(Ok(())));
+        assert_eq!(recv(&mut b), Some(vec![1, 2, 3]));
+        assert_eq!(recv(&mut b), None);
+        assert_eq!(recv(&mut a), Some(vec![4]));
+        assert_eq!(a.stats().tx_packets, 1);
+        assert_eq!(b.stats().rx_packets, 1);
+        assert_eq!(b.stats().rx_would_block, 1);
+    }
+
+    #[test]
+    fn full_queue_is_backpressure() {
+        let DevicePair { mut a, mut b } = DevicePair::new(1500, 1, false, None).unwrap();
+        assert_eq!(send(&mut a, &[1]), Some(Ok(())));
+        assert!(send(&mut a, &[2]).is_none());
+        assert_eq!(a.stats().tx_would_block, 1);
+        assert_eq!(a.state(), DeviceState::Up);
+        assert_eq!(recv(&mut b), Some(vec![1]));
+        assert_eq!(send(&mut a, &[2]), Some(Ok(())));
+    }
+
+    #[test]
+    fn dropped_peer_takes_the_device_down() {
+        let DevicePair { mut a, mut b } = DevicePair::new(1500, 2, false, None).unwrap();
+        assert_eq!(send(&mut b, &[1]), Some(Ok(())));
+        drop(b);
+        // frames which were sent before are still received
+        assert_eq!(recv(&mut a), Some(vec![1]));
+        assert!(send(&mut a, &[2]).is_none());
+        assert_eq!(a.state(), DeviceState::Down);
+        assert_eq!(a.last_error().unwrap().raw_os_error(), Some(libc::EPIPE));
+        a.clear_error();
+        assert_eq!(a.state(), DeviceState::Up);
+    }
+
+    #[test]
+    fn eventfd_is_readable_while_frames_are_queued() {
+        let DevicePair { mut a, mut b } = DevicePair::new(1500, 4, true, None).unwrap();
+        assert!(a.as_raw_fd() >= 0);
+        assert_ne!(a.as_raw_fd(), b.as_raw_fd());
+        assert!(!readable(b.as_raw_fd()));
+        assert_eq!(send(&mut a, &[1]), Some(Ok(())));
+        assert_eq!(send(&mut a, &[2]), Some(Ok(())));
+        assert!(readable(b.as_raw_fd()));
+        assert!(!readable(a.as_raw_fd()));
+        assert_eq!(recv(&mut b), Some(vec![1]));
+        assert!(readable(b.as_raw_fd()));
+        assert_eq!(recv(&mut b), Some(vec![2]));
+        assert!(!readable(b.as_raw_fd()));
+    }
+}
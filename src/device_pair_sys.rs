@@ -0,0 +1,186 @@
+use libc;
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+
+use batch::{recv_each, send_each, FrameBuf};
+use health::Health;
+use stats::Stats;
+
+/// Frames in flight to one side of the pair, with the eventfd of the
+/// receiving side which is readable while frames are queued.
+#[derive(Debug)]
+struct Queue {
+    frames: VecDeque<Vec<u8>>,
+    capacity: usize,
+    eventfd: Option<libc::c_int>,
+}
+
+impl Queue {
+    fn new(capacity: usize, with_eventfd: bool) -> io::Result<Queue> {
+        let eventfd = if with_eventfd {
+            let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Some(fd)
+        } else {
+            None
+        };
+        Ok(Queue {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            eventfd,
+        })
+    }
+
+    fn signal(&self) {
+        if let Some(fd) = self.eventfd {
+            let one: u64 = 1;
+            unsafe {
+                libc::write(fd, &one as *const u64 as *const libc::c_void, 8);
+            }
+        }
+    }
+
+    fn reset(&self) {
+        if let Some(fd) = self.eventfd {
+            let mut count: u64 = 0;
+            unsafe {
+                libc::read(fd, &mut count as *mut u64 as *mut libc::c_void, 8);
+            }
+        }
+    }
+}
+
+impl Drop for Queue {
+    fn drop(&mut self) {
+        if let Some(fd) = self.eventfd {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DevicePairDesc {
+    rx: Arc<Mutex<Queue>>,
+    tx: Arc<Mutex<Queue>>,
+    health: Health,
+    stats: Stats,
+}
+
+impl AsRawFd for DevicePairDesc {
+    fn as_raw_fd(&self) -> RawFd {
+        self.rx.lock().unwrap().eventfd.unwrap_or(-1)
+    }
+}
+
+impl DevicePairDesc {
+    pub fn new_pair(
+        queue_len: usize,
+        with_eventfd: bool,
+    ) -> io::Result<(DevicePairDesc, DevicePairDesc)> {
+        if queue_len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "queue length must not be zero",
+            ));
+        }
+        let a = Arc::new(Mutex::new(Queue::new(queue_len, with_eventfd)?));
+        let b = Arc::new(Mutex::new(Queue::new(queue_len, with_eventfd)?));
+        Ok((
+            DevicePairDesc {
+                rx: a.clone(),
+                tx: b.clone(),
+                health: Health::default(),
+                stats: Stats::default(),
+            },
+            DevicePairDesc {
+                rx: b,
+                tx: a,
+                health: Health::default(),
+                stats: Stats::default(),
+            },
+        ))
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn health_mut(&mut self) -> &mut Health {
+        &mut self.health
+    }
+
+    fn peer_connected(&self) -> io::Result<()> {
+        // the queues are only shared with the peer
+        if Arc::strong_count(&self.tx) == 1 {
+            return Err(io::Error::from_raw_os_error(libc::EPIPE));
+        }
+        Ok(())
+    }
+
+    pub fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let mut rx = self.rx.lock().unwrap();
+        match rx.frames.pop_front() {
+            Some(frame) => {
+                if rx.frames.is_empty() {
+                    rx.reset();
+                }
+                self.stats.rx(frame.len());
+                Ok(frame)
+            }
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock, "queue empty")),
+        }
+    }
+
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        recv_each(bufs, |buf| {
+            let frame = self.recv()?;
+            buf.copy_from(&frame[..]);
+            Ok(())
+        })
+    }
+
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        send_each(frames, |frame| self.send(frame.as_slice().to_vec()))
+    }
+
+    pub fn rx_pending(&self) -> bool {
+        !self.rx.lock().unwrap().frames.is_empty()
+    }
+
+    pub fn send_ready(&self) -> io::Result<()> {
+        self.peer_connected()?;
+        let tx = self.tx.lock().unwrap();
+        if tx.frames.len() >= tx.capacity {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "queue full"));
+        }
+        Ok(())
+    }
+
+    pub fn send(&mut self, frame: Vec<u8>) -> io::Result<()> {
+        self.peer_connected()?;
+        let mut tx = self.tx.lock().unwrap();
+        if tx.frames.len() >= tx.capacity {
+            return Err(io::Error::from_raw_os_error(libc::ENOBUFS));
+        }
+        self.stats.tx(frame.len());
+        tx.frames.push_back(frame);
+        if tx.frames.len() == 1 {
+            tx.signal();
+        }
+        Ok(())
+    }
+}
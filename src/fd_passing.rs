@@ -0,0 +1,406 @@
+use libc;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+
+use SMOLTCP_ETHERNET_HEADER;
+
+/// Identifies the messages, followed by the version of the format.
+const MAGIC: &[u8; 4] = b"USNF";
+const VERSION: u8 = 1;
+
+/// Largest MTU accepted for devices without a buffer size of their own, the
+/// largest IP MTU of Linux plus the Ethernet header.
+pub const MAX_SHARED_MTU: usize = 65535 + SMOLTCP_ETHERNET_HEADER;
+
+/// The device type of a file descriptor sent over a Unix stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SharedKind {
+    #[cfg(feature = "netmap")]
+    Netmap = 1,
+    Tap = 2,
+    Raw = 3,
+    #[cfg(feature = "xdp")]
+    Xdp = 4,
+    #[cfg(feature = "xdp")]
+    XdpUmem = 5,
+    #[cfg(feature = "xdp")]
+    XdpLink = 6,
+}
+
+/// What the receiving process needs besides the file descriptor to set up
+/// the device without access to the interface, e.g. inside a sandbox.
+#[derive(Debug, Default)]
+pub struct SharedMeta {
+    /// The interface, or the parent interface of a Netmap port.
+    pub parent: String,
+    pub mtu: usize,
+    pub reduce_mtu_by: Option<usize>,
+    pub uses_wait: bool,
+    /// Specific to the kind, e.g. the `nmreq` of Netmap or the medium of TAP.
+    pub extra: Vec<u8>,
+}
+
+impl SharedMeta {
+    /// Checks that the MTU sent by the other process is at most `max` and
+    /// larger than `reduce_mtu_by`.
+    pub fn check_mtu(&self, max: usize) -> io::Result<()> {
+        if self.mtu == 0 || self.mtu > max {
+            return Err(invalid("MTU out of range"));
+        }
+        if self.reduce_mtu_by.is_some_and(|by| by >= self.mtu) {
+            return Err(invalid("reduce_mtu_by not below the MTU"));
+        }
+        Ok(())
+    }
+
+    fn encode(&self, kind: SharedKind) -> io::Result<Vec<u8>> {
+        if self.parent.len() > usize::from(u8::MAX) || self.extra.len() > usize::from(u16::MAX) {
+            return Err(invalid("metadata too long"));
+        }
+        let mut msg = Vec::with_capacity(64 + self.extra.len());
+        msg.extend_from_slice(&[0, 0]); // length of the rest
+        msg.extend_from_slice(MAGIC);
+        msg.push(VERSION);
+        msg.push(kind as u8);
+        msg.push(self.parent.len() as u8);
+        msg.extend_from_slice(self.parent.as_bytes());
+        msg.extend_from_slice(&(self.mtu as u32).to_le_bytes());
+        let reduce_mtu_by = self.reduce_mtu_by.map_or(u32::MAX, |by| by as u32);
+        msg.extend_from_slice(&reduce_mtu_by.to_le_bytes());
+        msg.push(self.uses_wait as u8);
+        msg.extend_from_slice(&(self.extra.len() as u16).to_le_bytes());
+        msg.extend_from_slice(&self.extra);
+        if msg.len() - 2 > usize::from(u16::MAX) {
+            return Err(invalid("metadata too long"));
+        }
+        let len = (msg.len() - 2) as u16;
+        msg[..2].copy_from_slice(&len.to_le_bytes());
+        Ok(msg)
+    }
+
+    fn decode(mut msg: &[u8], kind: SharedKind) -> io::Result<SharedMeta> {
+        if take(&mut msg, MAGIC.len())? != MAGIC || take(&mut msg, 1)?[0] != VERSION {
+            return Err(invalid("not a device of this version"));
+        }
+        if take(&mut msg, 1)?[0] != kind as u8 {
+            return Err(invalid("device of another kind"));
+        }
+        let parent_len = usize::from(take(&mut msg, 1)?[0]);
+        if parent_len >= libc::IF_NAMESIZE {
+            return Err(invalid("interface name too long"));
+        }
+        let parent = String::from_utf8(take(&mut msg, parent_len)?.to_vec())
+            .map_err(|_| invalid("interface name not UTF-8"))?;
+        let mtu = read_u32(take(&mut msg, 4)?) as usize;
+        let reduce_mtu_by = match read_u32(take(&mut msg, 4)?) {
+            u32::MAX => None,
+            by => Some(by as usize),
+        };
+        let uses_wait = take(&mut msg, 1)?[0] != 0;
+        let extra_len = take(&mut msg, 2)?;
+        let extra_len = usize::from(u16::from_le_bytes([extra_len[0], extra_len[1]]));
+        let extra = take(&mut msg, extra_len)?.to_vec();
+        Ok(SharedMeta {
+            parent,
+            mtu,
+            reduce_mtu_by,
+            uses_wait,
+            extra,
+        })
+    }
+}
+
+fn take<'a>(msg: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if msg.len() < n {
+        return Err(invalid("truncated metadata"));
+    }
+    let (head, tail) = msg.split_at(n);
+    *msg = tail;
+    Ok(head)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Room for the control message with one file descriptor, aligned for `cmsghdr`.
+#[repr(C)]
+struct CmsgBuf {
+    buf: [u64; 4],
+}
+
+/// Sends `fd` with `SCM_RIGHTS` and the metadata in one message.
+pub fn send_fd(
+    stream: &UnixStream,
+    fd: RawFd,
+    kind: SharedKind,
+    meta: &SharedMeta,
+) -> io::Result<()> {
+    let msg = meta.encode(kind)?;
+    let mut cmsg_buf = CmsgBuf { buf: [0; 4] };
+    let mut iov = libc::iovec {
+        iov_base: msg.as_ptr() as *mut libc::c_void,
+        iov_len: msg.len(),
+    };
+    let sent = unsafe {
+        let cmsg_space = libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) as usize;
+        let mut hdr: libc::msghdr = mem::zeroed();
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = cmsg_buf.buf.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = cmsg_space;
+        let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as usize;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+        libc::sendmsg(stream.as_raw_fd(), &hdr, libc::MSG_NOSIGNAL)
+    };
+    if sent == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // the file descriptor went with the first byte
+    let mut rest = &msg[sent as usize..];
+    while !rest.is_empty() {
+        let res = unsafe {
+            libc::send(
+                stream.as_raw_fd(),
+                rest.as_ptr() as *const libc::c_void,
+                rest.len(),
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if res == -1 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        rest = &rest[res as usize..];
+    }
+    Ok(())
+}
+
+/// Receives a file descriptor sent by `send_fd` for a device of `kind`.
+/// The caller owns the file descriptor, which is closed on errors.
+pub fn recv_fd(stream: &UnixStream, kind: SharedKind) -> io::Result<(RawFd, SharedMeta)> {
+    let mut len = [0u8; 2];
+    let mut cmsg_buf = CmsgBuf { buf: [0; 4] };
+    let mut iov = libc::iovec {
+        iov_base: len.as_mut_ptr() as *mut libc::c_void,
+        iov_len: len.len(),
+    };
+    let (received, fds, truncated) = unsafe {
+        let mut hdr: libc::msghdr = mem::zeroed();
+        hdr.msg_iov = &mut iov;
+        hdr.msg_iovlen = 1;
+        hdr.msg_control = cmsg_buf.buf.as_mut_ptr() as *mut libc::c_void;
+        hdr.msg_controllen = mem::size_of::<CmsgBuf>();
+        let res = libc::recvmsg(stream.as_raw_fd(), &mut hdr, libc::MSG_CMSG_CLOEXEC);
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // collect every passed fd, the peer may have sent more than one
+        let mut fds = Vec::new();
+        let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(ptr::read_unaligned(data.add(i)));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
+        }
+        (res as usize, fds, hdr.msg_flags & libc::MSG_CTRUNC != 0)
+    };
+    // only the first fd is used, and none if the kernel discarded some
+    let used = if truncated { 0 } else { 1 };
+    for &fd in fds.iter().skip(used) {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+    if truncated {
+        return Err(invalid("control message truncated"));
+    }
+    let fd = match fds.first() {
+        Some(&fd) => fd,
+        None if received == 0 => {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "stream closed",
+            ))
+        }
+        None => return Err(invalid("no file descriptor received")),
+    };
+    let meta = read_meta(stream, &mut len, received, kind).inspect_err(|_| unsafe {
+        libc::close(fd);
+    })?;
+    Ok((fd, meta))
+}
+
+fn read_meta(
+    mut stream: &UnixStream,
+    len: &mut [u8; 2],
+    received: usize,
+    kind: SharedKind,
+) -> io::Result<SharedMeta> {
+    stream.read_exact(&mut len[received..])?;
+    let mut msg = vec![0; usize::from(u16::from_le_bytes(*len))];
+    stream.read_exact(&mut msg)?;
+    SharedMeta::decode(&msg, kind)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::Device;
+    use {RawSocket, TapInterface};
+
+    /// Sends an eventfd as device of `kind` with `meta`, returns the receiving end.
+    fn send_eventfd(meta: &SharedMeta, kind: SharedKind) -> io::Result<UnixStream> {
+        let (tx, rx) = UnixStream::pair()?;
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let sent = send_fd(&tx, fd, kind, meta);
+        unsafe {
+            libc::close(fd);
+        }
+        sent.map(|_| rx)
+    }
+
+    /// Sends an eventfd as raw socket with `meta` and receives it again.
+    fn share(meta: &SharedMeta) -> io::Result<RawSocket> {
+        RawSocket::new_from_unix_stream(&send_eventfd(meta, SharedKind::Raw)?)
+    }
+
+    fn meta(mtu: usize, reduce_mtu_by: Option<usize>) -> SharedMeta {
+        SharedMeta {
+            parent: "eth0".to_string(),
+            mtu,
+            reduce_mtu_by,
+            ..SharedMeta::default()
+        }
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let sent = SharedMeta {
+            uses_wait: true,
+            extra: vec![1, 2, 3],
+            ..meta(1514, Some(4))
+        };
+        send_fd(&tx, tx.as_raw_fd(), SharedKind::Tap, &sent).unwrap();
+        let (fd, received) = recv_fd(&rx, SharedKind::Tap).unwrap();
+        unsafe {
+            libc::close(fd);
+        }
+        assert_eq!(received.parent, "eth0");
+        assert_eq!(received.mtu, 1514);
+        assert_eq!(received.reduce_mtu_by, Some(4));
+        assert!(received.uses_wait);
+        assert_eq!(received.extra, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn shared_mtu_is_checked() {
+        let raw = share(&meta(1514, Some(4))).unwrap();
+        assert_eq!(raw.capabilities().max_transmission_unit, 1510);
+        for &(mtu, reduce_mtu_by) in &[
+            (0, None),
+            (MAX_SHARED_MTU + 1, None),
+            (1514, Some(1514)),
+            (1514, Some(u32::MAX as usize - 1)),
+        ] {
+            let err = share(&meta(mtu, reduce_mtu_by)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn tap_metadata_is_checked() {
+        for extra in &[[2, 0], [0, 2], [0, 8], [1, 9]] {
+            let sent = SharedMeta {
+                extra: extra.to_vec(),
+                ..meta(1514, None)
+            };
+            let rx = send_eventfd(&sent, SharedKind::Tap).unwrap();
+            let err = TapInterface::new_from_unix_stream(&rx).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        // valid metadata, but the fd is not attached to a TAP or TUN interface
+        let sent = SharedMeta {
+            extra: vec![0, 7],
+            ..meta(1514, None)
+        };
+        let rx = send_eventfd(&sent, SharedKind::Tap).unwrap();
+        let err = TapInterface::new_from_unix_stream(&rx).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTTY));
+    }
+
+    /// Sends all `fds` in one `SCM_RIGHTS` message with the metadata.
+    fn send_fds(stream: &UnixStream, fds: &[RawFd], meta: &SharedMeta) {
+        let msg = meta.encode(SharedKind::Tap).unwrap();
+        let mut cmsg_buf = [0u64; 8];
+        let mut iov = libc::iovec {
+            iov_base: msg.as_ptr() as *mut libc::c_void,
+            iov_len: msg.len(),
+        };
+        let sent = unsafe {
+            let fds_len = mem::size_of_val(fds) as u32;
+            let mut hdr: libc::msghdr = mem::zeroed();
+            hdr.msg_iov = &mut iov;
+            hdr.msg_iovlen = 1;
+            hdr.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+            hdr.msg_controllen = libc::CMSG_SPACE(fds_len) as usize;
+            let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as usize;
+            let data = libc::CMSG_DATA(cmsg) as *mut RawFd;
+            for (i, &fd) in fds.iter().enumerate() {
+                ptr::write_unaligned(data.add(i), fd);
+            }
+            libc::sendmsg(stream.as_raw_fd(), &hdr, 0)
+        };
+        assert_eq!(sent, msg.len() as isize);
+    }
+
+    #[test]
+    fn extra_fds_are_handled() {
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let fds = [tx.as_raw_fd(), rx.as_raw_fd()];
+        send_fds(&tx, &fds, &meta(1514, None));
+        let (fd, received) = recv_fd(&rx, SharedKind::Tap).unwrap();
+        unsafe {
+            libc::close(fd);
+        }
+        assert_eq!(received.mtu, 1514);
+        // more fds than fit into the control buffer
+        send_fds(&tx, &[tx.as_raw_fd(); 6], &meta(1514, None));
+        let err = recv_fd(&rx, SharedKind::Tap).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn long_parent_is_rejected() {
+        let mut sent = meta(1514, None);
+        sent.parent = "a".repeat(libc::IF_NAMESIZE - 1);
+        share(&sent).unwrap();
+        sent.parent.push('a');
+        let err = share(&sent).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
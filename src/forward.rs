@@ -0,0 +1,109 @@
+use std::any::Any;
+
+use smoltcp::phy::{Device, RxToken, TxToken};
+use smoltcp::time::Instant;
+use smoltcp::{Error, Result};
+
+#[cfg(feature = "netmap")]
+use netmap::Netmap;
+#[cfg(feature = "xdp")]
+use xdp::XdpSocket;
+use AnyDevice;
+
+/// Moves the next frame received on `from` into the TX path of `to`, e.g. to
+/// bridge a Netmap port and a TAP interface for the host stack.
+///
+/// Returns whether a frame was forwarded, which is not the case if `from`
+/// has no frame or `to` has no room for one. In the latter case the frame
+/// is not received and stays with `from`.
+///
+/// The frame is copied once from the RX into the TX buffer, unless both
+/// devices are `Netmap` devices sharing a memory region or `XdpSocket`s
+/// sharing a UMEM (also inside an `AnyDevice`), which swap buffers with
+/// `zc_forward` instead. A frame larger than the MTU of `to` is dropped
+/// with `Error::Truncated`. Devices of different media (e.g. a TUN and a TAP
+/// interface) fail with `Error::Illegal` as their frames are not compatible.
+pub fn forward<F, T>(from: &mut F, to: &mut T) -> Result<bool>
+where
+    F: Any + for<'a> Device<'a>,
+    T: Any + for<'a> Device<'a>,
+{
+    if from.capabilities().medium != to.capabilities().medium {
+        return Err(Error::Illegal);
+    }
+    if let Some(forwarded) = zc_forward(inner(from), inner(to)) {
+        return forwarded;
+    }
+    if !tx_ready(inner(to)) {
+        return Ok(false);
+    }
+    let mtu = to.capabilities().max_transmission_unit;
+    let tx = match to.transmit() {
+        Some(tx) => tx,
+        None => return Ok(false),
+    };
+    let rx = match from.receive() {
+        Some((rx, _)) => rx,
+        None => return Ok(false),
+    };
+    let timestamp = Instant::now();
+    rx.consume(timestamp, |frame| {
+        if frame.len() > mtu {
+            return Err(Error::Truncated);
+        }
+        tx.consume(timestamp, frame.len(), |buffer| {
+            buffer.copy_from_slice(frame);
+            Ok(())
+        })
+    })?;
+    Ok(true)
+}
+
+/// Unwraps an `AnyDevice` so that the device inside can be recognized.
+fn inner<D: Any>(device: &mut D) -> &mut dyn Any {
+    let device = device as &mut dyn Any;
+    if device.is::<AnyDevice>() {
+        device.downcast_mut::<AnyDevice>().unwrap().inner_mut()
+    } else {
+        device
+    }
+}
+
+/// Whether `to` has room for a frame, `Netmap::transmit` returns a token
+/// even if its TX rings are full.
+#[cfg_attr(not(feature = "netmap"), allow(unused_variables))]
+fn tx_ready(to: &mut dyn Any) -> bool {
+    #[cfg(feature = "netmap")]
+    {
+        if let Some(to) = to.downcast_mut::<Netmap>() {
+            return to.tx_ready();
+        }
+    }
+    true
+}
+
+/// Forwards without copying if both devices support it, or returns `None`.
+#[cfg_attr(not(any(feature = "netmap", feature = "xdp")), allow(unused_variables))]
+fn zc_forward(from: &mut dyn Any, to: &mut dyn Any) -> Option<Result<bool>> {
+    #[cfg(feature = "netmap")]
+    {
+        if let (Some(from), Some(to)) = (from.downcast_mut::<Netmap>(), to.downcast_mut::<Netmap>())
+        {
+            if to.shares_memory(from) {
+                return Some(to.zc_forward_next(from));
+            }
+        }
+    }
+    #[cfg(feature = "xdp")]
+    {
+        if let (Some(from), Some(to)) = (
+            from.downcast_mut::<XdpSocket>(),
+            to.downcast_mut::<XdpSocket>(),
+        ) {
+            if to.shares_umem(from) {
+                return Some(to.zc_forward_next(from));
+            }
+        }
+    }
+    None
+}
@@ -0,0 +1,123 @@
+use libc;
+use std::io;
+
+use smoltcp::Error;
+
+/// Health of a device as observed by its I/O operations.
+///
+/// Every device records the errors of its I/O operations instead of
+/// panicking, except for backpressure (would-block or `ENOBUFS`) which only
+/// shows in its `Stats`. `state` returns the resulting state, `last_error`
+/// the error which caused it, and `clear_error` resets the device to `Up`,
+/// e.g. after the interface came up again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceState {
+    /// No error occurred since the device was opened or the error was cleared.
+    Up,
+    /// A transient error occurred (e.g. `EMSGSIZE`), the device may recover by itself.
+    Degraded,
+    /// The interface is down or gone (e.g. `ENETDOWN`, or `EIO` after a TAP
+    /// detach), the device should be reopened or the application fail over.
+    Down,
+}
+
+#[derive(Debug)]
+struct LastError {
+    kind: io::ErrorKind,
+    raw_os_error: Option<i32>,
+    message: String,
+}
+
+/// Records the last error of a device descriptor.
+#[derive(Debug)]
+pub struct Health {
+    last_error: Option<LastError>,
+    state: DeviceState,
+}
+
+impl Default for Health {
+    fn default() -> Health {
+        Health {
+            last_error: None,
+            state: DeviceState::Up,
+        }
+    }
+}
+
+impl Health {
+    /// Records `err` unless it only signals backpressure (see `is_backpressure`),
+    /// and hands it back for further propagation.
+    pub fn record(&mut self, err: io::Error) -> io::Error {
+        if is_backpressure(&err) {
+            return err;
+        }
+        self.state = match err.raw_os_error() {
+            Some(libc::ENETDOWN)
+            | Some(libc::ENXIO)
+            | Some(libc::ENODEV)
+            | Some(libc::EIO)
+            | Some(libc::EBADF)
+            | Some(libc::EBADFD)
+            | Some(libc::EPIPE)
+            | Some(libc::ECONNREFUSED)
+            | Some(libc::ENOTCONN) => DeviceState::Down,
+            _ => DeviceState::Degraded,
+        };
+        self.last_error = Some(LastError {
+            kind: err.kind(),
+            raw_os_error: err.raw_os_error(),
+            message: err.to_string(),
+        });
+        err
+    }
+
+    pub fn state(&self) -> DeviceState {
+        self.state
+    }
+
+    pub fn last_error(&self) -> Option<io::Error> {
+        self.last_error
+            .as_ref()
+            .map(|last| match last.raw_os_error {
+                Some(code) => io::Error::from_raw_os_error(code),
+                None => io::Error::new(last.kind, last.message.clone()),
+            })
+    }
+
+    pub fn clear(&mut self) {
+        self.last_error = None;
+        self.state = DeviceState::Up;
+    }
+}
+
+/// Whether `err` means that the kernel queue is full (or empty for RX)
+/// and the operation can be retried later.
+pub fn is_backpressure(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+/// Maps a smoltcp error of a ring based transmission back to an I/O error for
+/// the batch APIs, the original error is kept by `Health::last_error`.
+pub fn to_io_error(err: Error) -> io::Error {
+    match err {
+        Error::Exhausted => io::Error::new(io::ErrorKind::WouldBlock, "tx ring full"),
+        Error::Truncated => io::Error::from_raw_os_error(libc::EMSGSIZE),
+        _ => io::Error::from_raw_os_error(libc::EIO),
+    }
+}
+
+/// Maps an I/O error of a device to the closest smoltcp error.
+pub fn to_smoltcp_error(err: &io::Error) -> Error {
+    if is_backpressure(err) {
+        return Error::Exhausted;
+    }
+    match err.raw_os_error() {
+        Some(libc::ENOMEM) => Error::Exhausted,
+        Some(libc::EMSGSIZE) => Error::Truncated,
+        Some(libc::ECONNREFUSED)
+        | Some(libc::ENOTCONN)
+        | Some(libc::EDESTADDRREQ)
+        | Some(libc::ENOENT) => Error::Unaddressable,
+        _ => Error::Illegal,
+    }
+}
@@ -0,0 +1,623 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::vec::Vec;
+
+use smoltcp::phy;
+use smoltcp::phy::{Device, DeviceCapabilities};
+use smoltcp::time::{Duration, Instant};
+use smoltcp::{Error, Result};
+
+/// Impairments applied to the frames of one direction, all disabled by default.
+///
+/// Probabilities are given in the range from 0.0 to 1.0.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Impairment {
+    /// Probability that a frame is dropped.
+    pub loss: f64,
+    /// Constant delay of every frame.
+    pub delay: Duration,
+    /// Maximal additional random delay of a frame.
+    pub jitter: Duration,
+    /// Probability that a frame is sent without delay and thus overtakes
+    /// delayed frames (like netem's reorder, requires a delay).
+    pub reorder: f64,
+    /// Probability that a frame is sent twice.
+    pub duplicate: f64,
+    /// Probability that a single random bit of a frame is flipped.
+    pub corrupt: f64,
+    /// Link rate in bits per second, frames wait until the previous frame
+    /// was serialized.
+    pub rate_bps: Option<u64>,
+    /// Maximal number of delayed frames, further frames are dropped.
+    /// A value of 0 stands for 1024 frames.
+    pub queue_len: usize,
+}
+
+const DEFAULT_QUEUE_LEN: usize = 1024;
+
+/// The xorshift64* generator, good enough for impairments and reproducible
+/// for a given seed.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(if seed == 0 {
+            0x9E37_79B9_7F4A_7C15
+        } else {
+            seed
+        })
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns `true` with probability `p`.
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next_u64() % n
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Delayed {
+    due: Instant,
+    seq: u64,
+    data: Vec<u8>,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Delayed) -> bool {
+        self.due == other.due && self.seq == other.seq
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Delayed) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    // reversed for a min-heap, frames with the same due time stay in order
+    fn cmp(&self, other: &Delayed) -> Ordering {
+        other
+            .due
+            .cmp(&self.due)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Debug)]
+struct Path {
+    impairment: Impairment,
+    queue: BinaryHeap<Delayed>,
+    link_free: Option<Instant>,
+}
+
+impl Path {
+    fn new(impairment: Impairment) -> Path {
+        Path {
+            impairment,
+            queue: BinaryHeap::new(),
+            link_free: None,
+        }
+    }
+
+    fn queue_len(&self) -> usize {
+        match self.impairment.queue_len {
+            0 => DEFAULT_QUEUE_LEN,
+            n => n,
+        }
+    }
+
+    /// Returns the due times of the copies of a frame which arrived at `now`,
+    /// none if it is lost. The frame may get corrupted.
+    fn schedule(&mut self, rng: &mut Rng, now: Instant, frame: &mut [u8]) -> Vec<Instant> {
+        let imp = self.impairment;
+        if rng.chance(imp.loss) {
+            return Vec::new();
+        }
+        if !frame.is_empty() && rng.chance(imp.corrupt) {
+            let bit = rng.below(frame.len() as u64 * 8) as usize;
+            frame[bit / 8] ^= 1 << (bit % 8);
+        }
+        let copies = if rng.chance(imp.duplicate) { 2 } else { 1 };
+        (0..copies)
+            .map(|_| {
+                let mut due = if rng.chance(imp.reorder) {
+                    now
+                } else {
+                    now + imp.delay
+                        + Duration::from_micros(rng.below(imp.jitter.total_micros() + 1))
+                };
+                if let Some(rate) = imp.rate_bps.filter(|rate| *rate > 0) {
+                    if let Some(link_free) = self.link_free {
+                        if link_free > due {
+                            due = link_free;
+                        }
+                    }
+                    let bits = frame.len() as u64 * 8;
+                    self.link_free = Some(due + Duration::from_micros(bits * 1_000_000 / rate));
+                }
+                due
+            })
+            .collect()
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.queue.peek().map(|frame| frame.due)
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.next_due()? <= now {
+            self.queue.pop().map(|frame| frame.data)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ImpairState {
+    rng: Rng,
+    rx: Path,
+    tx: Path,
+    seq: u64,
+    /// The last timestamp passed to a token
+    now: Option<Instant>,
+    /// A frame was not due in the last RX consume, see `PcapDevice`
+    deferred: bool,
+}
+
+impl ImpairState {
+    fn has_delayed(&self) -> bool {
+        !self.rx.queue.is_empty() || !self.tx.queue.is_empty()
+    }
+
+    fn push(&mut self, rx: bool, due: Instant, data: Vec<u8>) {
+        self.seq += 1;
+        let path = if rx { &mut self.rx } else { &mut self.tx };
+        if path.queue.len() < path.queue_len() {
+            path.queue.push(Delayed {
+                due,
+                seq: self.seq,
+                data,
+            });
+        }
+    }
+}
+
+/// Drops, delays, reorders, duplicates, corrupts and rate-limits the frames
+/// of the wrapped device according to the `Impairment` of each direction,
+/// with the smoltcp timestamps passed to the tokens as clock.
+///
+/// Delayed frames are kept in the wrapper: received frames are handed out
+/// once the timestamp of an RX `consume` reached their due time, frames to
+/// transmit are passed to the wrapped device on the next `receive` or
+/// `transmit` call after they are due. Therefore the device should be polled
+/// at least at `next_due` in addition to the readiness of its file descriptor.
+#[derive(Debug)]
+pub struct Impaired<D> {
+    inner: D,
+    state: Arc<Mutex<ImpairState>>,
+}
+
+impl<D: AsRawFd> AsRawFd for Impaired<D> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<D> Impaired<D>
+where
+    D: for<'a> Device<'a>,
+{
+    /// Wraps `inner` with the impairments `rx` for received and `tx` for
+    /// transmitted frames, the same `seed` gives the same random decisions.
+    pub fn new(inner: D, rx: Impairment, tx: Impairment, seed: u64) -> Impaired<D> {
+        Impaired {
+            inner,
+            state: Arc::new(Mutex::new(ImpairState {
+                rng: Rng::new(seed),
+                rx: Path::new(rx),
+                tx: Path::new(tx),
+                seq: 0,
+                now: None,
+                deferred: false,
+            })),
+        }
+    }
+
+    /// Passes the delayed frames to transmit which are due to the wrapped device.
+    pub fn flush(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        let now = match state.now {
+            Some(now) => now,
+            None => return,
+        };
+        while let Some(due) = state.tx.next_due() {
+            if due > now {
+                break;
+            }
+            let token = match self.inner.transmit() {
+                Some(token) => token,
+                None => break,
+            };
+            let frame = state.tx.pop_due(now).unwrap();
+            let _ = phy::TxToken::consume(token, now, frame.len(), |buf| {
+                buf.copy_from_slice(&frame);
+                Ok(())
+            });
+        }
+    }
+}
+
+impl<D> Impaired<D> {
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Returns the earliest due time of the delayed frames of both directions.
+    pub fn next_due(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        match (state.rx.next_due(), state.tx.next_due()) {
+            (Some(rx), Some(tx)) => Some(if rx < tx { rx } else { tx }),
+            (rx, tx) => rx.or(tx),
+        }
+    }
+
+    /// Replaces the impairments, frames already delayed stay queued.
+    pub fn set_impairments(&mut self, rx: Impairment, tx: Impairment) {
+        let mut state = self.state.lock().unwrap();
+        state.rx.impairment = rx;
+        state.tx.impairment = tx;
+    }
+}
+
+impl<'a, D> Device<'a> for Impaired<D>
+where
+    D: for<'b> Device<'b>,
+{
+    type RxToken = RxToken<<D as Device<'a>>::RxToken>;
+    type TxToken = TxToken<<D as Device<'a>>::TxToken>;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        self.flush();
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.deferred {
+                // end the receive loop of the current poll
+                state.deferred = false;
+                return None;
+            }
+        }
+        let state = &self.state;
+        match self.inner.receive() {
+            Some((rx, tx)) => Some((
+                RxToken {
+                    inner: Some(rx),
+                    state: state.clone(),
+                },
+                TxToken {
+                    inner: Some(tx),
+                    state: state.clone(),
+                },
+            )),
+            None if state.lock().unwrap().has_delayed() => Some((
+                // the RX consume also provides the clock for delayed TX frames
+                RxToken {
+                    inner: None,
+                    state: state.clone(),
+                },
+                TxToken {
+                    inner: None,
+                    state: state.clone(),
+                },
+            )),
+            None => None,
+        }
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        self.flush();
+        let (full, empty) = {
+            let state = self.state.lock().unwrap();
+            (
+                state.tx.queue.len() >= state.tx.queue_len(),
+                state.tx.queue.is_empty(),
+            )
+        };
+        if full {
+            return None;
+        }
+        // frames can only be passed through if none are delayed
+        let inner = if empty { self.inner.transmit() } else { None };
+        Some(TxToken {
+            inner,
+            state: self.state.clone(),
+        })
+    }
+}
+
+/// Hands out the next due received frame, or marks the device as deferred.
+fn deliver_delayed<R, F>(state: &Mutex<ImpairState>, now: Instant, f: F) -> Result<R>
+where
+    F: FnOnce(&mut [u8]) -> Result<R>,
+{
+    let frame = {
+        let mut state = state.lock().unwrap();
+        let frame = state.rx.pop_due(now);
+        if frame.is_none() {
+            state.deferred = true;
+        }
+        frame
+    };
+    match frame {
+        Some(mut frame) => f(&mut frame[..]),
+        None => Err(Error::Exhausted),
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken<T> {
+    inner: Option<T>,
+    state: Arc<Mutex<ImpairState>>,
+}
+
+impl<T: phy::RxToken> phy::RxToken for RxToken<T> {
+    fn consume<R, F>(self, timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let state = self.state;
+        state.lock().unwrap().now = Some(timestamp);
+        let inner = match self.inner {
+            Some(inner) => inner,
+            None => return deliver_delayed(&state, timestamp, f),
+        };
+        inner.consume(timestamp, |buffer| {
+            let pass_through = {
+                let mut guard = state.lock().unwrap();
+                let state = &mut *guard;
+                let dues = state.rx.schedule(&mut state.rng, timestamp, buffer);
+                let mut pass_through = false;
+                for due in dues {
+                    let overtakes = match state.rx.next_due() {
+                        Some(next) => next > timestamp,
+                        None => true,
+                    };
+                    if due <= timestamp && overtakes && !pass_through {
+                        pass_through = true;
+                    } else {
+                        state.push(true, due, buffer.to_vec());
+                    }
+                }
+                pass_through
+            };
+            if pass_through {
+                f(buffer)
+            } else {
+                deliver_delayed(&state, timestamp, f)
+            }
+        })
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken<T> {
+    inner: Option<T>,
+    state: Arc<Mutex<ImpairState>>,
+}
+
+impl<T: phy::TxToken> phy::TxToken for TxToken<T> {
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut buffer = vec![0; len];
+        let result = f(&mut buffer)?;
+        let pass_through = {
+            let mut guard = self.state.lock().unwrap();
+            let state = &mut *guard;
+            state.now = Some(timestamp);
+            let dues = state.tx.schedule(&mut state.rng, timestamp, &mut buffer);
+            let mut pass_through = false;
+            for due in dues {
+                let can_pass = self.inner.is_some() && state.tx.queue.is_empty();
+                if due <= timestamp && can_pass && !pass_through {
+                    pass_through = true;
+                } else {
+                    state.push(false, due, buffer.clone());
+                }
+            }
+            pass_through
+        };
+        if pass_through {
+            let inner = self.inner.unwrap();
+            inner.consume(timestamp, len, |buf| {
+                buf.copy_from_slice(&buffer);
+                Ok(())
+            })?;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::{RxToken as _, TxToken as _};
+    use {DevicePair, PairedDevice};
+
+    fn ms(millis: i64) -> Instant {
+        Instant::from_millis(millis)
+    }
+
+    fn send<D: for<'a> Device<'a>>(device: &mut D, now: Instant, frame: &[u8]) {
+        let tx = device.transmit().unwrap();
+        tx.consume(now, frame.len(), |buf| {
+            buf.copy_from_slice(frame);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    /// Receives like the poll loop of an interface until `receive` returns `None`.
+    fn poll_rx<D: for<'a> Device<'a>>(device: &mut D, now: Instant) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while let Some((rx, _)) = device.receive() {
+            match rx.consume(now, |buf| Ok(buf.to_vec())) {
+                Ok(frame) => frames.push(frame),
+                Err(Error::Exhausted) => {}
+                Err(err) => panic!("{}", err),
+            }
+        }
+        frames
+    }
+
+    /// Advances the clock of `device` to `now` and transmits the frames due.
+    fn tick(device: &mut Impaired<PairedDevice>, now: Instant) {
+        poll_rx(device, now);
+        device.flush();
+    }
+
+    /// Impairs the frames received by `b`.
+    fn rx_pair(rx: Impairment, seed: u64) -> (PairedDevice, Impaired<PairedDevice>) {
+        let DevicePair { a, b } = DevicePair::new(1500, 64, false, None).unwrap();
+        (a, Impaired::new(b, rx, Impairment::default(), seed))
+    }
+
+    /// Sends `count` frames one millisecond apart and returns the first byte
+    /// of each received frame in order of arrival.
+    fn replay(rx: Impairment, seed: u64, count: u8) -> Vec<u8> {
+        let (mut a, mut b) = rx_pair(rx, seed);
+        let mut received = Vec::new();
+        for i in 0..count {
+            send(&mut a, ms(i64::from(i)), &[i]);
+            received.extend(poll_rx(&mut b, ms(i64::from(i))));
+        }
+        received.extend(poll_rx(&mut b, ms(1000)));
+        received.iter().map(|frame| frame[0]).collect()
+    }
+
+    #[test]
+    fn frames_pass_unimpaired() {
+        assert_eq!(
+            replay(Impairment::default(), 1, 10),
+            (0..10).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn loss_drops_frames() {
+        let all = Impairment {
+            loss: 1.0,
+            ..Impairment::default()
+        };
+        assert!(replay(all, 1, 10).is_empty());
+        let imp = Impairment {
+            loss: 0.5,
+            ..Impairment::default()
+        };
+        let received = replay(imp, 1, 200);
+        assert!(received.len() > 50 && received.len() < 150);
+        assert!(received.windows(2).all(|w| w[0] < w[1]));
+        // the same seed gives the same losses
+        assert_eq!(replay(imp, 1, 200), received);
+        assert_ne!(replay(imp, 2, 200), received);
+    }
+
+    #[test]
+    fn duplicate_sends_frames_twice() {
+        let imp = Impairment {
+            duplicate: 1.0,
+            ..Impairment::default()
+        };
+        assert_eq!(replay(imp, 1, 3), vec![0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn delay_holds_frames_until_due() {
+        let imp = Impairment {
+            delay: Duration::from_millis(10),
+            ..Impairment::default()
+        };
+        let (mut a, mut b) = rx_pair(imp, 1);
+        send(&mut a, ms(0), &[1]);
+        assert!(poll_rx(&mut b, ms(0)).is_empty());
+        send(&mut a, ms(2), &[2]);
+        assert!(poll_rx(&mut b, ms(2)).is_empty());
+        assert_eq!(b.next_due(), Some(ms(10)));
+        assert!(poll_rx(&mut b, ms(9)).is_empty());
+        assert_eq!(poll_rx(&mut b, ms(10)), vec![vec![1]]);
+        assert_eq!(b.next_due(), Some(ms(12)));
+        assert_eq!(poll_rx(&mut b, ms(12)), vec![vec![2]]);
+        assert_eq!(b.next_due(), None);
+    }
+
+    #[test]
+    fn reorder_lets_frames_overtake_delayed_ones() {
+        let imp = Impairment {
+            delay: Duration::from_millis(10),
+            reorder: 0.5,
+            ..Impairment::default()
+        };
+        let received = replay(imp, 1, 50);
+        let mut sorted = received.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..50).collect::<Vec<_>>());
+        assert_ne!(received, sorted);
+        assert_eq!(replay(imp, 1, 50), received);
+    }
+
+    #[test]
+    fn rate_limit_serializes_frames() {
+        let DevicePair { a, mut b } = DevicePair::new(1500, 64, false, None).unwrap();
+        let tx = Impairment {
+            // one byte per millisecond
+            rate_bps: Some(8000),
+            ..Impairment::default()
+        };
+        let mut a = Impaired::new(a, Impairment::default(), tx, 1);
+        for i in 0..3 {
+            send(&mut a, ms(0), &[i; 10]);
+        }
+        assert_eq!(poll_rx(&mut b, ms(0)), vec![vec![0; 10]]);
+        assert_eq!(a.next_due(), Some(ms(10)));
+        tick(&mut a, ms(9));
+        assert!(poll_rx(&mut b, ms(9)).is_empty());
+        tick(&mut a, ms(10));
+        assert_eq!(poll_rx(&mut b, ms(10)), vec![vec![1; 10]]);
+        tick(&mut a, ms(20));
+        assert_eq!(poll_rx(&mut b, ms(20)), vec![vec![2; 10]]);
+        assert_eq!(a.next_due(), None);
+    }
+}
@@ -1,38 +1,109 @@
+#![allow(clippy::redundant_field_names)]
+
 extern crate libc;
+#[cfg(feature = "mio")]
+extern crate mio;
 extern crate smoltcp;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
 #[cfg(feature = "netmap")]
 mod netmap;
 #[cfg(feature = "netmap")]
 mod nm;
 
+mod any_device;
+#[cfg(feature = "tokio")]
+mod async_device;
+mod batch;
+mod capture;
+mod device_pair;
+mod device_pair_sys;
+mod fd_passing;
+mod forward;
+mod health;
+mod impair;
+#[cfg(feature = "mio")]
+mod mio_source;
+mod packet_ring;
+mod pcap_device;
+mod pcap_device_sys;
+mod pcapng;
+mod pool;
 mod raw_socket;
 mod raw_socket_sys;
+mod spec;
+mod stats;
 mod tap_interface;
 mod tap_interface_sys;
 mod uds;
 mod unixdomainsocket;
+mod vnet;
+mod wait;
+#[cfg(feature = "xdp")]
+mod xdp;
+#[cfg(feature = "xdp")]
+mod xsk;
 
 #[cfg(feature = "netmap")]
-pub use self::netmap::{nmreq, Netmap, RxToken as NetmapRxToken, TxToken as NetmapTxToken};
+pub use self::netmap::{
+    nmreq, Netmap, NetmapPipeEnd, RxToken as NetmapRxToken, TxToken as NetmapTxToken,
+};
 
+pub use self::any_device::{
+    AnyDevice, DeviceOps, RxToken as AnyDeviceRxToken, TxToken as AnyDeviceTxToken,
+};
+#[cfg(feature = "tokio")]
+pub use self::async_device::{AsyncDevice, Readable, Receive, Transmit, Writable};
+pub use self::batch::FrameBuf;
+pub use self::capture::{Capture, RxToken as CaptureRxToken, TxToken as CaptureTxToken};
+pub use self::device_pair::{
+    DevicePair, PairedDevice, RxToken as PairedDeviceRxToken, TxToken as PairedDeviceTxToken,
+};
+pub use self::forward::forward;
+pub use self::health::DeviceState;
+pub use self::impair::{
+    Impaired, Impairment, RxToken as ImpairedRxToken, TxToken as ImpairedTxToken,
+};
+pub use self::packet_ring::PacketRingConfig;
+pub use self::pcap_device::{
+    PcapDevice, RxToken as PcapDeviceRxToken, TxToken as PcapDeviceTxToken,
+};
+pub use self::pcapng::PcapngSink;
+pub use self::pool::{BufferPool, DEFAULT_POOL_SIZE};
 pub use self::raw_socket::{RawSocket, RxToken as RawSocketRxToken, TxToken as RawSocketTxToken};
+pub use self::spec::{open, DeviceKind, DeviceSpec, SpecError};
+pub use self::stats::Stats;
 pub use self::tap_interface::{
     RxToken as TapInterfaceRxToken, TapInterface, TxToken as TapInterfaceTxToken,
 };
 pub use self::unixdomainsocket::{
     RxToken as UnixDomainSocketRxToken, TxToken as UnixDomainSocketTxToken, UnixDomainSocket,
 };
+pub use self::vnet::VnetOffload;
+pub use self::wait::wait;
+#[cfg(feature = "xdp")]
+pub use self::xdp::{RxToken as XdpRxToken, TxToken as XdpTxToken, XdpConfig, XdpShared, XdpSocket};
 use std::io;
+use std::mem;
 
 pub const SMOLTCP_ETHERNET_HEADER: usize = 14;
 
 const SIOCGIFMTU: libc::c_ulong = 0x8921;
 const SIOCGIFINDEX: libc::c_ulong = 0x8933;
 const ETH_P_ALL: libc::c_short = 0x0003;
+const IFF_TUN: libc::c_int = 0x0001;
 const IFF_TAP: libc::c_int = 0x0002;
 const IFF_NO_PI: libc::c_int = 0x1000;
+const IFF_MULTI_QUEUE: libc::c_int = 0x0100;
+const IFF_ATTACH_QUEUE: libc::c_int = 0x0200;
+const IFF_DETACH_QUEUE: libc::c_int = 0x0400;
 const TUNSETIFF: libc::c_ulong = 0x400454CA;
+const TUNGETIFF: libc::c_ulong = 0x800454D2;
+const TUNSETQUEUE: libc::c_ulong = 0x400454D9;
+const IFF_VNET_HDR: libc::c_int = 0x4000;
+const TUNSETOFFLOAD: libc::c_ulong = 0x400454D0;
+const TUNSETVNETHDRSZ: libc::c_ulong = 0x400454D8;
 
 #[repr(C)]
 #[derive(Debug)]
@@ -41,7 +112,13 @@ struct ifreq {
     ifr_data: libc::c_int, /* ifr_ifindex or ifr_mtu */
 }
 
-fn ifreq_for(name: &str) -> ifreq {
+fn ifreq_for(name: &str) -> io::Result<ifreq> {
+    if name.len() >= libc::IF_NAMESIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "interface name too long",
+        ));
+    }
     let mut ifreq = ifreq {
         ifr_name: [0; libc::IF_NAMESIZE],
         ifr_data: 0,
@@ -49,7 +126,17 @@ fn ifreq_for(name: &str) -> ifreq {
     for (i, byte) in name.as_bytes().iter().enumerate() {
         ifreq.ifr_name[i] = *byte as libc::c_char
     }
-    ifreq
+    Ok(ifreq)
+}
+
+fn ifreq_name(ifreq: &ifreq) -> String {
+    let name: Vec<u8> = ifreq
+        .ifr_name
+        .iter()
+        .take_while(|&&byte| byte != 0)
+        .map(|&byte| byte as u8)
+        .collect();
+    String::from_utf8_lossy(&name).into_owned()
 }
 
 fn ifreq_ioctl(
@@ -66,3 +153,89 @@ fn ifreq_ioctl(
 
     Ok(ifreq.ifr_data)
 }
+
+/// Checks without blocking whether `fd` can be written to. A full queue is
+/// reported as would-block, but an error condition of `fd` (e.g. a detached
+/// TAP queue or a closed peer) as the error which a write would return.
+fn fd_writable(fd: libc::c_int) -> io::Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    let res = unsafe { libc::poll(&mut pollfd, 1, 0) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if pollfd.revents & libc::POLLNVAL != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EBADF));
+    }
+    if pollfd.revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+        let mut err: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut err as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res == 0 && err != 0 {
+            return Err(io::Error::from_raw_os_error(err));
+        }
+        // no socket or no pending socket error
+        let code = if pollfd.revents & libc::POLLHUP != 0 {
+            libc::EPIPE
+        } else {
+            libc::EIO
+        };
+        return Err(io::Error::from_raw_os_error(code));
+    }
+    if pollfd.revents & libc::POLLOUT == 0 {
+        return Err(io::Error::new(io::ErrorKind::WouldBlock, "not writable"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipe() -> (libc::c_int, libc::c_int) {
+        let mut fds = [0; 2];
+        let res = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK) };
+        assert_eq!(res, 0);
+        (fds[0], fds[1])
+    }
+
+    #[test]
+    fn fd_writable_reports_backpressure() {
+        let (read, write) = pipe();
+        assert!(fd_writable(write).is_ok());
+        let chunk = [0u8; 4096];
+        while unsafe { libc::write(write, chunk.as_ptr() as *const libc::c_void, chunk.len()) } > 0
+        {
+        }
+        let err = fd_writable(write).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        unsafe {
+            libc::close(read);
+            libc::close(write);
+        }
+    }
+
+    #[test]
+    fn fd_writable_reports_errors() {
+        let (read, write) = pipe();
+        unsafe {
+            libc::close(read);
+        }
+        let err = fd_writable(write).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        unsafe {
+            libc::close(write);
+        }
+    }
+}
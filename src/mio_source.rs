@@ -0,0 +1,61 @@
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+
+use capture::Capture;
+use device_pair::PairedDevice;
+use impair::Impaired;
+#[cfg(feature = "netmap")]
+use netmap::Netmap;
+use raw_socket::RawSocket;
+use tap_interface::TapInterface;
+use unixdomainsocket::UnixDomainSocket;
+#[cfg(feature = "xdp")]
+use xdp::XdpSocket;
+use AnyDevice;
+
+/// Registers the file descriptor of the device, which stays owned by the device.
+/// Generic wrappers list their type parameters after the type.
+macro_rules! impl_source {
+    ($device:ty $(, $param:ident)*) => {
+        impl<$($param: AsRawFd),*> Source for $device {
+            fn register(
+                &mut self,
+                registry: &Registry,
+                token: Token,
+                interests: Interest,
+            ) -> io::Result<()> {
+                SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+            }
+
+            fn reregister(
+                &mut self,
+                registry: &Registry,
+                token: Token,
+                interests: Interest,
+            ) -> io::Result<()> {
+                SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+            }
+
+            fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+                SourceFd(&self.as_raw_fd()).deregister(registry)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "netmap")]
+impl_source!(Netmap);
+#[cfg(feature = "xdp")]
+impl_source!(XdpSocket);
+impl_source!(TapInterface);
+impl_source!(RawSocket);
+impl_source!(UnixDomainSocket);
+impl_source!(PairedDevice);
+// fails for a PcapDevice because regular files can not be polled
+impl_source!(AnyDevice);
+impl_source!(Capture<D>, D);
+impl_source!(Impaired<D>, D);
@@ -1,17 +1,34 @@
+use libc;
 use std::io;
+use std::mem;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::slice;
 use std::sync::{Arc, RwLock};
 
+use batch::FrameBuf;
+use fd_passing::{recv_fd, send_fd, SharedKind, SharedMeta};
+use health::to_smoltcp_error;
 use nm;
 use smoltcp::phy;
 use smoltcp::phy::{Device, DeviceCapabilities};
 use smoltcp::time::Instant;
 use smoltcp::Result;
 
-use SMOLTCP_ETHERNET_HEADER;
+use {DeviceState, Stats, SMOLTCP_ETHERNET_HEADER};
 
 pub use nm::nmreq;
 
+/// The end of a netmap pipe, frames sent on one end are received on the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetmapPipeEnd {
+    /// Opened with `{`, e.g. "netmap:eth0{1".
+    Master,
+    /// Opened with `}`, e.g. "netmap:eth0}1".
+    Slave,
+}
+
 /// Netmap provies a virtual Ethernet interface.
 /// smoltcp compatible Netmap (w/ rx sync ioctl, implicit batched tx, parent mtu, no recv_ready, no explicit issue_tx_sync, no zc_forward)
 #[derive(Debug)]
@@ -34,8 +51,9 @@ impl Netmap {
     /// Since the interface may be a pipe or vale port etc. the `parent` name
     /// refers to the underlying system interface for MTU discovery.
     /// If `uses_wait` is set, then `wait` needs to be used in order to receive
-    /// packets because it calls `select`. If `wait` is not used, then a value
-    /// of `false` for `uses_wait` will cause issueing RXSYNC ioctls on receival.
+    /// packets because its `poll` syncs the RX rings, and also the TX rings.
+    /// If `wait` is not used, then a value of `false` for `uses_wait` will
+    /// cause issueing RXSYNC ioctls on receival.
     pub fn new(
         name: &str,
         parent: &str,
@@ -51,14 +69,116 @@ impl Netmap {
         })
     }
 
+    /// Attaches to the NIC rings (`netmap:eth0`) and the host stack rings
+    /// (`netmap:eth0^`) of the interface `ifname` and returns both as devices
+    /// sharing one memory region, e.g. to pass the frames which no
+    /// application claims on to the kernel and back with `zc_forward` or
+    /// `forward` without copying.
+    ///
+    /// While attached the kernel stack only sees the frames which are
+    /// forwarded to the host stack device, and its frames only reach the NIC
+    /// when received on the host stack device and forwarded to the NIC device.
+    /// See `new` for `uses_wait`.
+    pub fn new_host_pair(
+        ifname: &str,
+        uses_wait: bool,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<(Netmap, Netmap)> {
+        let nic = Netmap::new(
+            &format!("netmap:{}", ifname),
+            ifname,
+            uses_wait,
+            reduce_mtu_by,
+        )?;
+        let mut lower = nm::NetmapDesc::new_shared(
+            &format!("netmap:{}^", ifname),
+            ifname,
+            uses_wait,
+            nic.lower.clone(),
+        )?;
+        let mtu = lower.interface_mtu()?;
+        let host = Netmap {
+            lower: Arc::new(RwLock::new(lower)),
+            mtu: mtu + SMOLTCP_ETHERNET_HEADER,
+            reduce_mtu_by: reduce_mtu_by,
+        };
+        Ok((nic, host))
+    }
+
+    /// Attaches to the end `end` of the pipe `id` of the netmap port `port`
+    /// (e.g. "netmap:eth0" or "vale0:p0"), the other end can be opened by
+    /// another process. The pipe is created with the first of its ends and
+    /// shares the memory region of `port`.
+    ///
+    /// `parent` refers to the system interface for MTU discovery, see `new`
+    /// for `uses_wait`.
+    pub fn new_pipe(
+        port: &str,
+        id: u32,
+        end: NetmapPipeEnd,
+        parent: &str,
+        uses_wait: bool,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<Netmap> {
+        let sep = match end {
+            NetmapPipeEnd::Master => '{',
+            NetmapPipeEnd::Slave => '}',
+        };
+        let name = format!("{}{}{}", port, sep, id);
+        Netmap::new(&name, parent, uses_wait, reduce_mtu_by)
+    }
+
+    /// Attaches to the port `port` of the VALE switch `switch` (e.g. "vale0"),
+    /// both are created if they do not exist yet and the port is removed again
+    /// when the last device attached to it is dropped. Ports of the same
+    /// switch exchange frames like a learning Ethernet bridge.
+    ///
+    /// `parent` refers to the system interface for MTU discovery, see `new`
+    /// for `uses_wait`.
+    pub fn new_vale_port(
+        switch: &str,
+        port: &str,
+        parent: &str,
+        uses_wait: bool,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<Netmap> {
+        if !switch.starts_with("vale") {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "VALE switch names start with vale",
+            ));
+        }
+        let name = format!("{}:{}", switch, port);
+        Netmap::new(&name, parent, uses_wait, reduce_mtu_by)
+    }
+
+    /// Attaches the NIC `ifname` to the VALE switch `switch`, so that its
+    /// frames are switched to the other ports, and with `host_stack` also
+    /// the host stack of the NIC as another port. While attached the NIC can
+    /// not be opened in netmap mode otherwise.
+    pub fn vale_attach(switch: &str, ifname: &str, host_stack: bool) -> io::Result<()> {
+        nm::vale_attach(switch, ifname, host_stack)
+    }
+
+    /// Detaches the NIC `ifname` from the VALE switch `switch`.
+    pub fn vale_detach(switch: &str, ifname: &str) -> io::Result<()> {
+        nm::vale_detach(switch, ifname)
+    }
+
     /// Attaches to a Netmap interface opened by another process which shared
-    /// the file descriptor via Unix Domain Socket sendmsg IPC.
+    /// the file descriptor via Unix Domain Socket sendmsg IPC, see
+    /// `new_from_unix_stream` for a receiver of `send_over_unix_stream`.
     ///
     /// Since the interface may be a pipe or vale port etc. the `parent` name
     /// refers to the underlying system interface for MTU discovery.
     /// If `uses_wait` is set, then `wait` needs to be used in order to receive
-    /// packets because it calls `select`. If `wait` is not used, then a value
-    /// of `false` for `uses_wait` will cause issueing RXSYNC ioctls on receival.
+    /// packets because its `poll` syncs the RX rings, and also the TX rings.
+    /// If `wait` is not used, then a value of `false` for `uses_wait` will
+    /// cause issueing RXSYNC ioctls on receival.
+    ///
+    /// The device takes ownership of `fd`, which is closed on errors too.
+    /// Fails with `InvalidData` if `req` is not of the netmap API version of
+    /// this build, has no rings, a ring out of range or an unknown mode.
     pub fn new_from_shared_fd(
         fd: RawFd,
         req: nmreq,
@@ -75,11 +195,76 @@ impl Netmap {
         })
     }
 
+    /// Sends the file descriptor of the device with `SCM_RIGHTS` over the
+    /// blocking `stream`, together with its `nmreq`, parent name, `uses_wait`
+    /// and MTU, so that another process can attach to the same rings with
+    /// `new_from_unix_stream`. The device stays usable in this process, but
+    /// only one of them should use the rings.
+    pub fn send_over_unix_stream(&self, stream: &UnixStream) -> io::Result<()> {
+        let lower = self.lower.read().unwrap();
+        let req = lower.get_nmreq();
+        let req = unsafe {
+            slice::from_raw_parts(&req as *const nmreq as *const u8, mem::size_of::<nmreq>())
+        };
+        let meta = SharedMeta {
+            parent: lower.parent(),
+            mtu: self.mtu,
+            reduce_mtu_by: self.reduce_mtu_by,
+            uses_wait: lower.get_uses_wait(),
+            extra: req.to_vec(),
+        };
+        send_fd(stream, lower.as_raw_fd(), SharedKind::Netmap, &meta)
+    }
+
+    /// Attaches to a Netmap interface which another process sent with
+    /// `send_over_unix_stream`, without the need to access its parent interface.
+    pub fn new_from_unix_stream(stream: &UnixStream) -> io::Result<Netmap> {
+        let (fd, meta) = recv_fd(stream, SharedKind::Netmap)?;
+        if meta.extra.len() != mem::size_of::<nmreq>() {
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid nmreq"));
+        }
+        let req = unsafe { ptr::read_unaligned(meta.extra.as_ptr() as *const nmreq) };
+        let lower = nm::NetmapDesc::new_from_shared_fd(fd, req, &meta.parent, meta.uses_wait)?;
+        // frames are sent in one netmap buffer
+        meta.check_mtu(lower.buf_size())?;
+        Ok(Netmap {
+            lower: Arc::new(RwLock::new(lower)),
+            mtu: meta.mtu,
+            reduce_mtu_by: meta.reduce_mtu_by,
+        })
+    }
+
     pub fn tx_flush(&mut self) -> Result<()> {
         let mut lower = self.lower.write().unwrap();
         lower.tx_flush()
     }
 
+    /// Whether a TX ring has a free slot, after a TXSYNC if none had one.
+    /// Unlike `transmit`, which hands out a token even if the rings are full.
+    pub(crate) fn tx_ready(&mut self) -> bool {
+        if self.lower.read().unwrap().send_ready().is_ok() {
+            return true;
+        }
+        let _ = self.tx_flush();
+        let mut lower = self.lower.write().unwrap();
+        if lower.send_ready().is_ok() {
+            return true;
+        }
+        lower.stats_mut().tx_would_block += 1;
+        false
+    }
+
+    pub(crate) fn rx_pending(&self) -> bool {
+        self.lower.read().unwrap().rx_pending()
+    }
+
+    pub(crate) fn prepare_poll(&mut self) {
+        self.lower.write().unwrap().prepare_poll()
+    }
+
     pub fn set_uses_wait(&mut self, uses_wait: bool) {
         let mut lower = self.lower.write().unwrap();
         lower.set_uses_wait(uses_wait);
@@ -95,11 +280,125 @@ impl Netmap {
         lower.get_nmreq()
     }
 
+    pub(crate) fn with_last_rx<R, F>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        self.lower.read().unwrap().with_last_rx(f)
+    }
+
     pub fn zc_forward(&mut self, from: &mut Netmap) -> Result<()> {
         let mut lower = self.lower.write().unwrap();
         let mut from_lower = from.lower.write().unwrap();
         lower.zc_forward(&mut from_lower)
     }
+
+    /// Whether both devices use the same netmap memory region (`nr_arg2`),
+    /// which `zc_forward` requires.
+    pub fn shares_memory(&self, other: &Netmap) -> bool {
+        self.get_nmreq().nr_arg2 == other.get_nmreq().nr_arg2
+    }
+
+    /// Receives the next frame on `from` and forwards it without copying,
+    /// if this device has a free TX slot. Returns whether a frame was forwarded.
+    pub(crate) fn zc_forward_next(&mut self, from: &mut Netmap) -> Result<bool> {
+        let mut lower = self.lower.write().unwrap();
+        if lower.send_ready().is_err() {
+            lower.tx_flush()?;
+            if lower.send_ready().is_err() {
+                lower.stats_mut().tx_would_block += 1;
+                return Ok(false);
+            }
+        }
+        let mut from_lower = from.lower.write().unwrap();
+        match from_lower.recv() {
+            Ok(_) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                from_lower.stats_mut().rx_would_block += 1;
+                return Ok(false);
+            }
+            Err(err) => return Err(to_smoltcp_error(&from_lower.health_mut().record(err))),
+        }
+        lower.zc_forward(&mut from_lower)?;
+        Ok(true)
+    }
+
+    /// Forwards up to `max` frames received on `from` without copying them,
+    /// with one TXSYNC for the whole batch. Returns the number of frames
+    /// forwarded together with the error which stopped the batch.
+    pub fn zc_forward_batch(&mut self, from: &mut Netmap, max: usize) -> (usize, Result<()>) {
+        Netmap::zc_switch(from, &mut [self], max, |_| Some(0))
+    }
+
+    /// Forwards up to `max` frames received on `from` without copying them to
+    /// the port of `to` whose index `classify` returns for the frame, or drops
+    /// the frame for `None` or an index out of range. This is the data path of
+    /// a software switch, each port is synced once per batch and all ports have
+    /// to share the memory region of `from`.
+    ///
+    /// Returns the number of frames taken from `from` together with the error
+    /// which stopped the batch, e.g. of a port which failed. The frames taken
+    /// before are forwarded nevertheless. The batch also stops early if a port
+    /// is full.
+    pub fn zc_switch<F>(
+        from: &mut Netmap,
+        to: &mut [&mut Netmap],
+        max: usize,
+        classify: F,
+    ) -> (usize, Result<()>)
+    where
+        F: FnMut(&[u8]) -> Option<usize>,
+    {
+        let mut from_lower = from.lower.write().unwrap();
+        let mut guards: Vec<_> = to.iter().map(|port| port.lower.write().unwrap()).collect();
+        let mut ports: Vec<&mut nm::NetmapDesc> =
+            guards.iter_mut().map(|guard| &mut **guard).collect();
+        from_lower.zc_switch(&mut ports, max, classify)
+    }
+
+    /// Copies up to `bufs.len()` frames out of the RX rings, each buffer is set
+    /// to the length of its frame.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        let mut lower = self.lower.write().unwrap();
+        lower.recv_batch(bufs).map_err(|err| {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                lower.stats_mut().rx_would_block += 1;
+            }
+            lower.health_mut().record(err)
+        })
+    }
+
+    /// Sends the frames and returns how many of them were sent, the TX ring
+    /// is synced once for all of them.
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        let mut lower = self.lower.write().unwrap();
+        // errors of the ring operations are already recorded
+        lower.send_batch(frames).inspect_err(|err| {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                lower.stats_mut().tx_would_block += 1;
+            }
+        })
+    }
+
+    /// Returns the frame counters of the device.
+    pub fn stats(&self) -> Stats {
+        *self.lower.read().unwrap().stats()
+    }
+
+    /// Returns the `DeviceState` of the device.
+    pub fn state(&self) -> DeviceState {
+        self.lower.read().unwrap().health().state()
+    }
+
+    /// Returns the error which caused the current `DeviceState`.
+    pub fn last_error(&self) -> Option<io::Error> {
+        self.lower.read().unwrap().health().last_error()
+    }
+
+    /// Resets the device to `DeviceState::Up`.
+    pub fn clear_error(&mut self) {
+        self.lower.write().unwrap().health_mut().clear();
+    }
 }
 
 impl<'a> Device<'a> for Netmap {
@@ -124,8 +423,14 @@ impl<'a> Device<'a> for Netmap {
                 };
                 Some((rx, tx))
             }
-            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => None,
-            Err(err) => panic!("{}", err),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                lower.stats_mut().rx_would_block += 1;
+                None
+            }
+            Err(err) => {
+                lower.health_mut().record(err);
+                None
+            }
         }
     }
 
@@ -136,6 +441,7 @@ impl<'a> Device<'a> for Netmap {
                 lower: self.lower.clone(),
             }),
             Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                self.lower.write().unwrap().stats_mut().tx_would_block += 1;
                 // workaround for https://github.com/luigirizzo/netmap/issues/457
                 let _ = self.tx_flush();
                 Some(TxToken {
@@ -144,7 +450,12 @@ impl<'a> Device<'a> for Netmap {
                 // done
                 // None
             }
-            Err(err) => panic!("{}", err),
+            Err(err) => {
+                let mut lower = self.lower.write().unwrap();
+                lower.stats_mut().tx_error(&err);
+                lower.health_mut().record(err);
+                None
+            }
         }
     }
 }
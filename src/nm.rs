@@ -5,6 +5,8 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr;
 use std::slice;
 use std::string::ToString;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::{Arc, RwLock};
 
 use smoltcp::{Error, Result};
 
@@ -12,30 +14,220 @@ extern crate netmap_sys;
 
 pub use self::netmap_sys::netmap::nmreq;
 use self::netmap_sys::netmap::{
-    netmap_slot, nm_ring_empty, NETMAP_RING_MASK, NIOCRXSYNC, NIOCTXSYNC, NR_REG_ALL_NIC,
-    NR_REG_MASK, NR_REG_NIC_SW, NR_REG_ONE_NIC, NR_REG_SW, NS_BUF_CHANGED,
+    netmap_if, netmap_ring, netmap_slot, nm_ring_empty, NETMAP_API, NETMAP_BDG_ATTACH,
+    NETMAP_BDG_DETACH, NETMAP_BDG_HOST, NETMAP_HW_RING, NETMAP_RING_MASK, NETMAP_SW_RING,
+    NIOCREGIF, NIOCRXSYNC, NIOCTXSYNC, NR_REG_ALL_NIC, NR_REG_DEFAULT, NR_REG_MASK, NR_REG_NIC_SW,
+    NR_REG_ONE_NIC, NR_REG_PIPE_MASTER, NR_REG_PIPE_SLAVE, NR_REG_SW, NS_BUF_CHANGED,
 };
 use self::netmap_sys::netmap_user::{
-    nm_close, nm_desc, nm_open, nm_ring_next, NETMAP_BUF, NETMAP_FD, NETMAP_RXRING, NETMAP_TXRING,
+    nm_close, nm_desc, nm_open, nm_ring_next, NETMAP_BUF, NETMAP_FD, NETMAP_IF, NETMAP_RXRING,
+    NETMAP_TXRING, NM_OPEN_NO_MMAP,
 };
 
-use super::{ifreq, ifreq_for, ifreq_ioctl, SIOCGIFMTU};
+use super::{ifreq, ifreq_for, ifreq_ioctl, ifreq_name, SIOCGIFMTU};
+use batch::{recv_each, FrameBuf};
+use health::{to_io_error, to_smoltcp_error, Health};
 use libc;
+use stats::Stats;
 
 use libc::c_int;
 
-extern "C" {
-    pub fn nm_mmap(nm_desc: *mut nm_desc, parent: *const nm_desc) -> c_int;
+fn read_buf_size() -> io::Result<u16> {
+    fs::read_to_string("/sys/module/netmap/parameters/buf_size")?
+        .trim_end()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid netmap buf_size"))
+}
+
+/// Attaches the interface `ifname` to the VALE switch `switch` (e.g. "vale0"),
+/// with its host stack rings as second port if `host_stack` is set, like
+/// `vale-ctl -a` or `-h`.
+pub fn vale_attach(switch: &str, ifname: &str, host_stack: bool) -> io::Result<()> {
+    let (flags, arg) = if host_stack {
+        (NR_REG_NIC_SW, NETMAP_BDG_HOST)
+    } else {
+        (NR_REG_ALL_NIC, 0)
+    };
+    vale_ctl(switch, ifname, NETMAP_BDG_ATTACH, arg, flags)
+}
+
+/// Detaches the interface `ifname` from the VALE switch `switch`, like `vale-ctl -d`.
+pub fn vale_detach(switch: &str, ifname: &str) -> io::Result<()> {
+    vale_ctl(switch, ifname, NETMAP_BDG_DETACH, 0, 0)
+}
+
+fn vale_ctl(switch: &str, ifname: &str, cmd: c_int, arg: c_int, flags: u32) -> io::Result<()> {
+    let name = format!("{}:{}", switch, ifname);
+    let mut req: nmreq = unsafe { mem::zeroed() };
+    // the name needs a terminating NUL
+    if !switch.starts_with("vale") || name.len() >= req.nr_name.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid VALE switch or interface name",
+        ));
+    }
+    for (dst, src) in req.nr_name.iter_mut().zip(name.as_bytes()) {
+        *dst = *src as libc::c_char;
+    }
+    req.nr_version = NETMAP_API as u32;
+    req.nr_cmd = cmd as u16;
+    req.nr_arg1 = arg as u16;
+    req.nr_flags = flags;
+    let fd = unsafe {
+        libc::open(
+            "/dev/netmap\0".as_ptr() as *const libc::c_char,
+            libc::O_RDWR,
+        )
+    };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let res = unsafe { libc::ioctl(fd, NIOCREGIF, &mut req as *mut nmreq) };
+    let err = io::Error::last_os_error();
+    unsafe {
+        libc::close(fd);
+    }
+    if res == -1 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Returns the NR_REG mode of `req`. Legacy requests with NR_REG_DEFAULT
+/// select the rings with the NETMAP_SW_RING and NETMAP_HW_RING flags of
+/// `nr_ringid` instead, which the kernel decodes the same way.
+fn reg_mode(req: &nmreq) -> u32 {
+    match req.nr_flags & NR_REG_MASK as u32 {
+        NR_REG_DEFAULT if req.nr_ringid & NETMAP_SW_RING as u16 != 0 => NR_REG_SW,
+        NR_REG_DEFAULT if req.nr_ringid & NETMAP_HW_RING as u16 != 0 => NR_REG_ONE_NIC,
+        NR_REG_DEFAULT => NR_REG_ALL_NIC,
+        mode => mode,
+    }
+}
+
+/// Checks a request received from another process before its rings are used.
+fn validate_nmreq(req: &nmreq) -> io::Result<()> {
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    if req.nr_version != NETMAP_API as u32 {
+        return invalid(format!(
+            "nmreq of netmap API version {}, expected {}",
+            req.nr_version, NETMAP_API
+        ));
+    }
+    if req.nr_tx_rings == 0 || req.nr_rx_rings == 0 {
+        return invalid(format!(
+            "nmreq with {} TX and {} RX rings",
+            req.nr_tx_rings, req.nr_rx_rings
+        ));
+    }
+    if req.nr_offset as usize + mem::size_of::<netmap_if>() > req.nr_memsize as usize {
+        return invalid(format!(
+            "nmreq with interface offset {} outside of the memory region of {} bytes",
+            req.nr_offset, req.nr_memsize
+        ));
+    }
+    match reg_mode(req) {
+        NR_REG_ALL_NIC | NR_REG_SW | NR_REG_NIC_SW | NR_REG_PIPE_MASTER | NR_REG_PIPE_SLAVE => {
+            Ok(())
+        }
+        NR_REG_ONE_NIC => {
+            // like netmap, a direction with fewer rings uses its first ring
+            let ring = req.nr_ringid & NETMAP_RING_MASK as u16;
+            if ring >= req.nr_tx_rings.max(req.nr_rx_rings) {
+                return invalid(format!(
+                    "nmreq for ring {} of {} TX and {} RX rings",
+                    ring, req.nr_tx_rings, req.nr_rx_rings
+                ));
+            }
+            Ok(())
+        }
+        mode => invalid(format!("nmreq with unknown NR_REG mode {}", mode)),
+    }
+}
+
+/// Maps the memory region of a request from another process like `nm_mmap`,
+/// but checks the interface and its rings before they are dereferenced.
+unsafe fn mmap_shared(des: &mut nm_desc) -> io::Result<()> {
+    let mem = libc::mmap(
+        ptr::null_mut(),
+        des.req.nr_memsize as usize,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_SHARED,
+        des.fd,
+        0,
+    );
+    if mem == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    // unmapped by nm_close from here on
+    des.mem = mem;
+    des.memsize = des.req.nr_memsize;
+    des.done_mmap = 1;
+    // validate_nmreq checked that the interface is inside of the region
+    des.nifp = NETMAP_IF(mem, des.req.nr_offset as isize);
+    validate_mapping(des)?;
+    let ring = NETMAP_RXRING(des.nifp, des.first_rx_ring as isize);
+    des.some_ring = ring;
+    des.buf_start = NETMAP_BUF(ring, 0) as *const libc::c_void;
+    des.buf_end = (mem as *const u8).add(des.memsize as usize) as *const libc::c_void;
+    Ok(())
+}
+
+/// Checks the rings selected by a request from another process against the
+/// interface in the mapped memory region, the request alone can not be trusted.
+unsafe fn validate_mapping(des: &nm_desc) -> io::Result<()> {
+    let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    let nifp = des.nifp;
+    let (tx_rings, rx_rings) = ((*nifp).ni_tx_rings, (*nifp).ni_rx_rings);
+    if tx_rings != u32::from(des.req.nr_tx_rings) || rx_rings != u32::from(des.req.nr_rx_rings) {
+        return invalid(format!(
+            "nmreq with {} TX and {} RX rings for an interface with {} and {}",
+            des.req.nr_tx_rings, des.req.nr_rx_rings, tx_rings, rx_rings
+        ));
+    }
+    // the host ring of each direction follows the hardware rings
+    if u32::from(des.last_tx_ring) > tx_rings || u32::from(des.last_rx_ring) > rx_rings {
+        return invalid(format!(
+            "nmreq for TX ring {} and RX ring {} beyond the host rings",
+            des.last_tx_ring, des.last_rx_ring
+        ));
+    }
+    // the mapping has the size of the memory region, as checked by the kernel
+    let start = des.mem as usize;
+    let end = start + des.memsize as usize;
+    let ring_ofs = (tx_rings as usize + rx_rings as usize + 2) * mem::size_of::<isize>();
+    if nifp as usize + mem::size_of::<netmap_if>() + ring_ofs > end {
+        return invalid("nmreq with rings outside of the memory region".to_string());
+    }
+    let tx = (des.first_tx_ring..=des.last_tx_ring).map(|i| NETMAP_TXRING(nifp, i as isize));
+    let rx = (des.first_rx_ring..=des.last_rx_ring).map(|i| NETMAP_RXRING(nifp, i as isize));
+    for ring in tx.chain(rx) {
+        let slots = ring as usize + mem::size_of::<netmap_ring>();
+        if (ring as usize) < start
+            || slots > end
+            || slots + (*ring).num_slots as usize * mem::size_of::<netmap_slot>() > end
+        {
+            return invalid("nmreq with rings outside of the memory region".to_string());
+        }
+    }
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct NetmapDesc {
     nm_desc: *mut nm_desc,
     zc_rx_slot: Option<*mut netmap_slot>,
-    boxed: bool,
+    // RX ring and its new head once the slot of the last received frame is released
+    rx_release: Option<(u16, u32)>,
     buf_size: u16,
     ifreq: ifreq,
     uses_wait: bool,
+    // TX slots were filled since the last TXSYNC
+    tx_unsynced: bool,
+    // the descriptor whose memory mapping is used, kept open while in use
+    mapped_by: Option<Arc<RwLock<NetmapDesc>>>,
+    health: Health,
+    stats: Stats,
 }
 
 unsafe impl Send for NetmapDesc {}
@@ -49,47 +241,90 @@ impl AsRawFd for NetmapDesc {
 
 impl NetmapDesc {
     pub fn new(name: &str, parent: &str, uses_wait: bool) -> io::Result<NetmapDesc> {
+        NetmapDesc::open(name, parent, uses_wait, None)
+    }
+
+    /// Opens `name` like `new`, but reuses the memory mapping of `shared` if
+    /// both use the same memory region, e.g. for the host rings of a NIC.
+    pub fn new_shared(
+        name: &str,
+        parent: &str,
+        uses_wait: bool,
+        shared: Arc<RwLock<NetmapDesc>>,
+    ) -> io::Result<NetmapDesc> {
+        NetmapDesc::open(name, parent, uses_wait, Some(shared))
+    }
+
+    fn open(
+        name: &str,
+        parent: &str,
+        uses_wait: bool,
+        mapped_by: Option<Arc<RwLock<NetmapDesc>>>,
+    ) -> io::Result<NetmapDesc> {
         let ifname = name.to_string() + "\0";
+        let ifreq = ifreq_for(parent)?;
+        let buf_size = read_buf_size()?;
+        let (flags, arg) = match mapped_by {
+            Some(ref shared) => (NM_OPEN_NO_MMAP as u64, shared.read().unwrap().nm_desc),
+            None => (0, ptr::null_mut()),
+        };
         let nm_desc = unsafe {
             nm_open(
                 ifname.as_ptr() as *const libc::c_char,
                 ptr::null(),
-                0,
-                ptr::null(),
+                flags,
+                arg,
             )
         };
 
         if nm_desc.is_null() {
             Err(io::Error::last_os_error())
         } else {
-            let buf_size = fs::read_to_string("/sys/module/netmap/parameters/buf_size")?
-                .trim_end()
-                .parse()
-                .unwrap();
-
             Ok(NetmapDesc {
                 nm_desc: nm_desc,
                 zc_rx_slot: None,
-                boxed: false,
+                rx_release: None,
                 buf_size: buf_size,
-                ifreq: ifreq_for(parent),
+                ifreq: ifreq,
                 uses_wait: uses_wait,
+                tx_unsynced: false,
+                mapped_by: mapped_by,
+                health: Health::default(),
+                stats: Stats::default(),
             })
         }
     }
 
+    /// Maps the rings of `fd` as described by `req`, which is validated since
+    /// it comes from another process. Takes ownership of `fd`, which is
+    /// closed on errors as well.
     pub fn new_from_shared_fd(
         fd: RawFd,
         req: nmreq,
         parent: &str,
         uses_wait: bool,
     ) -> io::Result<NetmapDesc> {
-        let nmd_box: Box<nm_desc> = Box::new(unsafe { mem::zeroed() });
-        let des: &'static mut nm_desc = Box::leak(nmd_box);
+        let (ifreq, buf_size) = ifreq_for(parent)
+            .and_then(|ifreq| {
+                validate_nmreq(&req)?;
+                Ok((ifreq, read_buf_size()?))
+            })
+            .inspect_err(|_| unsafe {
+                libc::close(fd);
+            })?;
+        // allocated like in nm_open because nm_close frees it
+        let des = unsafe { libc::calloc(1, mem::size_of::<nm_desc>()) as *mut nm_desc };
+        if des.is_null() {
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(io::Error::from_raw_os_error(libc::ENOMEM));
+        }
+        let des = unsafe { &mut *des };
         des.self_ = des;
         des.fd = fd;
         des.req = req;
-        match req.nr_flags & NR_REG_MASK as u32 {
+        match reg_mode(&req) {
             NR_REG_SW => {
                 // host stack
                 des.last_tx_ring = des.req.nr_tx_rings;
@@ -97,13 +332,6 @@ impl NetmapDesc {
                 des.last_rx_ring = des.req.nr_rx_rings;
                 des.first_rx_ring = des.last_rx_ring;
             }
-            NR_REG_ALL_NIC => {
-                // only nic
-                des.first_tx_ring = 0;
-                des.first_rx_ring = 0;
-                des.last_tx_ring = des.req.nr_tx_rings - 1;
-                des.last_rx_ring = des.req.nr_rx_rings - 1;
-            }
             NR_REG_NIC_SW => {
                 des.first_tx_ring = 0;
                 des.first_rx_ring = 0;
@@ -112,47 +340,72 @@ impl NetmapDesc {
             }
             NR_REG_ONE_NIC => {
                 let t = des.req.nr_ringid & NETMAP_RING_MASK as u16;
-                des.first_tx_ring = t;
-                des.last_tx_ring = t;
-                des.first_rx_ring = t;
-                des.last_rx_ring = t;
+                let tx = if t < des.req.nr_tx_rings { t } else { 0 };
+                let rx = if t < des.req.nr_rx_rings { t } else { 0 };
+                des.first_tx_ring = tx;
+                des.last_tx_ring = tx;
+                des.first_rx_ring = rx;
+                des.last_rx_ring = rx;
             }
             _ => {
-                // pipes
+                // NR_REG_ALL_NIC, only nic, or all rings of a pipe end
                 des.first_tx_ring = 0;
-                des.last_tx_ring = 0;
                 des.first_rx_ring = 0;
-                des.last_rx_ring = 0;
+                des.last_tx_ring = des.req.nr_tx_rings - 1;
+                des.last_rx_ring = des.req.nr_rx_rings - 1;
             }
         }
 
-        if unsafe { nm_mmap(des, ptr::null()) } != 0 {
-            Err(io::Error::last_os_error())
+        let mapped = unsafe { mmap_shared(des) };
+        if let Err(err) = mapped {
+            // closes the file descriptor, unmaps and frees the descriptor
+            unsafe {
+                nm_close(des);
+            }
+            Err(err)
         } else {
-            let buf_size = fs::read_to_string("/sys/module/netmap/parameters/buf_size")?
-                .trim_end()
-                .parse()
-                .unwrap();
-
             Ok(NetmapDesc {
                 nm_desc: des,
                 zc_rx_slot: None,
-                boxed: true,
+                rx_release: None,
                 buf_size: buf_size,
-                ifreq: ifreq_for(parent),
+                ifreq: ifreq,
                 uses_wait: uses_wait,
+                tx_unsynced: false,
+                mapped_by: None,
+                health: Health::default(),
+                stats: Stats::default(),
             })
         }
     }
 
     pub fn tx_flush(&mut self) -> Result<()> {
+        self.stats.tx_flushes += 1;
         let res = unsafe { libc::ioctl(NETMAP_FD(self.nm_desc), NIOCTXSYNC.into()) };
         if res == -1 {
-            return Err(Error::Illegal);
+            let err = self.health.record(io::Error::last_os_error());
+            return Err(to_smoltcp_error(&err));
         }
+        self.tx_unsynced = false;
         Ok(())
     }
 
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn health_mut(&mut self) -> &mut Health {
+        &mut self.health
+    }
+
     pub fn set_uses_wait(&mut self, uses_wait: bool) {
         self.uses_wait = uses_wait;
     }
@@ -161,6 +414,14 @@ impl NetmapDesc {
         self.uses_wait
     }
 
+    pub fn buf_size(&self) -> usize {
+        usize::from(self.buf_size)
+    }
+
+    pub fn parent(&self) -> String {
+        ifreq_name(&self.ifreq)
+    }
+
     pub fn interface_mtu(&mut self) -> io::Result<usize> {
         let lower = unsafe {
             let lower = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP);
@@ -179,8 +440,25 @@ impl NetmapDesc {
         mtu
     }
 
+    /// Returns the slot of the last received frame to the kernel by moving
+    /// `head` past it. Until then only `cur` is advanced, so that a sync by
+    /// `poll` or `select` from any thread can not reuse the buffer while it
+    /// is read or about to be forwarded.
+    fn release_rx(&mut self) {
+        self.zc_rx_slot = None;
+        if let Some((ri, head)) = self.rx_release.take() {
+            unsafe {
+                let ring = NETMAP_RXRING((*self.nm_desc).nifp, ri as isize);
+                // the buffer swap of zc_forward must be visible before the release
+                fence(Ordering::Release);
+                (*ring).head = head;
+            }
+        }
+    }
+
     pub fn recv(&mut self) -> io::Result<&'static mut [u8]> {
-        unsafe fn find_nextpkt(d: *mut nm_desc) -> Option<(&'static mut [u8], *mut netmap_slot)> {
+        type Found = (&'static mut [u8], *mut netmap_slot, (u16, u32));
+        unsafe fn find_nextpkt(d: *mut nm_desc) -> Option<Found> {
             let mut ri = (*d).cur_rx_ring;
 
             loop {
@@ -193,11 +471,10 @@ impl NetmapDesc {
                     let buf = NETMAP_BUF(ring, (*slot).buf_idx as isize);
                     let slice = slice::from_raw_parts_mut(buf as *mut u8, (*slot).len as usize);
                     let next = nm_ring_next(ring, i);
-                    (*ring).head = next;
                     (*ring).cur = next;
                     (*d).cur_rx_ring = ri as u16;
-                    // read or zero copy forward can only work with this buffer before next syscall
-                    return Some((slice, slot));
+                    // read or zero copy forward can only work with this buffer before next receive
+                    return Some((slice, slot, (ri, next)));
                 }
                 ri += 1;
                 if ri > (*d).last_rx_ring {
@@ -209,22 +486,25 @@ impl NetmapDesc {
             }
             None /* nothing found */
         }
-        if let Some((slice, slot)) = unsafe { find_nextpkt(self.nm_desc) } {
+        // the token of the previous frame was consumed or dropped by now
+        self.release_rx();
+        if let Some((slice, slot, release)) = unsafe { find_nextpkt(self.nm_desc) } {
             self.zc_rx_slot = Some(slot);
+            self.rx_release = Some(release);
+            self.stats.rx(slice.len());
             Ok(slice)
         } else {
             if self.uses_wait {
-                Err(io::Error::new(
-                    io::ErrorKind::WouldBlock,
-                    "needs phy_wait()",
-                ))
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "needs wait()"))
             } else {
                 let res = unsafe { libc::ioctl(NETMAP_FD(self.nm_desc), NIOCRXSYNC.into()) };
                 if res == -1 {
-                    return Err(io::Error::new(io::ErrorKind::Interrupted, "rx sync failed"));
+                    return Err(io::Error::last_os_error());
                 }
-                if let Some((slice, slot)) = unsafe { find_nextpkt(self.nm_desc) } {
+                if let Some((slice, slot, release)) = unsafe { find_nextpkt(self.nm_desc) } {
                     self.zc_rx_slot = Some(slot);
+                    self.rx_release = Some(release);
+                    self.stats.rx(slice.len());
                     Ok(slice)
                 } else {
                     Err(io::Error::new(
@@ -253,6 +533,53 @@ impl NetmapDesc {
     pub fn send<R, F>(&mut self, packet_size: usize, f: F) -> Result<R>
     where
         F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let result = self.fill_tx(packet_size, f)?;
+        if !self.uses_wait || self.send_ready().is_err() {
+            // workaround for https://github.com/luigirizzo/netmap/issues/457
+            self.tx_flush()?;
+        }
+        result
+    }
+
+    /// Copies up to `bufs.len()` frames out of the RX rings, their slots are
+    /// returned to the kernel right away.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        let received = recv_each(bufs, |buf| {
+            let frame = self.recv()?;
+            buf.copy_from(frame);
+            Ok(())
+        });
+        self.release_rx();
+        received
+    }
+
+    /// Fills TX slots with the frames and syncs the TX rings once.
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        let mut sent = 0;
+        for frame in frames {
+            if frame.len() > self.buf_size as usize {
+                if sent > 0 {
+                    break;
+                }
+                return Err(to_io_error(Error::Truncated));
+            }
+            let result = self.fill_tx(frame.len(), |buf| buf.copy_from_slice(frame.as_slice()));
+            match result {
+                Ok(()) => sent += 1,
+                Err(_) if sent > 0 => break,
+                Err(err) => return Err(to_io_error(err)),
+            }
+        }
+        self.tx_flush().map_err(to_io_error)?;
+        Ok(sent)
+    }
+
+    /// Fills the next free TX slot with `f` and advances the ring without
+    /// syncing it, an error means that no slot could be filled.
+    fn fill_tx<R, F>(&mut self, packet_size: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> R,
     {
         assert!(packet_size <= self.buf_size as usize);
         if self.send_ready().is_err() {
@@ -273,55 +600,190 @@ impl NetmapDesc {
                     // packet_size is checked above (buf_size is u16)
                     (*slot).len = packet_size as u16;
                     let result = f(slice); // invoke closure
+                    self.stats.tx(packet_size);
                     let next = nm_ring_next(ring, current);
                     (*ring).head = next;
                     (*ring).cur = next;
-                    if !self.uses_wait || self.send_ready().is_err() {
-                        // workaround for https://github.com/luigirizzo/netmap/issues/457
-                        self.tx_flush()?;
-                    }
-                    return result;
+                    self.tx_unsynced = true;
+                    return Ok(result);
                 }
             }
             Err(Error::Exhausted)
         }
     }
 
+    /// Passes the frame last received to `f`, e.g. to record it before it is
+    /// forwarded with `zc_forward`.
+    pub fn with_last_rx<R, F>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let slot = self.zc_rx_slot?;
+        let (ri, _) = self.rx_release?;
+        unsafe {
+            let ring = NETMAP_RXRING((*self.nm_desc).nifp, ri as isize);
+            let buf = NETMAP_BUF(ring, (*slot).buf_idx as isize);
+            Some(f(slice::from_raw_parts(
+                buf as *const u8,
+                (*slot).len as usize,
+            )))
+        }
+    }
+
     pub fn zc_forward(&mut self, from: &mut NetmapDesc) -> Result<()> {
-        if self.send_ready().is_err() {
-            self.tx_flush()?; // workaround for https://github.com/luigirizzo/netmap/issues/457
+        let src = from.zc_rx_slot.ok_or(Error::Illegal)?;
+        if !unsafe { self.push_zc(src)? } {
+            return Err(Error::Exhausted);
         }
+        // the RX slot now holds the empty buffer of the TX slot
+        from.release_rx();
+        if !self.uses_wait || self.send_ready().is_err() {
+            // workaround for https://github.com/luigirizzo/netmap/issues/457
+            self.tx_flush()?;
+        }
+        Ok(())
+    }
+
+    /// Forwards up to `max` received frames to the ports of `to` chosen by
+    /// `classify` by swapping buffers, and drops the frames for which it
+    /// returns `None` or an index out of range. Returns the number of frames
+    /// taken from the RX rings together with the error which stopped the batch.
+    ///
+    /// Each port is synced once at the end of the batch, also after an error.
+    /// The batch stops early at a full port, whose frame is left for the next call.
+    /// Like `recv`, it starts with the RX ring at which the last batch stopped.
+    pub fn zc_switch<F>(
+        &mut self,
+        to: &mut [&mut NetmapDesc],
+        max: usize,
+        mut classify: F,
+    ) -> (usize, Result<()>)
+    where
+        F: FnMut(&[u8]) -> Option<usize>,
+    {
+        self.release_rx();
+        if !self.uses_wait && !self.rx_pending() {
+            let res = unsafe { libc::ioctl(NETMAP_FD(self.nm_desc), NIOCRXSYNC.into()) };
+            if res == -1 {
+                let err = self.health.record(io::Error::last_os_error());
+                return (0, Err(to_smoltcp_error(&err)));
+            }
+        }
+        let mut taken = 0;
+        let mut result = Ok(());
         unsafe {
-            for i in (*self.nm_desc).first_tx_ring..=(*self.nm_desc).last_tx_ring {
-                let dst_ring = NETMAP_TXRING((*self.nm_desc).nifp, i as isize);
-                if nm_ring_empty(dst_ring) {
-                    continue;
-                } else {
-                    let dst_slots: *mut netmap_slot = mem::transmute(&mut (*dst_ring).slot);
-                    let dst = dst_slots.offset((*dst_ring).cur as isize);
-                    if let Some(mut src) = from.zc_rx_slot {
-                        let tmp = (*dst).buf_idx;
-                        (*dst).buf_idx = (*src).buf_idx;
-                        (*dst).len = (*src).len;
-                        (*dst).flags = NS_BUF_CHANGED;
-                        (*src).buf_idx = tmp;
-                        (*src).flags = NS_BUF_CHANGED;
-                    } else {
-                        return Err(Error::Illegal);
-                    }
-                    from.zc_rx_slot = None;
-                    let next = nm_ring_next(dst_ring, (*dst_ring).cur);
-                    (*dst_ring).head = next;
-                    (*dst_ring).cur = next;
-                    if !self.uses_wait || self.send_ready().is_err() {
-                        // workaround for https://github.com/luigirizzo/netmap/issues/457
-                        self.tx_flush()?;
+            let d = self.nm_desc;
+            let (first, last) = ((*d).first_rx_ring, (*d).last_rx_ring);
+            // start where recv or the last batch stopped, like recv
+            let mut ri = (*d).cur_rx_ring;
+            if ri < first || ri > last {
+                ri = first;
+            }
+            'rings: for _ in first..=last {
+                let ring = NETMAP_RXRING((*d).nifp, ri as isize);
+                while taken < max && !nm_ring_empty(ring) {
+                    let i = (*ring).cur;
+                    let slot = (*ring).slot.as_mut_ptr().offset(i as isize);
+                    let len = usize::from((*slot).len);
+                    let buf = NETMAP_BUF(ring, (*slot).buf_idx as isize);
+                    let frame = slice::from_raw_parts(buf as *const u8, len);
+                    match classify(frame) {
+                        Some(port) if port < to.len() => match to[port].push_zc(slot) {
+                            Ok(true) => {}
+                            Ok(false) => break 'rings,
+                            Err(err) => {
+                                result = Err(err);
+                                break 'rings;
+                            }
+                        },
+                        _ => {}
                     }
-                    return Ok(());
+                    self.stats.rx(len);
+                    let next = nm_ring_next(ring, i);
+                    (*ring).cur = next;
+                    // the buffer swap must be visible before the release
+                    fence(Ordering::Release);
+                    (*ring).head = next;
+                    taken += 1;
+                }
+                ri = if ri == last { first } else { ri + 1 };
+                if taken == max {
+                    // the next batch starts with the following ring, so that
+                    // a busy ring does not starve the others
+                    break;
+                }
+            }
+            (*d).cur_rx_ring = ri;
+        }
+        for port in to.iter_mut() {
+            if port.tx_unsynced {
+                if let Err(err) = port.tx_flush() {
+                    result = result.and(Err(err));
                 }
             }
-            Err(Error::Exhausted)
         }
+        (taken, result)
+    }
+
+    /// Whether the RX rings hold frames which were not received yet.
+    pub fn rx_pending(&self) -> bool {
+        unsafe {
+            let d = self.nm_desc;
+            ((*d).first_rx_ring..=(*d).last_rx_ring)
+                .any(|ri| !nm_ring_empty(NETMAP_RXRING((*d).nifp, ri as isize)))
+        }
+    }
+
+    /// Releases the last received slot before a `poll` on the file descriptor,
+    /// which would report it as readable otherwise. The `poll` also syncs
+    /// the TX rings, thus the frames sent since the last `tx_flush` count as
+    /// flushed.
+    pub fn prepare_poll(&mut self) {
+        self.release_rx();
+        if self.tx_unsynced {
+            self.stats.tx_flushes += 1;
+            self.tx_unsynced = false;
+        }
+    }
+
+    fn free_tx_ring(&self) -> Option<*mut netmap_ring> {
+        unsafe {
+            let d = self.nm_desc;
+            ((*d).first_tx_ring..=(*d).last_tx_ring)
+                .map(|i| NETMAP_TXRING((*d).nifp, i as isize))
+                .find(|&ring| !nm_ring_empty(ring))
+        }
+    }
+
+    /// Swaps the buffer of the RX slot `src` into the next free TX slot without
+    /// syncing the TX ring. Returns `false` if all TX rings stay full after a sync.
+    unsafe fn push_zc(&mut self, src: *mut netmap_slot) -> Result<bool> {
+        let dst_ring = match self.free_tx_ring() {
+            Some(ring) => ring,
+            None => {
+                // workaround for https://github.com/luigirizzo/netmap/issues/457
+                self.tx_flush()?;
+                match self.free_tx_ring() {
+                    Some(ring) => ring,
+                    None => return Ok(false),
+                }
+            }
+        };
+        let dst_slots = (*dst_ring).slot.as_mut_ptr();
+        let dst = dst_slots.offset((*dst_ring).cur as isize);
+        self.stats.tx(usize::from((*src).len));
+        self.stats.zc_forwards += 1;
+        let tmp = (*dst).buf_idx;
+        (*dst).buf_idx = (*src).buf_idx;
+        (*dst).len = (*src).len;
+        (*dst).flags = NS_BUF_CHANGED;
+        (*src).buf_idx = tmp;
+        (*src).flags = NS_BUF_CHANGED;
+        let next = nm_ring_next(dst_ring, (*dst_ring).cur);
+        (*dst_ring).head = next;
+        (*dst_ring).cur = next;
+        self.tx_unsynced = true;
+        Ok(true)
     }
 
     pub fn get_nmreq(&self) -> nmreq {
@@ -334,8 +796,7 @@ impl Drop for NetmapDesc {
         unsafe {
             nm_close(self.nm_desc);
         }
-        if self.boxed {
-            let _ = unsafe { Box::from_raw(self.nm_desc) };
-        }
+        // only now the memory mapping of the other descriptor may go away
+        self.mapped_by.take();
     }
 }
@@ -0,0 +1,495 @@
+use libc;
+use std::io;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use smoltcp::{Error, Result};
+
+const SOL_PACKET: libc::c_int = 263;
+const PACKET_RX_RING: libc::c_int = 5;
+const PACKET_VERSION: libc::c_int = 10;
+const PACKET_TX_RING: libc::c_int = 13;
+const TPACKET_V3: libc::c_int = 2;
+
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+const TP_STATUS_AVAILABLE: u32 = 0;
+const TP_STATUS_SEND_REQUEST: u32 = 1;
+const TP_STATUS_WRONG_FORMAT: u32 = 4;
+
+/// TPACKET_ALIGN(sizeof(struct tpacket3_hdr)), the TX payload starts here
+const TPACKET3_HDR_ALIGNED: usize = 48;
+
+/// Offsets in struct tpacket_block_desc (with tpacket_hdr_v1)
+const BLOCK_STATUS: usize = 8;
+const BLOCK_NUM_PKTS: usize = 12;
+const BLOCK_OFFSET_TO_FIRST_PKT: usize = 16;
+
+/// Offsets in struct tpacket3_hdr
+const TP_NEXT_OFFSET: usize = 0;
+const TP_SNAPLEN: usize = 12;
+const TP_LEN: usize = 16;
+const TP_STATUS: usize = 20;
+const TP_MAC: usize = 24;
+
+#[repr(C)]
+struct tpacket_req3 {
+    tp_block_size: libc::c_uint,
+    tp_block_nr: libc::c_uint,
+    tp_frame_size: libc::c_uint,
+    tp_frame_nr: libc::c_uint,
+    tp_retire_blk_tov: libc::c_uint,
+    tp_sizeof_priv: libc::c_uint,
+    tp_feature_req_word: libc::c_uint,
+}
+
+/// Layout of the memory-mapped `PACKET_RX_RING` and `PACKET_TX_RING`
+/// (TPACKET_V3) used by `RawSocket::new_mmap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketRingConfig {
+    /// Size of a ring block in bytes, must be a power of two multiple of the page size.
+    pub block_size: usize,
+    /// Number of blocks in the RX ring.
+    pub rx_blocks: usize,
+    /// Number of blocks in the TX ring.
+    pub tx_blocks: usize,
+    /// Milliseconds after which the kernel hands over a partially filled RX block.
+    pub retire_timeout_ms: u32,
+}
+
+impl Default for PacketRingConfig {
+    fn default() -> PacketRingConfig {
+        PacketRingConfig {
+            block_size: 1 << 18,
+            rx_blocks: 16,
+            tx_blocks: 4,
+            retire_timeout_ms: 10,
+        }
+    }
+}
+
+/// The mapping of both rings, which stays alive as long as a `RingFrame`
+/// borrows from it, even after the `PacketRing` and the socket are gone.
+#[derive(Debug)]
+struct RingMap {
+    map: *mut u8,
+    map_len: usize,
+    block_size: usize,
+    /// Frames of each RX block which are still in use, plus one while
+    /// `recv` reads from the block. The block goes back to the kernel when
+    /// this drops to zero.
+    rx_refs: Vec<AtomicUsize>,
+    /// Whether a RX block belongs to user space, it can not be read again
+    /// before it was returned to the kernel.
+    rx_owned: Vec<AtomicBool>,
+}
+
+unsafe impl Send for RingMap {}
+unsafe impl Sync for RingMap {}
+
+impl RingMap {
+    fn rx_block_ptr(&self, block: usize) -> *mut u8 {
+        unsafe { self.map.add(block * self.block_size) }
+    }
+
+    fn release_rx_block(&self, block: usize) {
+        if self.rx_refs[block].fetch_sub(1, Ordering::AcqRel) == 1 {
+            fence(Ordering::Release);
+            unsafe { write_u32(self.rx_block_ptr(block).add(BLOCK_STATUS), TP_STATUS_KERNEL) };
+            self.rx_owned[block].store(false, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for RingMap {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.map_len);
+        }
+    }
+}
+
+/// A received frame in the RX ring, its block is returned to the kernel
+/// once all frames of the block were dropped.
+#[derive(Debug)]
+pub struct RingFrame {
+    map: Arc<RingMap>,
+    block: usize,
+    data: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for RingFrame {}
+
+impl RingFrame {
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.data, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.data, self.len) }
+    }
+}
+
+impl Drop for RingFrame {
+    fn drop(&mut self) {
+        self.map.release_rx_block(self.block);
+    }
+}
+
+#[derive(Debug)]
+pub struct PacketRing {
+    map: Arc<RingMap>,
+    rx_blocks: usize,
+    rx_block: usize,
+    rx_next_pkt: Option<(*mut u8, u32)>,
+    tx_ring: *mut u8,
+    tx_frame_size: usize,
+    tx_frames: usize,
+    tx_frame: usize,
+}
+
+unsafe impl Send for PacketRing {}
+unsafe impl Sync for PacketRing {}
+
+unsafe fn read_u32(p: *const u8) -> u32 {
+    ptr::read_volatile(p as *const u32)
+}
+
+unsafe fn write_u32(p: *mut u8, v: u32) {
+    ptr::write_volatile(p as *mut u32, v)
+}
+
+fn setsockopt<T>(fd: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_PACKET,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl PacketRing {
+    /// Sets up and maps the rings on the AF_PACKET socket `fd`, which must
+    /// not be bound yet. TX frames are sized to fit `max_frame` bytes.
+    pub fn new(
+        fd: libc::c_int,
+        config: PacketRingConfig,
+        max_frame: usize,
+    ) -> io::Result<PacketRing> {
+        let tx_frame_size = (max_frame + TPACKET3_HDR_ALIGNED).next_power_of_two();
+        if config.block_size < tx_frame_size
+            || !config.block_size.is_power_of_two()
+            || config.rx_blocks == 0
+            || config.tx_blocks == 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid packet ring configuration",
+            ));
+        }
+        setsockopt(fd, PACKET_VERSION, &TPACKET_V3)?;
+        let rx_req = tpacket_req3 {
+            tp_block_size: config.block_size as libc::c_uint,
+            tp_block_nr: config.rx_blocks as libc::c_uint,
+            // only used for sanity checks on RX, frames are variable-sized in V3
+            tp_frame_size: tx_frame_size as libc::c_uint,
+            tp_frame_nr: (config.block_size / tx_frame_size * config.rx_blocks) as libc::c_uint,
+            tp_retire_blk_tov: config.retire_timeout_ms,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(fd, PACKET_RX_RING, &rx_req)?;
+        let tx_frames = config.block_size / tx_frame_size * config.tx_blocks;
+        let tx_req = tpacket_req3 {
+            tp_block_size: config.block_size as libc::c_uint,
+            tp_block_nr: config.tx_blocks as libc::c_uint,
+            tp_frame_size: tx_frame_size as libc::c_uint,
+            tp_frame_nr: tx_frames as libc::c_uint,
+            tp_retire_blk_tov: 0,
+            tp_sizeof_priv: 0,
+            tp_feature_req_word: 0,
+        };
+        setsockopt(fd, PACKET_TX_RING, &tx_req)?;
+
+        let map_len = config.block_size * (config.rx_blocks + config.tx_blocks);
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PacketRing::from_map(
+            map as *mut u8,
+            map_len,
+            config,
+            tx_frame_size,
+        ))
+    }
+
+    /// Takes ownership of the mapping `map` of both rings.
+    fn from_map(
+        map: *mut u8,
+        map_len: usize,
+        config: PacketRingConfig,
+        tx_frame_size: usize,
+    ) -> PacketRing {
+        PacketRing {
+            map: Arc::new(RingMap {
+                map,
+                map_len,
+                block_size: config.block_size,
+                rx_refs: (0..config.rx_blocks).map(|_| AtomicUsize::new(0)).collect(),
+                rx_owned: (0..config.rx_blocks)
+                    .map(|_| AtomicBool::new(false))
+                    .collect(),
+            }),
+            rx_blocks: config.rx_blocks,
+            rx_block: 0,
+            rx_next_pkt: None,
+            tx_ring: unsafe { map.add(config.block_size * config.rx_blocks) },
+            tx_frame_size,
+            tx_frames: config.block_size / tx_frame_size * config.tx_blocks,
+            tx_frame: 0,
+        }
+    }
+
+    /// Capacity of a TX frame.
+    pub fn tx_frame_capacity(&self) -> usize {
+        self.tx_frame_size - TPACKET3_HDR_ALIGNED
+    }
+
+    /// Returns the next frame and its length on the wire, which is larger if
+    /// the frame was cut to fit into the ring frame.
+    pub fn recv(&mut self) -> io::Result<(RingFrame, usize)> {
+        unsafe {
+            let (pkt, remaining) = loop {
+                if let Some(next) = self.rx_next_pkt {
+                    break next;
+                }
+                let block = self.rx_block;
+                let block_ptr = self.map.rx_block_ptr(block);
+                // a block still in use after a wrap around keeps its status
+                if self.map.rx_owned[block].load(Ordering::Acquire)
+                    || read_u32(block_ptr.add(BLOCK_STATUS)) & TP_STATUS_USER == 0
+                {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "rx ring empty"));
+                }
+                fence(Ordering::Acquire);
+                self.map.rx_owned[block].store(true, Ordering::Relaxed);
+                self.map.rx_refs[block].store(1, Ordering::Relaxed);
+                let first = read_u32(block_ptr.add(BLOCK_OFFSET_TO_FIRST_PKT)) as usize;
+                let num_pkts = read_u32(block_ptr.add(BLOCK_NUM_PKTS));
+                if num_pkts == 0 {
+                    self.finish_rx_block();
+                } else {
+                    self.rx_next_pkt = Some((block_ptr.add(first), num_pkts));
+                }
+            };
+            let next = read_u32(pkt.add(TP_NEXT_OFFSET)) as usize;
+            let mac = usize::from(ptr::read_volatile(pkt.add(TP_MAC) as *const u16));
+            let snaplen = read_u32(pkt.add(TP_SNAPLEN)) as usize;
+            let len = read_u32(pkt.add(TP_LEN)) as usize;
+            let block = self.rx_block;
+            self.map.rx_refs[block].fetch_add(1, Ordering::Relaxed);
+            let frame = RingFrame {
+                map: self.map.clone(),
+                block,
+                data: pkt.add(mac),
+                len: snaplen,
+            };
+            if remaining == 1 {
+                self.finish_rx_block();
+            } else {
+                self.rx_next_pkt = Some((pkt.add(next), remaining - 1));
+            }
+            Ok((frame, len))
+        }
+    }
+
+    /// Moves on to the next block after all frames of the current one were
+    /// handed out, it goes back to the kernel when they are dropped.
+    fn finish_rx_block(&mut self) {
+        self.map.release_rx_block(self.rx_block);
+        self.rx_block = (self.rx_block + 1) % self.rx_blocks;
+        self.rx_next_pkt = None;
+    }
+
+    fn tx_frame_ptr(&self) -> *mut u8 {
+        unsafe { self.tx_ring.add(self.tx_frame * self.tx_frame_size) }
+    }
+
+    pub fn send_ready(&self) -> bool {
+        let status = unsafe { read_u32(self.tx_frame_ptr().add(TP_STATUS)) };
+        status == TP_STATUS_AVAILABLE || status == TP_STATUS_WRONG_FORMAT
+    }
+
+    /// Fills the next free TX frame, which the kernel transmits on the next `send`.
+    pub fn send<R, F>(&mut self, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        if len > self.tx_frame_capacity() {
+            return Err(Error::Truncated);
+        }
+        if !self.send_ready() {
+            return Err(Error::Exhausted);
+        }
+        let frame = self.tx_frame_ptr();
+        fence(Ordering::Acquire);
+        let result = unsafe {
+            let data = slice::from_raw_parts_mut(frame.add(TPACKET3_HDR_ALIGNED), len);
+            let result = f(data);
+            write_u32(frame.add(TP_NEXT_OFFSET), 0);
+            write_u32(frame.add(TP_LEN), len as u32);
+            write_u32(frame.add(TP_SNAPLEN), len as u32);
+            fence(Ordering::Release);
+            write_u32(frame.add(TP_STATUS), TP_STATUS_SEND_REQUEST);
+            result
+        };
+        self.tx_frame = (self.tx_frame + 1) % self.tx_frames;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 4096;
+    const TX_FRAME_SIZE: usize = 2048;
+
+    /// Maps anonymous memory in place of the socket rings, the tests play the
+    /// kernel by filling RX blocks.
+    fn ring(rx_blocks: usize) -> PacketRing {
+        let config = PacketRingConfig {
+            block_size: BLOCK_SIZE,
+            rx_blocks,
+            tx_blocks: 1,
+            retire_timeout_ms: 0,
+        };
+        let map_len = BLOCK_SIZE * (rx_blocks + 1);
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(map, libc::MAP_FAILED);
+        PacketRing::from_map(map as *mut u8, map_len, config, TX_FRAME_SIZE)
+    }
+
+    fn block_status(ring: &PacketRing, block: usize) -> u32 {
+        unsafe { read_u32(ring.map.rx_block_ptr(block).add(BLOCK_STATUS)) }
+    }
+
+    /// Hands the frames over to user space in `block`.
+    fn fill_block(ring: &PacketRing, block: usize, frames: &[&[u8]]) {
+        const FIRST: usize = 48;
+        const MAC: usize = 64;
+        unsafe {
+            let block_ptr = ring.map.rx_block_ptr(block);
+            write_u32(block_ptr.add(BLOCK_NUM_PKTS), frames.len() as u32);
+            write_u32(block_ptr.add(BLOCK_OFFSET_TO_FIRST_PKT), FIRST as u32);
+            let mut pkt = block_ptr.add(FIRST);
+            for frame in frames {
+                let next = (MAC + frame.len() + 15) & !15; // TPACKET_ALIGN
+                write_u32(pkt.add(TP_NEXT_OFFSET), next as u32);
+                write_u32(pkt.add(TP_SNAPLEN), frame.len() as u32);
+                write_u32(pkt.add(TP_LEN), frame.len() as u32);
+                ptr::write_volatile(pkt.add(TP_MAC) as *mut u16, MAC as u16);
+                ptr::copy_nonoverlapping(frame.as_ptr(), pkt.add(MAC), frame.len());
+                pkt = pkt.add(next);
+            }
+            write_u32(block_ptr.add(BLOCK_STATUS), TP_STATUS_USER);
+        }
+    }
+
+    fn would_block<T>(result: io::Result<T>) -> bool {
+        match result {
+            Err(err) => err.kind() == io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn recv_returns_frames_of_a_block() {
+        let mut ring = ring(2);
+        assert!(would_block(ring.recv()));
+        fill_block(&ring, 0, &[b"first", b"second frame"]);
+        let (first, len) = ring.recv().unwrap();
+        assert_eq!(first.as_slice(), b"first");
+        assert_eq!(len, 5);
+        let (second, _) = ring.recv().unwrap();
+        assert_eq!(second.as_slice(), b"second frame");
+        assert!(would_block(ring.recv()));
+    }
+
+    #[test]
+    fn block_is_released_after_all_frames_dropped() {
+        let mut ring = ring(2);
+        fill_block(&ring, 0, &[b"a", b"b"]);
+        let (a, _) = ring.recv().unwrap();
+        let (b, _) = ring.recv().unwrap();
+        assert_eq!(block_status(&ring, 0), TP_STATUS_USER);
+        drop(b);
+        assert_eq!(block_status(&ring, 0), TP_STATUS_USER);
+        assert_eq!(a.as_slice(), b"a");
+        drop(a);
+        assert_eq!(block_status(&ring, 0), TP_STATUS_KERNEL);
+    }
+
+    #[test]
+    fn block_in_use_is_not_read_again_after_wrap_around() {
+        let mut ring = ring(1);
+        fill_block(&ring, 0, &[b"a"]);
+        let (a, _) = ring.recv().unwrap();
+        // the status still says user space, but the block was not returned yet
+        assert!(would_block(ring.recv()));
+        drop(a);
+        assert!(would_block(ring.recv()));
+        fill_block(&ring, 0, &[b"b"]);
+        let (b, _) = ring.recv().unwrap();
+        assert_eq!(b.as_slice(), b"b");
+    }
+
+    #[test]
+    fn empty_block_is_skipped() {
+        let mut ring = ring(2);
+        fill_block(&ring, 0, &[]);
+        fill_block(&ring, 1, &[b"a"]);
+        let (a, _) = ring.recv().unwrap();
+        assert_eq!(a.as_slice(), b"a");
+        assert_eq!(block_status(&ring, 0), TP_STATUS_KERNEL);
+    }
+
+    #[test]
+    fn frame_outlives_ring() {
+        let mut ring = ring(1);
+        fill_block(&ring, 0, &[b"kept"]);
+        let (mut frame, _) = ring.recv().unwrap();
+        drop(ring);
+        frame.as_mut_slice()[0] = b'K';
+        assert_eq!(frame.as_slice(), b"Kept");
+    }
+}
@@ -0,0 +1,185 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, RwLock};
+use std::vec::Vec;
+
+use health::to_smoltcp_error;
+use pcap_device_sys;
+use smoltcp::phy;
+use smoltcp::phy::{Device, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::{Error, Result};
+
+use {DeviceState, Stats, SMOLTCP_ETHERNET_HEADER};
+
+/// MTU of the replayed interface, not stored in pcap files.
+const PCAP_DEVICE_MTU: usize = 1500;
+
+/// Replays the frames of a pcap or pcapng file as received frames and
+/// records transmitted frames in another (pcapng) file.
+#[derive(Debug)]
+pub struct PcapDevice {
+    lower: Arc<RwLock<pcap_device_sys::PcapDeviceDesc>>,
+    mtu: usize,
+    reduce_mtu_by: Option<usize>,
+    medium: Medium,
+}
+
+impl AsRawFd for PcapDevice {
+    fn as_raw_fd(&self) -> RawFd {
+        self.lower.read().unwrap().as_raw_fd()
+    }
+}
+
+impl PcapDevice {
+    /// Opens the pcap or pcapng file `input` for RX and creates the file
+    /// `output` for TX, the medium follows from the link type of `input`.
+    ///
+    /// Frames are received as fast as they are consumed unless
+    /// `replay_timing` is set: Then the first frame is received at once and
+    /// the timestamp passed to its `consume` is taken as reference. Later
+    /// frames are only received when the `consume` timestamp reached their
+    /// original offset to the first frame (see `next_due`).
+    pub fn new(
+        input: &str,
+        output: &str,
+        replay_timing: bool,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<PcapDevice> {
+        let lower = pcap_device_sys::PcapDeviceDesc::new(input, output, replay_timing)?;
+        let medium = lower.medium();
+        let mtu = match medium {
+            Medium::Ethernet => PCAP_DEVICE_MTU + SMOLTCP_ETHERNET_HEADER,
+            _ => PCAP_DEVICE_MTU,
+        };
+        Ok(PcapDevice {
+            lower: Arc::new(RwLock::new(lower)),
+            mtu,
+            reduce_mtu_by,
+            medium,
+        })
+    }
+
+    /// Returns when the next frame is due if the timing is replayed, and
+    /// `None` if it can be received at once or the file has no more frames.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.lower.read().unwrap().next_due()
+    }
+
+    /// Whether all frames of the input file were received.
+    pub fn finished(&self) -> bool {
+        self.lower.read().unwrap().finished()
+    }
+
+    /// Writes buffered TX frames to the output file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.lower.write().unwrap().flush()
+    }
+
+    /// Returns the frame counters of the device.
+    pub fn stats(&self) -> Stats {
+        *self.lower.read().unwrap().stats()
+    }
+
+    /// Returns the `DeviceState` of the device.
+    pub fn state(&self) -> DeviceState {
+        self.lower.read().unwrap().health().state()
+    }
+
+    /// Returns the error which caused the current `DeviceState`.
+    pub fn last_error(&self) -> Option<io::Error> {
+        self.lower.read().unwrap().health().last_error()
+    }
+
+    /// Resets the state to `DeviceState::Up`.
+    pub fn clear_error(&mut self) {
+        self.lower.write().unwrap().health_mut().clear();
+    }
+}
+
+impl<'a> Device<'a> for PcapDevice {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = self.medium;
+        caps.max_transmission_unit = self.mtu - self.reduce_mtu_by.unwrap_or(0);
+        caps
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let mut lower = self.lower.write().unwrap();
+        match lower.recv_ready() {
+            Ok(true) => {
+                let rx = RxToken {
+                    lower: self.lower.clone(),
+                };
+                let tx = TxToken {
+                    lower: self.lower.clone(),
+                };
+                Some((rx, tx))
+            }
+            Ok(false) => {
+                lower.stats_mut().rx_would_block += 1;
+                None
+            }
+            Err(err) => {
+                lower.health_mut().record(err);
+                None
+            }
+        }
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        Some(TxToken {
+            lower: self.lower.clone(),
+        })
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken {
+    lower: Arc<RwLock<pcap_device_sys::PcapDeviceDesc>>,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        // the lock is released before f which may transmit a response
+        let r = self.lower.write().unwrap().recv(timestamp);
+        match r {
+            Ok(mut buffer) => f(&mut buffer[..]),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                self.lower.write().unwrap().stats_mut().rx_would_block += 1;
+                Err(Error::Exhausted)
+            }
+            Err(err) => {
+                let mut lower = self.lower.write().unwrap();
+                Err(to_smoltcp_error(&lower.health_mut().record(err)))
+            }
+        }
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken {
+    lower: Arc<RwLock<pcap_device_sys::PcapDeviceDesc>>,
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut buffer: Vec<u8> = vec![0; len];
+        let result = f(&mut buffer)?;
+        let mut lower = self.lower.write().unwrap();
+        match lower.send(timestamp, &buffer[..]) {
+            Ok(_) => Ok(result),
+            Err(err) => Err(to_smoltcp_error(&lower.health_mut().record(err))),
+        }
+    }
+}
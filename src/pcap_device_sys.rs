@@ -0,0 +1,580 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use smoltcp::phy::Medium;
+use smoltcp::time::Instant;
+
+use health::Health;
+use pcapng::{Direction, PcapngSink};
+use stats::Stats;
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAPNG_SHB: u32 = 0x0A0D_0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const PCAPNG_IDB: u32 = 0x0000_0001;
+const PCAPNG_SPB: u32 = 0x0000_0003;
+const PCAPNG_EPB: u32 = 0x0000_0006;
+const OPT_IF_TSRESOL: u16 = 9;
+/// Frames above this length are rejected as malformed, it is the largest
+/// snapshot length written by tcpdump and wireshark
+const MAX_FRAME_LEN: usize = 1 << 18;
+/// Blocks above this length are rejected before their body is allocated
+const MAX_BLOCK_LEN: usize = 1 << 22;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn medium_for(linktype: u32) -> io::Result<Medium> {
+    match linktype {
+        1 => Ok(Medium::Ethernet),
+        101 | 228 | 229 => Ok(Medium::Ip),
+        230 => Ok(Medium::Ieee802154),
+        _ => Err(invalid("unsupported pcap link type")),
+    }
+}
+
+#[derive(Debug)]
+enum Format {
+    Pcap {
+        units_per_sec: u64,
+    },
+    Pcapng {
+        /// Timestamp units per second of each interface
+        interfaces: Vec<u64>,
+    },
+}
+
+/// A frame with its timestamp in microseconds.
+#[derive(Debug)]
+pub struct PcapFrame {
+    pub timestamp: i64,
+    pub data: Vec<u8>,
+}
+
+/// Reads the frames of a pcap or pcapng file in either byte order.
+#[derive(Debug)]
+struct PcapReader {
+    reader: BufReader<File>,
+    swapped: bool,
+    format: Format,
+    medium: Medium,
+    last_timestamp: i64,
+}
+
+impl PcapReader {
+    fn open(path: &str) -> io::Result<PcapReader> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        let mut pcap = PcapReader {
+            reader,
+            swapped: false,
+            format: Format::Pcapng {
+                interfaces: Vec::new(),
+            },
+            medium: Medium::Ethernet,
+            last_timestamp: 0,
+        };
+        let magic = u32::from_ne_bytes(magic);
+        if magic == PCAPNG_SHB {
+            pcap.read_pcapng_block(PCAPNG_SHB)?;
+            // the link type is known after the first interface description
+            while let Format::Pcapng { ref interfaces } = pcap.format {
+                if !interfaces.is_empty() {
+                    break;
+                }
+                let block_type = pcap.u32()?;
+                pcap.read_pcapng_block(block_type)?;
+            }
+            return Ok(pcap);
+        }
+        let units_per_sec = match magic {
+            PCAP_MAGIC_MICROS => 1_000_000,
+            PCAP_MAGIC_NANOS => 1_000_000_000,
+            _ => match magic.swap_bytes() {
+                PCAP_MAGIC_MICROS => 1_000_000,
+                PCAP_MAGIC_NANOS => 1_000_000_000,
+                _ => return Err(invalid("not a pcap or pcapng file")),
+            },
+        };
+        pcap.swapped = magic != PCAP_MAGIC_MICROS && magic != PCAP_MAGIC_NANOS;
+        pcap.format = Format::Pcap { units_per_sec };
+        let mut header = [0; 20];
+        pcap.reader.read_exact(&mut header)?;
+        let linktype = pcap.word(&header[16..20]) & 0x0fff_ffff;
+        pcap.medium = medium_for(linktype)?;
+        Ok(pcap)
+    }
+
+    fn word(&self, b: &[u8]) -> u32 {
+        let v = u32::from_ne_bytes([b[0], b[1], b[2], b[3]]);
+        if self.swapped {
+            v.swap_bytes()
+        } else {
+            v
+        }
+    }
+
+    fn half(&self, b: &[u8]) -> u16 {
+        let v = u16::from_ne_bytes([b[0], b[1]]);
+        if self.swapped {
+            v.swap_bytes()
+        } else {
+            v
+        }
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        self.reader.read_exact(&mut b)?;
+        Ok(self.word(&b))
+    }
+
+    fn to_micros(ts: u64, units_per_sec: u64) -> i64 {
+        (u128::from(ts) * 1_000_000 / u128::from(units_per_sec)) as i64
+    }
+
+    /// Reads the rest of a pcapng block after its type and returns the
+    /// frame if it is a packet block.
+    fn read_pcapng_block(&mut self, block_type: u32) -> io::Result<Option<PcapFrame>> {
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        if block_type == PCAPNG_SHB {
+            // a new section may change the byte order
+            let mut magic = [0; 4];
+            self.reader.read_exact(&mut magic)?;
+            self.swapped = match u32::from_ne_bytes(magic) {
+                PCAPNG_BYTE_ORDER_MAGIC => false,
+                m if m.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => true,
+                _ => return Err(invalid("invalid pcapng byte order magic")),
+            };
+            self.format = Format::Pcapng {
+                interfaces: Vec::new(),
+            };
+        }
+        let total = self.word(&len) as usize;
+        let read = if block_type == PCAPNG_SHB { 12 } else { 8 };
+        if total < read + 4 || total & 3 != 0 || total > MAX_BLOCK_LEN {
+            return Err(invalid("invalid pcapng block length"));
+        }
+        let mut body = vec![0; total - read];
+        self.reader.read_exact(&mut body)?;
+        let body = &body[..total - read - 4];
+        match block_type {
+            PCAPNG_IDB if body.len() >= 8 => {
+                let linktype = u32::from(self.half(&body[0..2]));
+                let units_per_sec = self.if_tsresol(&body[8..])?;
+                if let Format::Pcapng { ref mut interfaces } = self.format {
+                    if interfaces.is_empty() {
+                        self.medium = medium_for(linktype)?;
+                    }
+                    interfaces.push(units_per_sec);
+                }
+                Ok(None)
+            }
+            PCAPNG_EPB if body.len() >= 20 => {
+                let interface = self.word(&body[0..4]) as usize;
+                let ts =
+                    u64::from(self.word(&body[4..8])) << 32 | u64::from(self.word(&body[8..12]));
+                let caplen = self.word(&body[12..16]) as usize;
+                if caplen > body.len() - 20 || caplen > MAX_FRAME_LEN {
+                    return Err(invalid("invalid pcapng packet length"));
+                }
+                let units_per_sec = match self.format {
+                    Format::Pcapng { ref interfaces } => interfaces.get(interface).cloned(),
+                    Format::Pcap { .. } => None,
+                }
+                .ok_or_else(|| invalid("unknown pcapng interface"))?;
+                self.last_timestamp = PcapReader::to_micros(ts, units_per_sec);
+                Ok(Some(PcapFrame {
+                    timestamp: self.last_timestamp,
+                    data: body[20..20 + caplen].to_vec(),
+                }))
+            }
+            PCAPNG_SPB if body.len() >= 4 => {
+                // simple packets have no timestamp and are only limited by the block length
+                let origlen = self.word(&body[0..4]) as usize;
+                let caplen = origlen.min(body.len() - 4).min(MAX_FRAME_LEN);
+                Ok(Some(PcapFrame {
+                    timestamp: self.last_timestamp,
+                    data: body[4..4 + caplen].to_vec(),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the timestamp units per second from the options of an
+    /// interface description.
+    fn if_tsresol(&self, mut options: &[u8]) -> io::Result<u64> {
+        while options.len() >= 4 {
+            let code = self.half(&options[0..2]);
+            let len = usize::from(self.half(&options[2..4]));
+            let padded = (len + 3) & !3;
+            if options.len() < 4 + padded {
+                break;
+            }
+            if code == OPT_IF_TSRESOL && len == 1 {
+                let resol = options[4];
+                let exp = u32::from(resol & 0x7f);
+                return if resol & 0x80 != 0 {
+                    1u64.checked_shl(exp).filter(|_| exp < 64)
+                } else {
+                    10u64.checked_pow(exp)
+                }
+                .ok_or_else(|| invalid("unsupported timestamp resolution"));
+            }
+            options = &options[4 + padded..];
+        }
+        Ok(1_000_000)
+    }
+
+    /// Returns the next frame or `None` at the end of the file.
+    fn next_frame(&mut self) -> io::Result<Option<PcapFrame>> {
+        loop {
+            let mut head = [0; 4];
+            match self.reader.read_exact(&mut head) {
+                Ok(_) => {}
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err),
+            }
+            let first = self.word(&head);
+            match self.format {
+                Format::Pcap { units_per_sec } => {
+                    let mut rest = [0; 12];
+                    self.reader.read_exact(&mut rest)?;
+                    let ts = u64::from(first) * units_per_sec + u64::from(self.word(&rest[0..4]));
+                    let caplen = self.word(&rest[4..8]) as usize;
+                    if caplen > MAX_FRAME_LEN {
+                        return Err(invalid("invalid pcap packet length"));
+                    }
+                    let mut data = vec![0; caplen];
+                    self.reader.read_exact(&mut data)?;
+                    return Ok(Some(PcapFrame {
+                        timestamp: PcapReader::to_micros(ts, units_per_sec),
+                        data,
+                    }));
+                }
+                Format::Pcapng { .. } => {
+                    if let Some(frame) = self.read_pcapng_block(first)? {
+                        return Ok(Some(frame));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PcapDeviceDesc {
+    reader: PcapReader,
+    next: Option<PcapFrame>,
+    sink: PcapngSink,
+    replay_timing: bool,
+    /// The smoltcp instant at which the first frame was consumed, and its timestamp
+    start: Option<(Instant, i64)>,
+    deferred: bool,
+    eof: bool,
+    health: Health,
+    stats: Stats,
+}
+
+impl AsRawFd for PcapDeviceDesc {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.reader.get_ref().as_raw_fd()
+    }
+}
+
+impl PcapDeviceDesc {
+    pub fn new(input: &str, output: &str, replay_timing: bool) -> io::Result<PcapDeviceDesc> {
+        let reader = PcapReader::open(input)?;
+        let sink = PcapngSink::new(BufWriter::new(File::create(output)?))?;
+        sink.add_interface(input, reader.medium)?;
+        Ok(PcapDeviceDesc {
+            reader,
+            next: None,
+            sink,
+            replay_timing,
+            start: None,
+            deferred: false,
+            eof: false,
+            health: Health::default(),
+            stats: Stats::default(),
+        })
+    }
+
+    pub fn medium(&self) -> Medium {
+        self.reader.medium
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn health_mut(&mut self) -> &mut Health {
+        &mut self.health
+    }
+
+    /// Whether a frame is left, reads it ahead if needed.
+    /// After a frame was deferred by `recv` this returns `false` once so that
+    /// the receive loop of the current poll ends.
+    pub fn recv_ready(&mut self) -> io::Result<bool> {
+        if self.deferred {
+            self.deferred = false;
+            return Ok(false);
+        }
+        if self.next.is_none() && !self.eof {
+            // a malformed file ends the replay
+            self.eof = true;
+            self.next = self.reader.next_frame()?;
+            self.eof = self.next.is_none();
+        }
+        Ok(self.next.is_some())
+    }
+
+    /// Whether all frames of the file were received.
+    pub fn finished(&self) -> bool {
+        self.eof && self.next.is_none()
+    }
+
+    /// Takes the next frame if it is due at `now`, otherwise it is deferred
+    /// and a `WouldBlock` error is returned.
+    pub fn recv(&mut self, now: Instant) -> io::Result<Vec<u8>> {
+        let timestamp = match self.next {
+            Some(ref frame) => frame.timestamp,
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of file")),
+        };
+        if self.replay_timing {
+            match self.start {
+                Some((start, first))
+                    if timestamp - first > now.total_micros() - start.total_micros() =>
+                {
+                    self.deferred = true;
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        "frame not due yet",
+                    ));
+                }
+                Some(_) => {}
+                None => self.start = Some((now, timestamp)),
+            }
+        }
+        let data = self.next.take().unwrap().data;
+        self.stats.rx(data.len());
+        Ok(data)
+    }
+
+    /// The instant at which the next frame is due when replaying the timing.
+    pub fn next_due(&self) -> Option<Instant> {
+        match (self.start, self.next.as_ref()) {
+            (Some((start, first)), Some(frame)) if self.replay_timing => Some(
+                Instant::from_micros(start.total_micros() + frame.timestamp - first),
+            ),
+            _ => None,
+        }
+    }
+
+    pub fn send(&mut self, timestamp: Instant, buffer: &[u8]) -> io::Result<()> {
+        self.sink
+            .write_packet(0, timestamp, Direction::Outbound, 0, buffer)?;
+        self.stats.tx(buffer.len());
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// Writes a fixture to a file that is unique to the test.
+    fn fixture(name: &str, bytes: &[u8]) -> String {
+        let path = env::temp_dir().join(format!("pcap-device-{}-{}", process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn put(file: &mut Vec<u8>, word: u32, be: bool) {
+        file.extend_from_slice(&if be {
+            word.to_be_bytes()
+        } else {
+            word.to_le_bytes()
+        });
+    }
+
+    fn pcap_header(magic: u32, linktype: u32, be: bool) -> Vec<u8> {
+        let mut file = Vec::new();
+        put(&mut file, magic, be);
+        // version 2.4 as two halves
+        file.extend_from_slice(if be { &[0, 2, 0, 4] } else { &[2, 0, 4, 0] });
+        for &word in &[0, 0, 65535, linktype] {
+            put(&mut file, word, be);
+        }
+        file
+    }
+
+    fn pcap_record(file: &mut Vec<u8>, sec: u32, frac: u32, data: &[u8], be: bool) {
+        let len = data.len() as u32;
+        for &word in &[sec, frac, len, len] {
+            put(file, word, be);
+        }
+        file.extend_from_slice(data);
+    }
+
+    fn pcapng_block(file: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+        let total = (12 + body.len() + 3) as u32 & !3;
+        file.extend_from_slice(&block_type.to_le_bytes());
+        file.extend_from_slice(&total.to_le_bytes());
+        file.extend_from_slice(body);
+        file.resize(file.len() + (4 - body.len() % 4) % 4, 0);
+        file.extend_from_slice(&total.to_le_bytes());
+    }
+
+    fn pcapng_section(file: &mut Vec<u8>, linktype: u16, tsresol: Option<u8>) {
+        let mut shb = PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        shb.extend_from_slice(&[1, 0, 0, 0]);
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        pcapng_block(file, PCAPNG_SHB, &shb);
+        let mut idb = linktype.to_le_bytes().to_vec();
+        idb.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+        if let Some(resol) = tsresol {
+            idb.extend_from_slice(&OPT_IF_TSRESOL.to_le_bytes());
+            idb.extend_from_slice(&[1, 0, resol, 0, 0, 0]);
+        }
+        pcapng_block(file, PCAPNG_IDB, &idb);
+    }
+
+    fn pcapng_epb(file: &mut Vec<u8>, ts: u64, data: &[u8]) {
+        let mut epb = 0u32.to_le_bytes().to_vec();
+        epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        epb.extend_from_slice(data);
+        pcapng_block(file, PCAPNG_EPB, &epb);
+    }
+
+    fn frames(path: &str) -> (Medium, Vec<(i64, Vec<u8>)>) {
+        let mut reader = PcapReader::open(path).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.next_frame().unwrap() {
+            frames.push((frame.timestamp, frame.data));
+        }
+        (reader.medium, frames)
+    }
+
+    #[test]
+    fn pcap_frames_are_replayed() {
+        let mut file = pcap_header(PCAP_MAGIC_MICROS, 1, false);
+        pcap_record(&mut file, 1, 500, &[1, 2, 3], false);
+        pcap_record(&mut file, 2, 0, &[4, 5], false);
+        let path = fixture("micros.pcap", &file);
+        let (medium, frames) = frames(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(medium, Medium::Ethernet);
+        assert_eq!(
+            frames,
+            vec![(1_000_500, vec![1, 2, 3]), (2_000_000, vec![4, 5])]
+        );
+    }
+
+    #[test]
+    fn swapped_nanosecond_pcap_frames_are_replayed() {
+        let mut file = pcap_header(PCAP_MAGIC_NANOS, 101, true);
+        pcap_record(&mut file, 3, 2_000_000, &[0x45, 0], true);
+        let path = fixture("nanos.pcap", &file);
+        let (medium, frames) = frames(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(medium, Medium::Ip);
+        assert_eq!(frames, vec![(3_002_000, vec![0x45, 0])]);
+    }
+
+    #[test]
+    fn pcap_caplen_is_limited() {
+        let mut file = pcap_header(PCAP_MAGIC_MICROS, 1, false);
+        let caplen = MAX_FRAME_LEN as u32 + 1;
+        for &word in &[0, 0, caplen, caplen] {
+            put(&mut file, word, false);
+        }
+        let path = fixture("caplen.pcap", &file);
+        let mut reader = PcapReader::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let err = reader.next_frame().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn pcapng_frames_are_replayed() {
+        let mut file = Vec::new();
+        pcapng_section(&mut file, 1, Some(9));
+        pcapng_epb(&mut file, 5_000_000_000, &[1, 2, 3, 4, 5]);
+        // a simple packet keeps the timestamp of the previous one
+        let mut spb = 2u32.to_le_bytes().to_vec();
+        spb.extend_from_slice(&[6, 7]);
+        pcapng_block(&mut file, PCAPNG_SPB, &spb);
+        let path = fixture("nanos.pcapng", &file);
+        let (medium, frames) = frames(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(medium, Medium::Ethernet);
+        assert_eq!(
+            frames,
+            vec![(5_000_000, vec![1, 2, 3, 4, 5]), (5_000_000, vec![6, 7])]
+        );
+    }
+
+    #[test]
+    fn pcapng_block_length_is_limited() {
+        let mut file = Vec::new();
+        pcapng_section(&mut file, 101, None);
+        file.extend_from_slice(&PCAPNG_EPB.to_le_bytes());
+        file.extend_from_slice(&u32::MAX.to_le_bytes());
+        let path = fixture("block.pcapng", &file);
+        let mut reader = PcapReader::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(reader.medium, Medium::Ip);
+        let err = reader.next_frame().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn frames_are_deferred_until_due() {
+        let mut file = pcap_header(PCAP_MAGIC_MICROS, 1, false);
+        pcap_record(&mut file, 10, 0, &[1], false);
+        pcap_record(&mut file, 10, 3000, &[2], false);
+        let input = fixture("timing.pcap", &file);
+        let output = fixture("timing.pcapng", &[]);
+        let mut desc = PcapDeviceDesc::new(&input, &output, true).unwrap();
+        assert!(desc.recv_ready().unwrap());
+        assert_eq!(desc.recv(Instant::from_millis(100)).unwrap(), vec![1]);
+        assert!(desc.recv_ready().unwrap());
+        let err = desc.recv(Instant::from_millis(101)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(desc.next_due(), Some(Instant::from_millis(103)));
+        // the deferred frame ends the current poll
+        assert!(!desc.recv_ready().unwrap());
+        assert!(desc.recv_ready().unwrap());
+        assert_eq!(desc.recv(Instant::from_millis(103)).unwrap(), vec![2]);
+        assert!(!desc.recv_ready().unwrap());
+        assert!(desc.finished());
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&output).unwrap();
+    }
+}
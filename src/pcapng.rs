@@ -0,0 +1,220 @@
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use smoltcp::phy::Medium;
+use smoltcp::time::Instant;
+
+const BLOCK_SHB: u32 = 0x0A0D_0D0A;
+const BLOCK_IDB: u32 = 0x0000_0001;
+const BLOCK_ISB: u32 = 0x0000_0005;
+const BLOCK_EPB: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+const OPT_EPB_DROPCOUNT: u16 = 4;
+const OPT_ISB_STARTTIME: u16 = 2;
+const OPT_ISB_ENDTIME: u16 = 3;
+const OPT_ISB_IFRECV: u16 = 4;
+
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_IEEE802_15_4_NOFCS: u16 = 230;
+
+/// Direction of a captured frame as stored in the `epb_flags` option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound = 1,
+    Outbound = 2,
+}
+
+/// Frame and drop counters of a captured interface, written as
+/// Interface Statistics Block.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InterfaceStatistics {
+    pub start: Option<Instant>,
+    /// Number of frames received by the device.
+    pub received: u64,
+    /// Number of frames which could not be written to the capture, stored
+    /// as comment since `isb_osdrop` only counts drops of the OS.
+    pub dropped: u64,
+}
+
+/// Microseconds since the epoch as high and low 32 bit words.
+fn timestamp_bytes(ts: Instant) -> [u8; 8] {
+    let micros = ts.total_micros() as u64;
+    let mut b = [0; 8];
+    b[..4].copy_from_slice(&((micros >> 32) as u32).to_ne_bytes());
+    b[4..].copy_from_slice(&(micros as u32).to_ne_bytes());
+    b
+}
+
+/// A block under construction, padded to 32 bits and framed with its
+/// total length when finished.
+struct Block {
+    buf: Vec<u8>,
+}
+
+impl Block {
+    fn new(block_type: u32) -> Block {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&block_type.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        Block { buf }
+    }
+
+    fn u16(&mut self, v: u16) -> &mut Block {
+        self.buf.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    fn u32(&mut self, v: u32) -> &mut Block {
+        self.buf.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    fn timestamp(&mut self, ts: Instant) -> &mut Block {
+        self.buf.extend_from_slice(&timestamp_bytes(ts));
+        self
+    }
+
+    fn bytes(&mut self, data: &[u8]) -> &mut Block {
+        self.buf.extend_from_slice(data);
+        while self.buf.len() & 3 != 0 {
+            self.buf.push(0);
+        }
+        self
+    }
+
+    fn option(&mut self, code: u16, value: &[u8]) -> &mut Block {
+        self.u16(code).u16(value.len() as u16).bytes(value)
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        self.option(OPT_ENDOFOPT, &[]);
+        let total = (self.buf.len() + 4) as u32;
+        self.u32(total);
+        self.buf[4..8].copy_from_slice(&total.to_ne_bytes());
+        &self.buf
+    }
+}
+
+/// Serializes pcapng blocks in native byte order.
+struct PcapngWriter {
+    writer: Box<dyn Write + Send>,
+    interfaces: u32,
+}
+
+impl PcapngWriter {
+    fn write(&mut self, block: &mut Block) -> io::Result<()> {
+        self.writer.write_all(block.finish())
+    }
+}
+
+/// A pcapng file shared by the `Capture` wrappers of several devices,
+/// each of which is recorded as a separate interface.
+#[derive(Clone)]
+pub struct PcapngSink {
+    writer: Arc<Mutex<PcapngWriter>>,
+}
+
+impl std::fmt::Debug for PcapngSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PcapngSink").finish()
+    }
+}
+
+impl PcapngSink {
+    /// Writes the section header to `writer`, which should be buffered
+    /// because every frame results in a separate write.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> io::Result<PcapngSink> {
+        let mut writer = PcapngWriter {
+            writer: Box::new(writer),
+            interfaces: 0,
+        };
+        let mut shb = Block::new(BLOCK_SHB);
+        shb.u32(BYTE_ORDER_MAGIC)
+            .u16(1)
+            .u16(0)
+            .u32(0xffff_ffff)
+            .u32(0xffff_ffff) // section length not specified
+            .option(OPT_SHB_USERAPPL, b"usnet_devices");
+        writer.write(&mut shb)?;
+        Ok(PcapngSink {
+            writer: Arc::new(Mutex::new(writer)),
+        })
+    }
+
+    /// Writes an Interface Description Block and returns the interface ID.
+    pub(crate) fn add_interface(&self, name: &str, medium: Medium) -> io::Result<u32> {
+        let linktype = match medium {
+            Medium::Ethernet => LINKTYPE_ETHERNET,
+            Medium::Ip => LINKTYPE_RAW,
+            Medium::Ieee802154 => LINKTYPE_IEEE802_15_4_NOFCS,
+        };
+        let mut writer = self.writer.lock().unwrap();
+        let mut idb = Block::new(BLOCK_IDB);
+        idb.u16(linktype)
+            .u16(0)
+            .u32(0) // no snap length limit
+            .option(OPT_IF_NAME, name.as_bytes());
+        writer.write(&mut idb)?;
+        writer.interfaces += 1;
+        Ok(writer.interfaces - 1)
+    }
+
+    /// Writes an Enhanced Packet Block, `dropped` is the number of frames
+    /// lost since the previous frame of the interface.
+    pub(crate) fn write_packet(
+        &self,
+        interface: u32,
+        timestamp: Instant,
+        direction: Direction,
+        dropped: u64,
+        frame: &[u8],
+    ) -> io::Result<()> {
+        let mut epb = Block::new(BLOCK_EPB);
+        epb.u32(interface)
+            .timestamp(timestamp)
+            .u32(frame.len() as u32)
+            .u32(frame.len() as u32)
+            .bytes(frame)
+            .option(OPT_EPB_FLAGS, &(direction as u32).to_ne_bytes());
+        if dropped > 0 {
+            epb.option(OPT_EPB_DROPCOUNT, &dropped.to_ne_bytes());
+        }
+        self.writer.lock().unwrap().write(&mut epb)
+    }
+
+    /// Writes an Interface Statistics Block for the interface.
+    pub(crate) fn write_statistics(
+        &self,
+        interface: u32,
+        timestamp: Instant,
+        stats: &InterfaceStatistics,
+    ) -> io::Result<()> {
+        let mut isb = Block::new(BLOCK_ISB);
+        isb.u32(interface).timestamp(timestamp);
+        if let Some(start) = stats.start {
+            isb.option(OPT_ISB_STARTTIME, &timestamp_bytes(start));
+        }
+        isb.option(OPT_ISB_ENDTIME, &timestamp_bytes(timestamp))
+            .option(OPT_ISB_IFRECV, &stats.received.to_ne_bytes());
+        if stats.dropped > 0 {
+            let comment = format!(
+                "{} frames could not be written to the capture",
+                stats.dropped
+            );
+            isb.option(OPT_COMMENT, comment.as_bytes());
+        }
+        self.writer.lock().unwrap().write(&mut isb)
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().writer.flush()
+    }
+}
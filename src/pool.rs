@@ -0,0 +1,77 @@
+use std::sync::{Arc, Mutex};
+
+use batch::FrameBuf;
+
+/// Number of free buffers which a device keeps by default.
+pub const DEFAULT_POOL_SIZE: usize = 64;
+
+#[derive(Debug)]
+struct PoolInner {
+    free: Vec<Vec<u8>>,
+    buf_size: usize,
+    pool_size: usize,
+    allocations: u64,
+}
+
+/// Frame buffers shared by a device and its tokens, which return to the pool
+/// when their `FrameBuf` is dropped.
+///
+/// Buffers are allocated on demand until enough of them circulate, thus the
+/// steady state does no heap allocation. Requests for more than `buf_size`
+/// bytes and buffers beyond `pool_size` fall back to the allocator.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    inner: Arc<Mutex<PoolInner>>,
+}
+
+impl Default for BufferPool {
+    fn default() -> BufferPool {
+        BufferPool::new(0, 0)
+    }
+}
+
+impl BufferPool {
+    pub fn new(buf_size: usize, pool_size: usize) -> BufferPool {
+        BufferPool {
+            inner: Arc::new(Mutex::new(PoolInner {
+                free: Vec::with_capacity(pool_size),
+                buf_size,
+                pool_size,
+                allocations: 0,
+            })),
+        }
+    }
+
+    /// Returns an empty frame buffer which can hold at least `capacity` bytes.
+    pub fn get(&self, capacity: usize) -> FrameBuf {
+        let buffer = {
+            let mut inner = self.inner.lock().unwrap();
+            let free = if capacity <= inner.buf_size {
+                inner.free.pop()
+            } else {
+                None
+            };
+            match free {
+                Some(buffer) => buffer,
+                None => {
+                    inner.allocations += 1;
+                    vec![0; capacity.max(inner.buf_size)]
+                }
+            }
+        };
+        FrameBuf::pooled(buffer, self.clone())
+    }
+
+    /// Keeps `buffer` for reuse if it has the pool's size and the pool is not full.
+    pub fn put(&self, buffer: Vec<u8>) {
+        let mut inner = self.inner.lock().unwrap();
+        if buffer.len() == inner.buf_size && inner.free.len() < inner.pool_size {
+            inner.free.push(buffer);
+        }
+    }
+
+    /// Buffers allocated because none was free, including those of the warm-up.
+    pub fn allocations(&self) -> u64 {
+        self.inner.lock().unwrap().allocations
+    }
+}
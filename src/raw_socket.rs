@@ -1,16 +1,21 @@
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::sync::{Arc, RwLock};
-use std::vec::Vec;
 
 use smoltcp::phy;
 use smoltcp::phy::{Device, DeviceCapabilities};
 use smoltcp::time::Instant;
 use smoltcp::Result;
 
+use batch::FrameBuf;
+use fd_passing::{recv_fd, send_fd, SharedKind, SharedMeta, MAX_SHARED_MTU};
+use health::{is_backpressure, to_smoltcp_error};
+use packet_ring::{PacketRingConfig, RingFrame};
+use pool::{BufferPool, DEFAULT_POOL_SIZE};
 use raw_socket_sys;
 
-use SMOLTCP_ETHERNET_HEADER;
+use {DeviceState, Stats, SMOLTCP_ETHERNET_HEADER};
 
 /// A socket that captures or transmits the complete frame.
 #[derive(Debug)]
@@ -34,13 +39,164 @@ impl RawSocket {
     pub fn new(name: &str, reduce_mtu_by: Option<usize>) -> io::Result<RawSocket> {
         let mut lower = raw_socket_sys::RawSocketDesc::new(name)?;
         lower.bind_interface()?;
-        let mtu = lower.interface_mtu()?;
+        let mtu = lower.interface_mtu()? + SMOLTCP_ETHERNET_HEADER;
+        lower.set_pool(BufferPool::new(mtu, DEFAULT_POOL_SIZE));
         Ok(RawSocket {
             lower: Arc::new(RwLock::new(lower)),
-            mtu: mtu + SMOLTCP_ETHERNET_HEADER,
+            mtu,
             reduce_mtu_by: reduce_mtu_by,
         })
     }
+
+    /// Creates a raw socket like `new`, but exchanges frames through memory-mapped
+    /// `PACKET_RX_RING` and `PACKET_TX_RING` buffers (TPACKET_V3) instead of
+    /// one syscall per frame.
+    ///
+    /// The received frame is borrowed directly from the ring, its ring block is
+    /// only returned to the kernel when all tokens of the block were dropped.
+    /// Sent frames are only transmitted once the TX ring is full or on
+    /// `tx_flush`, which thus has to be called after `Interface::poll`.
+    pub fn new_mmap(
+        name: &str,
+        config: PacketRingConfig,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<RawSocket> {
+        let mut lower = raw_socket_sys::RawSocketDesc::new(name)?;
+        let mtu = lower.interface_mtu()? + SMOLTCP_ETHERNET_HEADER;
+        lower.setup_ring(config, mtu)?;
+        lower.bind_interface()?;
+        Ok(RawSocket {
+            lower: Arc::new(RwLock::new(lower)),
+            mtu,
+            reduce_mtu_by: reduce_mtu_by,
+        })
+    }
+
+    /// Sends the file descriptor of the socket with `SCM_RIGHTS` over the
+    /// blocking `stream`, together with its interface name and MTU, so that
+    /// another process can use it with `new_from_unix_stream`. Sockets with
+    /// packet rings are not supported because the rings are mapped here.
+    pub fn send_over_unix_stream(&self, stream: &UnixStream) -> io::Result<()> {
+        let lower = self.lower.read().unwrap();
+        if lower.has_ring() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "packet rings can not be sent",
+            ));
+        }
+        let meta = SharedMeta {
+            parent: lower.name(),
+            mtu: self.mtu,
+            reduce_mtu_by: self.reduce_mtu_by,
+            uses_wait: false,
+            extra: Vec::new(),
+        };
+        send_fd(stream, lower.as_raw_fd(), SharedKind::Raw, &meta)
+    }
+
+    /// Uses a raw socket which another process sent with
+    /// `send_over_unix_stream`, which needs no privileges.
+    pub fn new_from_unix_stream(stream: &UnixStream) -> io::Result<RawSocket> {
+        let (fd, meta) = recv_fd(stream, SharedKind::Raw)?;
+        let mut lower = raw_socket_sys::RawSocketDesc::from_fd(fd, &meta.parent)?;
+        meta.check_mtu(MAX_SHARED_MTU)?;
+        lower.set_pool(BufferPool::new(meta.mtu, DEFAULT_POOL_SIZE));
+        Ok(RawSocket {
+            lower: Arc::new(RwLock::new(lower)),
+            mtu: meta.mtu,
+            reduce_mtu_by: meta.reduce_mtu_by,
+        })
+    }
+
+    /// Sets how many frames the `Device` implementation receives with one
+    /// `recvmmsg` and sends with one `sendmmsg`, both default to 1.
+    /// Sockets with packet rings already exchange the frames in batches.
+    ///
+    /// With a TX batch larger than 1, frames are staged until the batch is
+    /// complete, thus `tx_flush` has to be called after `Interface::poll`.
+    pub fn set_batch(&mut self, rx_batch: usize, tx_batch: usize) {
+        self.lower.write().unwrap().set_batch(rx_batch, tx_batch);
+    }
+
+    /// Sets how many free frame buffers are kept for reuse by the device and
+    /// its tokens, `DEFAULT_POOL_SIZE` by default.
+    pub fn set_pool_size(&mut self, pool_size: usize) {
+        let pool = BufferPool::new(self.mtu, pool_size);
+        self.lower.write().unwrap().set_pool(pool);
+    }
+
+    /// Sends the frames staged by the TX tokens, which stay staged if the
+    /// socket buffer is full, or asks the kernel to transmit the TX ring.
+    pub fn tx_flush(&mut self) -> Result<()> {
+        let mut lower = self.lower.write().unwrap();
+        if lower.has_ring() {
+            return lower.tx_flush();
+        }
+        match lower.flush_staged() {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                lower.stats_mut().tx_error(&err);
+                match lower.health_mut().record(err) {
+                    ref err if is_backpressure(err) => Ok(()),
+                    err => Err(to_smoltcp_error(&err)),
+                }
+            }
+        }
+    }
+
+    pub(crate) fn rx_pending(&self) -> bool {
+        self.lower.read().unwrap().rx_pending()
+    }
+
+    /// Receives up to `bufs.len()` frames with one `recvmmsg` call, which takes
+    /// at most 64 frames, or from the RX ring. Each buffer is set to the length
+    /// of its frame and should hold the MTU.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        let mut lower = self.lower.write().unwrap();
+        lower.recv_batch(bufs).map_err(|err| {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                lower.stats_mut().rx_would_block += 1;
+            }
+            lower.health_mut().record(err)
+        })
+    }
+
+    /// Sends the frames after the staged frames with one `sendmmsg` call, which
+    /// takes at most 64 frames, or through the TX ring with one flush, and
+    /// returns how many of them were sent.
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        let mut lower = self.lower.write().unwrap();
+        let result = lower.flush_staged().and_then(|_| lower.send_batch(frames));
+        result.map_err(|err| {
+            lower.stats_mut().tx_error(&err);
+            lower.health_mut().record(err)
+        })
+    }
+
+    /// Returns the frame counters of the device, including the frames which
+    /// the kernel dropped so far.
+    pub fn stats(&self) -> Stats {
+        let mut lower = self.lower.write().unwrap();
+        let _ = lower.update_kernel_drops();
+        let mut stats = *lower.stats();
+        stats.buffer_allocations = lower.pool().allocations();
+        stats
+    }
+
+    /// Returns the `DeviceState` of the device.
+    pub fn state(&self) -> DeviceState {
+        self.lower.read().unwrap().health().state()
+    }
+
+    /// Returns the error which caused the current `DeviceState`.
+    pub fn last_error(&self) -> Option<io::Error> {
+        self.lower.read().unwrap().health().last_error()
+    }
+
+    /// Resets the device to `DeviceState::Up`.
+    pub fn clear_error(&mut self) {
+        self.lower.write().unwrap().health_mut().clear();
+    }
 }
 
 impl<'a> Device<'a> for RawSocket {
@@ -55,36 +211,83 @@ impl<'a> Device<'a> for RawSocket {
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
         let mut lower = self.lower.write().unwrap();
-        let mut buffer = vec![0; self.mtu];
-        match lower.recv(&mut buffer[..]) {
-            Ok(size) => {
-                buffer.resize(size, 0);
-                let rx = RxToken { buffer };
+        if lower.has_ring() {
+            return match lower.recv_ring() {
+                Ok(frame) => {
+                    let rx = RxToken {
+                        buffer: RxBuffer::Ring(frame),
+                    };
+                    let tx = TxToken {
+                        lower: self.lower.clone(),
+                    };
+                    Some((rx, tx))
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    lower.stats_mut().rx_would_block += 1;
+                    None
+                }
+                Err(err) => {
+                    lower.health_mut().record(err);
+                    None
+                }
+            };
+        }
+        match lower.recv_staged(self.mtu) {
+            Ok(frame) => {
+                let rx = RxToken {
+                    buffer: RxBuffer::Owned(frame),
+                };
                 let tx = TxToken {
                     lower: self.lower.clone(),
                 };
                 Some((rx, tx))
             }
-            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => None,
-            Err(err) => panic!("{}", err),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                lower.stats_mut().rx_would_block += 1;
+                None
+            }
+            Err(err) => {
+                lower.health_mut().record(err);
+                None
+            }
         }
     }
 
     fn transmit(&'a mut self) -> Option<Self::TxToken> {
-        Some(TxToken {
-            lower: self.lower.clone(),
-        })
+        let mut lower = self.lower.write().unwrap();
+        match lower.send_ready() {
+            Ok(_) => Some(TxToken {
+                lower: self.lower.clone(),
+            }),
+            Err(err) => {
+                if lower.has_ring() {
+                    // the kernel only works through the TX ring on send
+                    let _ = lower.tx_flush();
+                }
+                lower.stats_mut().tx_error(&err);
+                lower.health_mut().record(err);
+                None
+            }
+        }
     }
 }
 
+enum RxBuffer {
+    Owned(FrameBuf),
+    Ring(RingFrame),
+}
+
 #[doc(hidden)]
 pub struct RxToken {
-    buffer: Vec<u8>,
+    buffer: RxBuffer,
 }
 
 impl phy::RxToken for RxToken {
-    fn consume<R, F: FnOnce(&mut [u8]) -> Result<R>>(mut self, _timestamp: Instant, f: F) -> Result<R> {
-        f(&mut self.buffer[..])
+    fn consume<R, F: FnOnce(&mut [u8]) -> Result<R>>(self, _timestamp: Instant, f: F) -> Result<R> {
+        match self.buffer {
+            RxBuffer::Owned(mut frame) => f(frame.as_mut_slice()),
+            RxBuffer::Ring(mut frame) => f(frame.as_mut_slice()),
+        }
     }
 }
 
@@ -101,9 +304,23 @@ impl phy::TxToken for TxToken {
         f: F,
     ) -> Result<R> {
         let mut lower = self.lower.write().unwrap();
-        let mut buffer = vec![0; len];
-        let result = f(&mut buffer);
-        lower.send(&buffer[..]).unwrap();
-        result
+        if lower.has_ring() {
+            return lower.send_ring(len, f);
+        }
+        let mut frame = lower.pool().get(len);
+        frame.set_len(len);
+        let result = f(frame.as_mut_slice());
+        let staged = lower.tx_batched();
+        match lower.send_staged(frame) {
+            Ok(_) => result,
+            Err(err) => {
+                lower.stats_mut().tx_error(&err);
+                match lower.health_mut().record(err) {
+                    // the frame stays staged for the next flush
+                    ref err if staged && is_backpressure(err) => result,
+                    err => Err(to_smoltcp_error(&err)),
+                }
+            }
+        }
     }
 }
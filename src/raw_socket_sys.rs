@@ -1,13 +1,37 @@
 use libc;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::{io, mem};
+use std::{io, mem, ptr, slice};
 
-use super::{ifreq, ifreq_for, ifreq_ioctl, ETH_P_ALL, SIOCGIFINDEX, SIOCGIFMTU};
+use smoltcp::Result;
+
+use super::{
+    fd_writable, ifreq, ifreq_for, ifreq_ioctl, ifreq_name, ETH_P_ALL, SIOCGIFINDEX, SIOCGIFMTU,
+};
+use batch::{recv_each, recv_mmsg, send_mmsg, FrameBuf, Staging};
+use health::{is_backpressure, to_io_error, to_smoltcp_error, Health};
+use packet_ring::{PacketRing, PacketRingConfig, RingFrame};
+use pool::BufferPool;
+use stats::Stats;
+
+const SOL_PACKET: libc::c_int = 263;
+const PACKET_STATISTICS: libc::c_int = 6;
+
+#[repr(C)]
+#[derive(Default)]
+struct tpacket_stats {
+    tp_packets: u32,
+    tp_drops: u32,
+}
 
 #[derive(Debug)]
 pub struct RawSocketDesc {
     lower: libc::c_int,
     ifreq: ifreq,
+    ring: Option<PacketRing>,
+    staging: Staging,
+    pool: BufferPool,
+    health: Health,
+    stats: Stats,
 }
 
 impl AsRawFd for RawSocketDesc {
@@ -18,6 +42,7 @@ impl AsRawFd for RawSocketDesc {
 
 impl RawSocketDesc {
     pub fn new(name: &str) -> io::Result<RawSocketDesc> {
+        let ifreq = ifreq_for(name)?;
         let lower = unsafe {
             let lower = libc::socket(
                 libc::AF_PACKET,
@@ -32,10 +57,88 @@ impl RawSocketDesc {
 
         Ok(RawSocketDesc {
             lower: lower,
-            ifreq: ifreq_for(name),
+            ifreq: ifreq,
+            ring: None,
+            staging: Staging::default(),
+            pool: BufferPool::default(),
+            health: Health::default(),
+            stats: Stats::default(),
         })
     }
 
+    /// Takes ownership of `lower`, which is already bound to the interface `name`.
+    /// `lower` is closed if `name` is not a valid interface name.
+    pub fn from_fd(lower: RawFd, name: &str) -> io::Result<RawSocketDesc> {
+        let ifreq = match ifreq_for(name) {
+            Ok(ifreq) => ifreq,
+            Err(err) => {
+                unsafe {
+                    libc::close(lower);
+                }
+                return Err(err);
+            }
+        };
+        Ok(RawSocketDesc {
+            lower: lower,
+            ifreq: ifreq,
+            ring: None,
+            staging: Staging::default(),
+            pool: BufferPool::default(),
+            health: Health::default(),
+            stats: Stats::default(),
+        })
+    }
+
+    pub fn name(&self) -> String {
+        ifreq_name(&self.ifreq)
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn health_mut(&mut self) -> &mut Health {
+        &mut self.health
+    }
+
+    /// Adds the frames dropped by the kernel since the last call to the stats,
+    /// the kernel resets its counters when they are read.
+    pub fn update_kernel_drops(&mut self) -> io::Result<()> {
+        let mut stats = tpacket_stats::default();
+        let mut optlen = mem::size_of::<tpacket_stats>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                self.lower,
+                SOL_PACKET,
+                PACKET_STATISTICS,
+                &mut stats as *mut tpacket_stats as *mut libc::c_void,
+                &mut optlen,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        self.stats.kernel_drops += u64::from(stats.tp_drops);
+        Ok(())
+    }
+
+    pub fn setup_ring(&mut self, config: PacketRingConfig, max_frame: usize) -> io::Result<()> {
+        self.ring = Some(PacketRing::new(self.lower, config, max_frame)?);
+        Ok(())
+    }
+
+    pub fn has_ring(&self) -> bool {
+        self.ring.is_some()
+    }
+
     pub fn interface_mtu(&mut self) -> io::Result<usize> {
         ifreq_ioctl(self.lower, &mut self.ifreq, SIOCGIFMTU).map(|mtu| mtu as usize)
     }
@@ -65,39 +168,159 @@ impl RawSocketDesc {
         Ok(())
     }
 
-    pub fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
-        unsafe {
-            let len = libc::recv(
-                self.lower,
-                buffer.as_mut_ptr() as *mut libc::c_void,
-                buffer.len(),
-                0,
-            );
-            if len == -1 {
-                return Err(io::Error::last_os_error());
+    /// Checks whether a TX token can be handed out: the TX ring or, without a
+    /// TX batch, the socket buffer must have room, and with a TX batch the
+    /// frames left over from a full socket buffer must have been sent.
+    pub fn send_ready(&mut self) -> io::Result<()> {
+        match self.ring {
+            Some(ref ring) if !ring.send_ready() => {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "tx ring full"))
+            }
+            Some(_) => Ok(()),
+            // frames left over from a larger batch go first
+            None if !self.staging.tx_batched() => {
+                self.flush_staged().and_then(|_| fd_writable(self.lower))
             }
-            Ok(len as usize)
+            None if self.staging.tx_full() => self.flush_staged(),
+            None => Ok(()),
         }
     }
 
-    pub fn send(&mut self, buffer: &[u8]) -> io::Result<usize> {
-        unsafe {
-            let len = libc::send(
-                self.lower,
-                buffer.as_ptr() as *const libc::c_void,
-                buffer.len(),
-                0,
-            );
-            if len == -1 {
-                Err(io::Error::last_os_error()).unwrap()
+    /// Receives up to `bufs.len()` frames, with one `recvmmsg` call or by
+    /// copying them out of the RX ring.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        if self.ring.is_none() {
+            return recv_mmsg(self.lower, bufs, &mut self.stats);
+        }
+        recv_each(bufs, |buf| {
+            let frame = self.recv_ring()?;
+            buf.copy_from(frame.as_slice());
+            Ok(())
+        })
+    }
+
+    /// Sends the frames with one `sendmmsg` call, or fills the TX ring with
+    /// them and asks the kernel once to transmit them.
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        if self.ring.is_none() {
+            return send_mmsg(self.lower, frames, &mut self.stats);
+        }
+        let mut sent = 0;
+        for frame in frames {
+            let len = frame.len();
+            let ring = self.ring.as_mut().expect("no packet ring");
+            let result = ring.send(len, |buf| {
+                buf.copy_from_slice(frame.as_slice());
+                Ok(())
+            });
+            match result {
+                Ok(_) => {
+                    self.stats.tx(len);
+                    sent += 1;
+                }
+                Err(_) if sent > 0 => break,
+                Err(err) => {
+                    let _ = self.tx_flush();
+                    return Err(to_io_error(err));
+                }
             }
-            Ok(len as usize)
         }
+        self.tx_flush().map_err(to_io_error)?;
+        Ok(sent)
+    }
+
+    pub fn pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    pub fn set_pool(&mut self, pool: BufferPool) {
+        self.pool = pool;
+    }
+
+    pub fn set_batch(&mut self, rx_batch: usize, tx_batch: usize) {
+        self.staging.set_batch(rx_batch, tx_batch);
+    }
+
+    /// Returns the next frame of the RX staging queue, which is refilled with one `recvmmsg`.
+    pub fn recv_staged(&mut self, mtu: usize) -> io::Result<FrameBuf> {
+        let fd = self.lower;
+        let stats = &mut self.stats;
+        self.staging
+            .recv(mtu, &self.pool, |bufs| recv_mmsg(fd, bufs, stats))
+    }
+
+    /// Sends `frame` right away without a TX batch, otherwise stages it and
+    /// sends the batch once it is complete. Only staged frames are kept on
+    /// backpressure.
+    pub fn send_staged(&mut self, frame: FrameBuf) -> io::Result<()> {
+        let fd = self.lower;
+        let stats = &mut self.stats;
+        if !self.staging.tx_batched() {
+            return send_mmsg(fd, slice::from_ref(&frame), stats).map(|_| ());
+        }
+        self.staging
+            .send(frame, |frames| send_mmsg(fd, frames, stats))
+    }
+
+    pub fn tx_batched(&self) -> bool {
+        self.staging.tx_batched()
+    }
+
+    pub fn rx_pending(&self) -> bool {
+        self.staging.rx_staged()
+    }
+
+    /// Sends the frames of the TX staging queue with `sendmmsg`.
+    pub fn flush_staged(&mut self) -> io::Result<()> {
+        let fd = self.lower;
+        let stats = &mut self.stats;
+        self.staging.flush(|frames| send_mmsg(fd, frames, stats))
+    }
+
+    pub fn recv_ring(&mut self) -> io::Result<RingFrame> {
+        let (frame, frame_len) = self.ring.as_mut().expect("no packet ring").recv()?;
+        let len = frame.as_slice().len();
+        if frame_len > len {
+            self.stats.rx_truncated += 1;
+        }
+        self.stats.rx(len);
+        Ok(frame)
+    }
+
+    pub fn send_ring<R, F>(&mut self, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let ring = self.ring.as_mut().expect("no packet ring");
+        let result = ring.send(len, f);
+        if result.is_ok() {
+            self.stats.tx(len);
+        }
+        // kick the kernel only once the ring is full, tx_flush sends the rest
+        if !ring.send_ready() {
+            self.tx_flush()?;
+        }
+        result
+    }
+
+    /// Asks the kernel to transmit all frames queued in the TX ring.
+    pub fn tx_flush(&mut self) -> Result<()> {
+        self.stats.tx_flushes += 1;
+        let res = unsafe { libc::send(self.lower, ptr::null(), 0, libc::MSG_DONTWAIT) };
+        if res == -1 {
+            let err = self.health.record(io::Error::last_os_error());
+            if !is_backpressure(&err) {
+                return Err(to_smoltcp_error(&err));
+            }
+        }
+        Ok(())
     }
 }
 
 impl Drop for RawSocketDesc {
     fn drop(&mut self) {
+        // the mapping itself stays until the last received frame is dropped
+        self.ring = None;
         unsafe {
             libc::close(self.lower);
         }
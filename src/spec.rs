@@ -0,0 +1,548 @@
+use libc;
+use std::error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::str::FromStr;
+
+#[cfg(feature = "netmap")]
+use netmap::Netmap;
+use packet_ring::PacketRingConfig;
+use raw_socket::RawSocket;
+use tap_interface::TapInterface;
+use unixdomainsocket::UnixDomainSocket;
+#[cfg(feature = "xdp")]
+use xdp::{XdpConfig, XdpSocket};
+
+use AnyDevice;
+
+/// Backend named by the prefix of a device specification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Tap,
+    Tun,
+    Macvtap,
+    Raw,
+    Netmap,
+    Uds,
+    Xdp,
+}
+
+impl DeviceKind {
+    fn from_prefix(prefix: &str) -> Option<DeviceKind> {
+        match prefix {
+            "tap" => Some(DeviceKind::Tap),
+            "tun" => Some(DeviceKind::Tun),
+            "macvtap" => Some(DeviceKind::Macvtap),
+            "raw" => Some(DeviceKind::Raw),
+            "netmap" => Some(DeviceKind::Netmap),
+            "uds" => Some(DeviceKind::Uds),
+            "xdp" => Some(DeviceKind::Xdp),
+            _ => None,
+        }
+    }
+
+    fn prefix(self) -> &'static str {
+        match self {
+            DeviceKind::Tap => "tap",
+            DeviceKind::Tun => "tun",
+            DeviceKind::Macvtap => "macvtap",
+            DeviceKind::Raw => "raw",
+            DeviceKind::Netmap => "netmap",
+            DeviceKind::Uds => "uds",
+            DeviceKind::Xdp => "xdp",
+        }
+    }
+
+    /// Whether the `parent` interface is needed for MTU discovery.
+    fn needs_parent(self) -> bool {
+        self == DeviceKind::Netmap || self == DeviceKind::Uds
+    }
+
+    /// Whether the name is the one of a network interface, unlike the
+    /// netmap port or the socket path.
+    fn names_interface(self) -> bool {
+        !self.needs_parent()
+    }
+}
+
+impl fmt::Display for DeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.prefix())
+    }
+}
+
+/// Reason why a device specification could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecError {
+    /// The `kind:` prefix is missing.
+    MissingKind,
+    UnknownKind(String),
+    EmptyName,
+    /// Netmap and UDS devices need `@parent`.
+    MissingParent(DeviceKind),
+    /// Only Netmap and UDS devices take `@parent`.
+    UnexpectedParent(DeviceKind),
+    UnknownOption(String),
+    /// The option exists but is not supported by this kind of device.
+    UnsupportedOption(DeviceKind, String),
+    InvalidValue(String, String),
+}
+
+impl fmt::Display for SpecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SpecError::MissingKind => write!(f, "missing device kind, expected kind:name"),
+            SpecError::UnknownKind(ref kind) => write!(f, "unknown device kind {:?}", kind),
+            SpecError::EmptyName => write!(f, "empty device name"),
+            SpecError::MissingParent(kind) => {
+                write!(f, "{} device needs a parent interface (name@parent)", kind)
+            }
+            SpecError::UnexpectedParent(kind) => {
+                write!(f, "{} device does not take a parent interface", kind)
+            }
+            SpecError::UnknownOption(ref option) => write!(f, "unknown option {:?}", option),
+            SpecError::UnsupportedOption(kind, ref option) => {
+                write!(
+                    f,
+                    "option {:?} is not supported by {} devices",
+                    option, kind
+                )
+            }
+            SpecError::InvalidValue(ref option, ref value) => {
+                write!(f, "invalid value {:?} for option {:?}", value, option)
+            }
+        }
+    }
+}
+
+impl error::Error for SpecError {}
+
+impl From<SpecError> for io::Error {
+    fn from(err: SpecError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// A device given as one string of the form `kind:name[@parent][?option&...]`,
+/// e.g. `tap:tap0`, `macvtap:macvtap0`, `raw:eth0?mmap`,
+/// `netmap:eth0-1@eth0?uses_wait`, `uds:/run/usnet.sock@eth0?reduce_mtu_by=4`,
+/// or `xdp:eth0?queue=1`.
+///
+/// The options are `reduce_mtu_by=N`, `uses_wait[=true|false]` (Netmap and XDP),
+/// `mmap` (raw sockets with the default `PacketRingConfig`), and `queue=N` (XDP).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSpec {
+    pub kind: DeviceKind,
+    pub name: String,
+    pub parent: Option<String>,
+    pub reduce_mtu_by: Option<usize>,
+    pub uses_wait: bool,
+    pub mmap: bool,
+    pub queue: u32,
+}
+
+fn parse_value<T: FromStr>(option: &str, value: Option<&str>) -> Result<T, SpecError> {
+    let value = value.unwrap_or("");
+    value
+        .parse()
+        .map_err(|_| SpecError::InvalidValue(option.to_string(), value.to_string()))
+}
+
+fn parse_flag(option: &str, value: Option<&str>) -> Result<bool, SpecError> {
+    match value {
+        None => Ok(true),
+        Some(_) => parse_value(option, value),
+    }
+}
+
+impl FromStr for DeviceSpec {
+    type Err = SpecError;
+
+    fn from_str(spec: &str) -> Result<DeviceSpec, SpecError> {
+        let colon = spec.find(':').ok_or(SpecError::MissingKind)?;
+        let kind = DeviceKind::from_prefix(&spec[..colon])
+            .ok_or_else(|| SpecError::UnknownKind(spec[..colon].to_string()))?;
+        let rest = &spec[colon + 1..];
+        let (rest, options) = match rest.find('?') {
+            Some(pos) => (&rest[..pos], Some(&rest[pos + 1..])),
+            None => (rest, None),
+        };
+        // the name may contain '@' itself (e.g. netmap options), the parent can not
+        let (name, parent) = match rest.rfind('@') {
+            Some(pos) => (&rest[..pos], Some(&rest[pos + 1..])),
+            None => (rest, None),
+        };
+        if name.is_empty() || parent == Some("") {
+            return Err(SpecError::EmptyName);
+        }
+        match (kind.needs_parent(), parent) {
+            (true, None) => return Err(SpecError::MissingParent(kind)),
+            (false, Some(_)) => return Err(SpecError::UnexpectedParent(kind)),
+            _ => {}
+        }
+        // the kernel takes interface names of less than IF_NAMESIZE bytes
+        if kind.names_interface() && name.len() >= libc::IF_NAMESIZE {
+            return Err(SpecError::InvalidValue(
+                "name".to_string(),
+                name.to_string(),
+            ));
+        }
+        if let Some(parent) = parent.filter(|parent| parent.len() >= libc::IF_NAMESIZE) {
+            return Err(SpecError::InvalidValue(
+                "parent".to_string(),
+                parent.to_string(),
+            ));
+        }
+        let mut parsed = DeviceSpec {
+            kind,
+            name: name.to_string(),
+            parent: parent.map(|parent| parent.to_string()),
+            reduce_mtu_by: None,
+            uses_wait: false,
+            mmap: false,
+            queue: 0,
+        };
+        for option in options.unwrap_or("").split('&') {
+            if option.is_empty() {
+                continue;
+            }
+            let (key, value) = match option.find('=') {
+                Some(pos) => (&option[..pos], Some(&option[pos + 1..])),
+                None => (option, None),
+            };
+            let supported = match key {
+                "reduce_mtu_by" => {
+                    parsed.reduce_mtu_by = Some(parse_value(key, value)?);
+                    true
+                }
+                "uses_wait" => {
+                    parsed.uses_wait = parse_flag(key, value)?;
+                    kind == DeviceKind::Netmap || kind == DeviceKind::Xdp
+                }
+                "mmap" => {
+                    parsed.mmap = parse_flag(key, value)?;
+                    kind == DeviceKind::Raw
+                }
+                "queue" => {
+                    parsed.queue = parse_value(key, value)?;
+                    kind == DeviceKind::Xdp
+                }
+                _ => return Err(SpecError::UnknownOption(key.to_string())),
+            };
+            if !supported {
+                return Err(SpecError::UnsupportedOption(kind, key.to_string()));
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+impl fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.kind, self.name)?;
+        if let Some(ref parent) = self.parent {
+            write!(f, "@{}", parent)?;
+        }
+        let mut options = Vec::new();
+        if let Some(reduce_mtu_by) = self.reduce_mtu_by {
+            options.push(format!("reduce_mtu_by={}", reduce_mtu_by));
+        }
+        if self.uses_wait {
+            options.push("uses_wait".to_string());
+        }
+        if self.mmap {
+            options.push("mmap".to_string());
+        }
+        if self.queue != 0 {
+            options.push(format!("queue={}", self.queue));
+        }
+        if !options.is_empty() {
+            write!(f, "?{}", options.join("&"))?;
+        }
+        Ok(())
+    }
+}
+
+impl DeviceSpec {
+    /// Opens the device, which fails with `ErrorKind::Unsupported` if its backend
+    /// was not enabled as feature of this crate.
+    pub fn open(&self) -> io::Result<AnyDevice> {
+        let parent = self.parent.as_deref();
+        match self.kind {
+            DeviceKind::Tap => TapInterface::new(&self.name, self.reduce_mtu_by).map(Into::into),
+            DeviceKind::Tun => {
+                TapInterface::new_tun(&self.name, self.reduce_mtu_by).map(Into::into)
+            }
+            DeviceKind::Macvtap => {
+                TapInterface::new_macvtap(&self.name, self.reduce_mtu_by).map(Into::into)
+            }
+            DeviceKind::Raw if self.mmap => {
+                RawSocket::new_mmap(&self.name, PacketRingConfig::default(), self.reduce_mtu_by)
+                    .map(Into::into)
+            }
+            DeviceKind::Raw => RawSocket::new(&self.name, self.reduce_mtu_by).map(Into::into),
+            DeviceKind::Uds => {
+                let socket = connect_unix_datagram(&self.name)?;
+                UnixDomainSocket::new_from_unix_datagram(
+                    socket,
+                    parent.unwrap_or(""),
+                    self.reduce_mtu_by,
+                )
+                .map(Into::into)
+            }
+            #[cfg(feature = "netmap")]
+            DeviceKind::Netmap => {
+                // VALE ports are opened by their own name, interfaces and
+                // pipes with the netmap: prefix
+                let name = if self.name.starts_with("vale") {
+                    self.name.clone()
+                } else {
+                    format!("netmap:{}", self.name)
+                };
+                Netmap::new(
+                    &name,
+                    parent.unwrap_or(""),
+                    self.uses_wait,
+                    self.reduce_mtu_by,
+                )
+                .map(Into::into)
+            }
+            #[cfg(feature = "xdp")]
+            DeviceKind::Xdp => XdpSocket::new(
+                &self.name,
+                self.queue,
+                XdpConfig::default(),
+                self.uses_wait,
+                self.reduce_mtu_by,
+            )
+            .map(Into::into),
+            #[allow(unreachable_patterns)]
+            kind => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} support is not enabled", kind),
+            )),
+        }
+    }
+}
+
+/// Connects a datagram socket to `path` after binding it to an autogenerated
+/// abstract address, so that the peer can reply to it.
+fn connect_unix_datagram(path: &str) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let res = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            mem::size_of::<libc::sa_family_t>() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// Parses `spec` (see `DeviceSpec`) and opens the device.
+/// Malformed specifications fail with `ErrorKind::InvalidInput` and carry
+/// the `SpecError`, which is available through `io::Error::get_ref`.
+pub fn open(spec: &str) -> io::Result<AnyDevice> {
+    spec.parse::<DeviceSpec>()?.open()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(kind: DeviceKind, name: &str, parent: Option<&str>) -> DeviceSpec {
+        DeviceSpec {
+            kind,
+            name: name.to_string(),
+            parent: parent.map(|parent| parent.to_string()),
+            reduce_mtu_by: None,
+            uses_wait: false,
+            mmap: false,
+            queue: 0,
+        }
+    }
+
+    fn parse(s: &str) -> Result<DeviceSpec, SpecError> {
+        s.parse()
+    }
+
+    #[test]
+    fn each_kind_is_parsed() {
+        assert_eq!(parse("tap:tap0"), Ok(spec(DeviceKind::Tap, "tap0", None)));
+        assert_eq!(parse("tun:tun0"), Ok(spec(DeviceKind::Tun, "tun0", None)));
+        assert_eq!(
+            parse("macvtap:macvtap0"),
+            Ok(spec(DeviceKind::Macvtap, "macvtap0", None))
+        );
+        assert_eq!(
+            parse("raw:eth0?mmap"),
+            Ok(DeviceSpec {
+                mmap: true,
+                ..spec(DeviceKind::Raw, "eth0", None)
+            })
+        );
+        assert_eq!(
+            parse("netmap:eth0-1@eth0?uses_wait"),
+            Ok(DeviceSpec {
+                uses_wait: true,
+                ..spec(DeviceKind::Netmap, "eth0-1", Some("eth0"))
+            })
+        );
+        assert_eq!(
+            parse("uds:/run/usnet.sock@eth0?reduce_mtu_by=4"),
+            Ok(DeviceSpec {
+                reduce_mtu_by: Some(4),
+                ..spec(DeviceKind::Uds, "/run/usnet.sock", Some("eth0"))
+            })
+        );
+        assert_eq!(
+            parse("xdp:eth0?queue=1&uses_wait=false"),
+            Ok(DeviceSpec {
+                queue: 1,
+                ..spec(DeviceKind::Xdp, "eth0", None)
+            })
+        );
+    }
+
+    #[test]
+    fn parent_follows_the_last_at() {
+        assert_eq!(
+            parse("netmap:vale0:p@x@eth0"),
+            Ok(spec(DeviceKind::Netmap, "vale0:p@x", Some("eth0")))
+        );
+        assert_eq!(
+            parse("uds:/tmp/a@b.sock@eth0").unwrap().name,
+            "/tmp/a@b.sock"
+        );
+    }
+
+    #[test]
+    fn specs_round_trip() {
+        for s in &[
+            "tap:tap0",
+            "raw:eth0?mmap",
+            "netmap:eth0-1@eth0?uses_wait",
+            "uds:/run/usnet.sock@eth0?reduce_mtu_by=4",
+            "xdp:eth0?uses_wait&queue=2",
+        ] {
+            assert_eq!(parse(s).unwrap().to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn malformed_specs_are_rejected() {
+        assert_eq!(parse("eth0"), Err(SpecError::MissingKind));
+        assert_eq!(
+            parse("veth:eth0"),
+            Err(SpecError::UnknownKind("veth".to_string()))
+        );
+        assert_eq!(parse("tap:"), Err(SpecError::EmptyName));
+        assert_eq!(parse("tap:?mmap"), Err(SpecError::EmptyName));
+        assert_eq!(parse("uds:/run/usnet.sock@"), Err(SpecError::EmptyName));
+        assert_eq!(
+            parse("netmap:eth0"),
+            Err(SpecError::MissingParent(DeviceKind::Netmap))
+        );
+        assert_eq!(
+            parse("uds:/run/usnet.sock"),
+            Err(SpecError::MissingParent(DeviceKind::Uds))
+        );
+        assert_eq!(
+            parse("tap:tap0@eth0"),
+            Err(SpecError::UnexpectedParent(DeviceKind::Tap))
+        );
+        assert_eq!(
+            parse("tap:tap0?promisc"),
+            Err(SpecError::UnknownOption("promisc".to_string()))
+        );
+        assert_eq!(
+            parse("tap:tap0?mmap"),
+            Err(SpecError::UnsupportedOption(
+                DeviceKind::Tap,
+                "mmap".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("raw:eth0?queue=1"),
+            Err(SpecError::UnsupportedOption(
+                DeviceKind::Raw,
+                "queue".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("xdp:eth0?queue=first"),
+            Err(SpecError::InvalidValue(
+                "queue".to_string(),
+                "first".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("tap:tap0?reduce_mtu_by"),
+            Err(SpecError::InvalidValue(
+                "reduce_mtu_by".to_string(),
+                String::new()
+            ))
+        );
+        assert_eq!(
+            parse("raw:eth0?mmap=yes"),
+            Err(SpecError::InvalidValue(
+                "mmap".to_string(),
+                "yes".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn long_interface_names_are_rejected() {
+        // IF_NAMESIZE includes the terminating NUL
+        let longest = "a".repeat(libc::IF_NAMESIZE - 1);
+        let too_long = "a".repeat(libc::IF_NAMESIZE);
+        for kind in &["tap", "tun", "macvtap", "raw", "xdp"] {
+            let s = format!("{}:{}", kind, longest);
+            assert_eq!(parse(&s).unwrap().name, longest);
+            let s = format!("{}:{}", kind, too_long);
+            assert_eq!(
+                parse(&s),
+                Err(SpecError::InvalidValue(
+                    "name".to_string(),
+                    too_long.clone()
+                ))
+            );
+        }
+        let s = format!("uds:/run/usnet.sock@{}", too_long);
+        assert_eq!(
+            parse(&s),
+            Err(SpecError::InvalidValue(
+                "parent".to_string(),
+                too_long.clone()
+            ))
+        );
+        // only the parent of netmap ports and sockets is an interface
+        let s = format!("netmap:{}@eth0", too_long);
+        assert_eq!(parse(&s).unwrap().name, too_long);
+    }
+
+    #[test]
+    fn open_reports_spec_errors_as_invalid_input() {
+        let err = open("tap:tap0@eth0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let inner = err.get_ref().unwrap().downcast_ref::<SpecError>();
+        assert_eq!(inner, Some(&SpecError::UnexpectedParent(DeviceKind::Tap)));
+    }
+
+    #[cfg(not(feature = "netmap"))]
+    #[test]
+    fn open_reports_disabled_backends() {
+        let err = open("netmap:eth0@eth0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,54 @@
+use std::io;
+
+use health::is_backpressure;
+
+/// Frame counters of a device since it was opened.
+///
+/// Counters which a backend can not observe stay zero, e.g. `rx_truncated`
+/// for TAP interfaces or `kernel_drops` for Netmap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+    /// Calls to `receive` which found no frame.
+    pub rx_would_block: u64,
+    /// Transmissions which failed because the ring, queue or socket buffer was full.
+    pub tx_would_block: u64,
+    /// Received frames which were longer than the buffer and cut to its size.
+    pub rx_truncated: u64,
+    /// Received frames which were discarded, e.g. with an invalid TCP/UDP
+    /// checksum when the checksum offload of a TAP interface is enabled.
+    pub rx_dropped: u64,
+    /// Syscalls which hand the frames of the TX ring to the kernel
+    /// (`NIOCTXSYNC` for Netmap, wakeups for XDP and packet rings).
+    pub tx_flushes: u64,
+    /// Frames sent with `zc_forward`, these are also part of `tx_packets`.
+    pub zc_forwards: u64,
+    /// Frames dropped by the kernel because the socket or ring was full
+    /// (`PACKET_STATISTICS` for raw sockets, `XDP_STATISTICS` for XDP).
+    pub kernel_drops: u64,
+    /// Frame buffers allocated because the buffer pool had none free, which
+    /// stops growing in the steady state (TAP, raw and Unix domain sockets).
+    pub buffer_allocations: u64,
+}
+
+impl Stats {
+    pub(crate) fn rx(&mut self, len: usize) {
+        self.rx_packets += 1;
+        self.rx_bytes += len as u64;
+    }
+
+    pub(crate) fn tx(&mut self, len: usize) {
+        self.tx_packets += 1;
+        self.tx_bytes += len as u64;
+    }
+
+    /// Counts a failed transmission as would-block if `err` only signals backpressure.
+    pub(crate) fn tx_error(&mut self, err: &io::Error) {
+        if is_backpressure(err) {
+            self.tx_would_block += 1;
+        }
+    }
+}
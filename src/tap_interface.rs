@@ -1,23 +1,37 @@
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::sync::{Arc, RwLock};
 use std::vec::Vec;
 
 use smoltcp::phy;
-use smoltcp::phy::{Device, DeviceCapabilities};
+use smoltcp::phy::{Checksum, Device, DeviceCapabilities, Medium};
 use smoltcp::time::Instant;
 use smoltcp::Result;
 
+use batch::FrameBuf;
+use fd_passing::{recv_fd, send_fd, SharedKind, SharedMeta, MAX_SHARED_MTU};
+use health::to_smoltcp_error;
+use pool::{BufferPool, DEFAULT_POOL_SIZE};
 use tap_interface_sys;
+use vnet;
+use vnet::{VnetOffload, VNET_HDR_LEN, VNET_MAX_PACKET};
 
-use SMOLTCP_ETHERNET_HEADER;
+use libc;
 
-/// A virtual Ethernet interface.
+use {
+    DeviceState, Stats, IFF_MULTI_QUEUE, IFF_NO_PI, IFF_TAP, IFF_TUN, IFF_VNET_HDR,
+    SMOLTCP_ETHERNET_HEADER,
+};
+
+/// A virtual Ethernet interface, or a virtual IP interface in TUN mode.
 #[derive(Debug)]
 pub struct TapInterface {
     lower: Arc<RwLock<tap_interface_sys::TapInterfaceDesc>>,
     mtu: usize,
     reduce_mtu_by: Option<usize>,
+    medium: Medium,
+    vnet: Option<VnetOffload>,
 }
 
 impl AsRawFd for TapInterface {
@@ -33,14 +47,24 @@ impl TapInterface {
     /// no special privileges are needed. Otherwise, this requires superuser privileges
     /// or a corresponding capability set on the executable.
     pub fn new(name: &str, reduce_mtu_by: Option<usize>) -> io::Result<TapInterface> {
-        let mut lower = tap_interface_sys::TapInterfaceDesc::new(name)?;
-        lower.attach_interface()?;
-        let mtu = lower.interface_mtu()?;
-        Ok(TapInterface {
-            lower: Arc::new(RwLock::new(lower)),
-            mtu: mtu + SMOLTCP_ETHERNET_HEADER,
-            reduce_mtu_by: reduce_mtu_by,
-        })
+        let lower = tap_interface_sys::TapInterfaceDesc::new(name)?;
+        TapInterface::attach(
+            lower,
+            IFF_TAP | IFF_NO_PI,
+            Medium::Ethernet,
+            None,
+            reduce_mtu_by,
+        )
+    }
+
+    /// Attaches to a TUN interface called `name`, or creates it if it does not exist.
+    ///
+    /// The interface carries bare IP packets without an Ethernet header, thus
+    /// smoltcp has to be configured for `Medium::Ip` and the MTU is the one of
+    /// the interface. The same privileges as for `new` are required.
+    pub fn new_tun(name: &str, reduce_mtu_by: Option<usize>) -> io::Result<TapInterface> {
+        let lower = tap_interface_sys::TapInterfaceDesc::new(name)?;
+        TapInterface::attach(lower, IFF_TUN | IFF_NO_PI, Medium::Ip, None, reduce_mtu_by)
     }
 
     /// Attaches to a MACVTAP interface called `name`.
@@ -53,15 +77,302 @@ impl TapInterface {
     /// The attached MAC address must also be used in smoltcp
     /// (for passthru it is the same as the underlying device).
     pub fn new_macvtap(name: &str, reduce_mtu_by: Option<usize>) -> io::Result<TapInterface> {
-        let mut lower = tap_interface_sys::TapInterfaceDesc::new_macvtap(name)?;
-        lower.attach_interface()?;
+        let lower = tap_interface_sys::TapInterfaceDesc::new_macvtap(name)?;
+        TapInterface::attach(
+            lower,
+            IFF_TAP | IFF_NO_PI,
+            Medium::Ethernet,
+            None,
+            reduce_mtu_by,
+        )
+    }
+
+    /// Attaches to a TAP interface called `name` like `new`, but exchanges a
+    /// virtio-net header with the kernel for every frame (`IFF_VNET_HDR`).
+    ///
+    /// This enables the given `offload`s: with `checksum` smoltcp does not
+    /// verify or compute TCP/UDP checksums, and with `tso` the MTU is raised
+    /// to allow GRO super-frames to be received and large TCP segments to be
+    /// sent, which the kernel then splits according to the interface MTU.
+    pub fn new_with_offload(
+        name: &str,
+        offload: VnetOffload,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<TapInterface> {
+        let lower = tap_interface_sys::TapInterfaceDesc::new(name)?;
+        TapInterface::attach(
+            lower,
+            IFF_TAP | IFF_NO_PI | IFF_VNET_HDR,
+            Medium::Ethernet,
+            Some(offload),
+            reduce_mtu_by,
+        )
+    }
+
+    /// Attaches to a MACVTAP interface called `name` like `new_macvtap`, but
+    /// with virtio-net header offloads as described for `new_with_offload`.
+    pub fn new_macvtap_with_offload(
+        name: &str,
+        offload: VnetOffload,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<TapInterface> {
+        let lower = tap_interface_sys::TapInterfaceDesc::new_macvtap(name)?;
+        TapInterface::attach(
+            lower,
+            IFF_TAP | IFF_NO_PI | IFF_VNET_HDR,
+            Medium::Ethernet,
+            Some(offload),
+            reduce_mtu_by,
+        )
+    }
+
+    /// Attaches to `queues` queues of the multi-queue TAP interface called `name`,
+    /// or creates it if it does not exist.
+    ///
+    /// Every returned device has its own file descriptor for one queue and can
+    /// be used from a separate thread. The kernel distributes the received frames
+    /// over all enabled queues. The same privileges as for `new` are required.
+    pub fn new_multi_queue(
+        name: &str,
+        queues: usize,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<Vec<TapInterface>> {
+        (0..queues)
+            .map(|_| {
+                let lower = tap_interface_sys::TapInterfaceDesc::new(name)?;
+                TapInterface::attach(
+                    lower,
+                    IFF_TAP | IFF_NO_PI | IFF_MULTI_QUEUE,
+                    Medium::Ethernet,
+                    None,
+                    reduce_mtu_by,
+                )
+            })
+            .collect()
+    }
+
+    /// Attaches to `queues` queues of the multi-queue TUN interface called `name`,
+    /// or creates it if it does not exist (see `new_multi_queue` and `new_tun`).
+    pub fn new_tun_multi_queue(
+        name: &str,
+        queues: usize,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<Vec<TapInterface>> {
+        (0..queues)
+            .map(|_| {
+                let lower = tap_interface_sys::TapInterfaceDesc::new(name)?;
+                TapInterface::attach(
+                    lower,
+                    IFF_TUN | IFF_NO_PI | IFF_MULTI_QUEUE,
+                    Medium::Ip,
+                    None,
+                    reduce_mtu_by,
+                )
+            })
+            .collect()
+    }
+
+    /// Sends the file descriptor of the interface with `SCM_RIGHTS` over the
+    /// blocking `stream`, together with its name, MTU, medium and offloads,
+    /// so that another process can use it with `new_from_unix_stream`.
+    pub fn send_over_unix_stream(&self, stream: &UnixStream) -> io::Result<()> {
+        let lower = self.lower.read().unwrap();
+        let medium = match self.medium {
+            Medium::Ethernet => 0,
+            _ => 1,
+        };
+        let offload = match self.vnet {
+            Some(offload) => 1 | (offload.checksum as u8) << 1 | (offload.tso as u8) << 2,
+            None => 0,
+        };
+        let meta = SharedMeta {
+            parent: lower.name(),
+            mtu: self.mtu,
+            reduce_mtu_by: self.reduce_mtu_by,
+            uses_wait: false,
+            extra: vec![medium, offload],
+        };
+        send_fd(stream, lower.as_raw_fd(), SharedKind::Tap, &meta)
+    }
+
+    /// Uses a TAP or TUN interface which another process sent with
+    /// `send_over_unix_stream`, which needs no privileges.
+    pub fn new_from_unix_stream(stream: &UnixStream) -> io::Result<TapInterface> {
+        let (fd, meta) = recv_fd(stream, SharedKind::Tap)?;
+        let lower = tap_interface_sys::TapInterfaceDesc::from_fd(fd, &meta.parent)?;
+        if meta.extra.len() != 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid TAP metadata",
+            ));
+        }
+        meta.check_mtu(MAX_SHARED_MTU)?;
+        let (medium, kind) = match meta.extra[0] {
+            0 => (Medium::Ethernet, IFF_TAP),
+            1 => (Medium::Ip, IFF_TUN),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown TAP medium",
+                ))
+            }
+        };
+        let offload = meta.extra[1];
+        let vnet = match offload {
+            0 => None,
+            1 | 3 | 5 | 7 => Some(VnetOffload {
+                checksum: offload & 2 != 0,
+                tso: offload & 4 != 0,
+            }),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown TAP offloads",
+                ))
+            }
+        };
+        // the metadata must describe the interface which the fd is attached to
+        let flags = lower.interface_flags()?;
+        let expected = kind | IFF_NO_PI | if vnet.is_some() { IFF_VNET_HDR } else { 0 };
+        if flags & (IFF_TUN | IFF_TAP | IFF_NO_PI | IFF_VNET_HDR) != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "TAP metadata does not match the interface",
+            ));
+        }
+        let mut tap = TapInterface {
+            lower: Arc::new(RwLock::new(lower)),
+            mtu: meta.mtu,
+            reduce_mtu_by: meta.reduce_mtu_by,
+            medium,
+            vnet,
+        };
+        tap.set_pool_size(DEFAULT_POOL_SIZE);
+        Ok(tap)
+    }
+
+    /// Enables or disables the queue of a device created by `new_multi_queue`.
+    ///
+    /// A disabled queue does not receive frames from the kernel and frames
+    /// sent on it are dropped, until it is enabled again.
+    pub fn set_queue_enabled(&mut self, enabled: bool) -> io::Result<()> {
+        let mut lower = self.lower.write().unwrap();
+        lower.set_queue(enabled)
+    }
+
+    fn attach(
+        mut lower: tap_interface_sys::TapInterfaceDesc,
+        flags: libc::c_int,
+        medium: Medium,
+        vnet: Option<VnetOffload>,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<TapInterface> {
+        lower.attach_interface(flags)?;
+        if let Some(offload) = vnet {
+            lower.set_offload(offload.tun_flags())?;
+        }
         let mtu = lower.interface_mtu()?;
-        Ok(TapInterface {
+        let mtu = match medium {
+            Medium::Ethernet => mtu + SMOLTCP_ETHERNET_HEADER,
+            _ => mtu,
+        };
+        let mut tap = TapInterface {
             lower: Arc::new(RwLock::new(lower)),
-            mtu: mtu + SMOLTCP_ETHERNET_HEADER,
+            mtu,
             reduce_mtu_by: reduce_mtu_by,
+            medium,
+            vnet,
+        };
+        tap.set_pool_size(DEFAULT_POOL_SIZE);
+        Ok(tap)
+    }
+
+    /// Sets how many free frame buffers are kept for reuse by the device and
+    /// its tokens, `DEFAULT_POOL_SIZE` by default.
+    pub fn set_pool_size(&mut self, pool_size: usize) {
+        let pool = BufferPool::new(self.buffer_len(), pool_size);
+        self.lower.write().unwrap().set_pool(pool);
+    }
+
+    fn header_len(&self) -> usize {
+        match self.medium {
+            Medium::Ethernet => SMOLTCP_ETHERNET_HEADER,
+            _ => 0,
+        }
+    }
+
+    fn tx_token(&self) -> TxToken {
+        TxToken {
+            lower: self.lower.clone(),
+            vnet: self.vnet,
+            ethernet: self.medium == Medium::Ethernet,
+            ip_mtu: self.mtu - self.header_len() - self.reduce_mtu_by.unwrap_or(0),
+        }
+    }
+
+    fn buffer_len(&self) -> usize {
+        match self.vnet {
+            Some(offload) if offload.tso => VNET_MAX_PACKET + self.header_len(),
+            _ => self.mtu,
+        }
+    }
+
+    /// Receives up to `bufs.len()` frames with one `read` each, each buffer is
+    /// set to the length of its frame. Interfaces with offloads are not supported.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        self.no_vnet()?;
+        let mut lower = self.lower.write().unwrap();
+        lower.recv_batch(bufs).map_err(|err| {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                lower.stats_mut().rx_would_block += 1;
+            }
+            lower.health_mut().record(err)
         })
     }
+
+    /// Sends the frames with one `write` each and returns how many of them were sent.
+    /// Interfaces with offloads are not supported.
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        self.no_vnet()?;
+        let mut lower = self.lower.write().unwrap();
+        lower.send_batch(frames).map_err(|err| {
+            lower.stats_mut().tx_error(&err);
+            lower.health_mut().record(err)
+        })
+    }
+
+    fn no_vnet(&self) -> io::Result<()> {
+        match self.vnet {
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "batches do not carry virtio-net headers",
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the frame counters of the device.
+    pub fn stats(&self) -> Stats {
+        let lower = self.lower.read().unwrap();
+        let mut stats = *lower.stats();
+        stats.buffer_allocations = lower.pool().allocations();
+        stats
+    }
+
+    /// Returns the `DeviceState` of the device.
+    pub fn state(&self) -> DeviceState {
+        self.lower.read().unwrap().health().state()
+    }
+
+    /// Returns the error which caused the current `DeviceState`.
+    pub fn last_error(&self) -> Option<io::Error> {
+        self.lower.read().unwrap().health().last_error()
+    }
+
+    /// Resets the device to `DeviceState::Up`.
+    pub fn clear_error(&mut self) {
+        self.lower.write().unwrap().health_mut().clear();
+    }
 }
 
 impl<'a> Device<'a> for TapInterface {
@@ -70,37 +381,74 @@ impl<'a> Device<'a> for TapInterface {
 
     fn capabilities(&self) -> DeviceCapabilities {
         let mut caps = DeviceCapabilities::default();
-        caps.max_transmission_unit = self.mtu - self.reduce_mtu_by.unwrap_or(0);
+        caps.medium = self.medium;
+        caps.max_transmission_unit = self.buffer_len() - self.reduce_mtu_by.unwrap_or(0);
+        if let Some(offload) = self.vnet {
+            if offload.checksum || offload.tso {
+                caps.checksum.tcp = Checksum::None;
+                caps.checksum.udp = Checksum::None;
+            }
+        }
         caps
     }
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
         let mut lower = self.lower.write().unwrap();
-        let mut buffer = vec![0; self.mtu];
-        match lower.recv(&mut buffer[..]) {
-            Ok(size) => {
-                buffer.resize(size, 0);
-                let rx = RxToken { buffer };
-                let tx = TxToken {
-                    lower: self.lower.clone(),
-                };
-                Some((rx, tx))
+        let mut frame = lower.pool().get(self.buffer_len());
+        loop {
+            let received = match self.vnet {
+                Some(_) => {
+                    let mut hdr = [0; VNET_HDR_LEN];
+                    let buffer = frame.buffer_mut();
+                    lower.recv_vnet(&mut hdr, buffer).map(|size| {
+                        let hdr = vnet::VnetHdr::from_bytes(&hdr);
+                        let ethernet = self.medium == Medium::Ethernet;
+                        (size, vnet::rx_checksum_ok(&buffer[..size], ethernet, &hdr))
+                    })
+                }
+                None => lower.recv(frame.buffer_mut()).map(|size| (size, true)),
+            };
+            match received {
+                Ok((_, false)) => {
+                    // drop frames with invalid checksum
+                    lower.stats_mut().rx_dropped += 1;
+                    continue;
+                }
+                Ok((size, true)) => {
+                    frame.set_len(size);
+                    let rx = RxToken { frame };
+                    let tx = self.tx_token();
+                    return Some((rx, tx));
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    lower.stats_mut().rx_would_block += 1;
+                    return None;
+                }
+                Err(err) => {
+                    lower.health_mut().record(err);
+                    return None;
+                }
             }
-            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => None,
-            Err(err) => panic!("{}", err),
         }
     }
 
     fn transmit(&'a mut self) -> Option<Self::TxToken> {
-        Some(TxToken {
-            lower: self.lower.clone(),
-        })
+        let r = self.lower.read().unwrap().send_ready();
+        match r {
+            Ok(_) => Some(self.tx_token()),
+            Err(err) => {
+                let mut lower = self.lower.write().unwrap();
+                lower.stats_mut().tx_error(&err);
+                lower.health_mut().record(err);
+                None
+            }
+        }
     }
 }
 
 #[doc(hidden)]
 pub struct RxToken {
-    buffer: Vec<u8>,
+    frame: FrameBuf,
 }
 
 impl phy::RxToken for RxToken {
@@ -108,13 +456,16 @@ impl phy::RxToken for RxToken {
     where
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
-        f(&mut self.buffer[..])
+        f(self.frame.as_mut_slice())
     }
 }
 
 #[doc(hidden)]
 pub struct TxToken {
     lower: Arc<RwLock<tap_interface_sys::TapInterfaceDesc>>,
+    vnet: Option<VnetOffload>,
+    ethernet: bool,
+    ip_mtu: usize,
 }
 
 impl phy::TxToken for TxToken {
@@ -123,9 +474,23 @@ impl phy::TxToken for TxToken {
         F: FnOnce(&mut [u8]) -> Result<R>,
     {
         let mut lower = self.lower.write().unwrap();
-        let mut buffer = vec![0; len];
-        let result = f(&mut buffer);
-        lower.send(&buffer[..]).unwrap();
-        result
+        let mut frame = lower.pool().get(len);
+        frame.set_len(len);
+        let result = f(frame.as_mut_slice());
+        let sent = match self.vnet {
+            Some(offload) => {
+                let buffer = frame.as_mut_slice();
+                let hdr = vnet::tx_prepare(buffer, self.ethernet, offload, self.ip_mtu);
+                lower.send_vnet(&hdr.to_bytes(), buffer)
+            }
+            None => lower.send(frame.as_slice()),
+        };
+        match sent {
+            Ok(_) => result,
+            Err(err) => {
+                lower.stats_mut().tx_error(&err);
+                Err(to_smoltcp_error(&lower.health_mut().record(err)))
+            }
+        }
     }
 }
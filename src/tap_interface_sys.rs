@@ -1,16 +1,26 @@
 use libc;
 use std::io;
+use std::mem;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::string::{String, ToString};
 
 use super::{
-    ifreq, ifreq_for, ifreq_ioctl, IFF_NO_PI, IFF_TAP, SIOCGIFINDEX, SIOCGIFMTU, TUNSETIFF,
+    fd_writable, ifreq, ifreq_for, ifreq_ioctl, ifreq_name, IFF_ATTACH_QUEUE, IFF_DETACH_QUEUE,
+    SIOCGIFINDEX, SIOCGIFMTU, TUNGETIFF, TUNSETIFF, TUNSETOFFLOAD, TUNSETQUEUE, TUNSETVNETHDRSZ,
 };
+use batch::{recv_each, send_each, FrameBuf};
+use health::Health;
+use pool::BufferPool;
+use stats::Stats;
+use vnet::VNET_HDR_LEN;
 
 #[derive(Debug)]
 pub struct TapInterfaceDesc {
     lower: libc::c_int,
     ifreq: ifreq,
+    pool: BufferPool,
+    health: Health,
+    stats: Stats,
 }
 
 impl AsRawFd for TapInterfaceDesc {
@@ -21,6 +31,7 @@ impl AsRawFd for TapInterfaceDesc {
 
 impl TapInterfaceDesc {
     pub fn new(name: &str) -> io::Result<TapInterfaceDesc> {
+        let ifreq = ifreq_for(name)?;
         let lower = unsafe {
             let lower = libc::open(
                 "/dev/net/tun\0".as_ptr() as *const libc::c_char,
@@ -34,12 +45,15 @@ impl TapInterfaceDesc {
 
         Ok(TapInterfaceDesc {
             lower: lower,
-            ifreq: ifreq_for(name),
+            ifreq: ifreq,
+            pool: BufferPool::default(),
+            health: Health::default(),
+            stats: Stats::default(),
         })
     }
 
     pub fn new_macvtap(name: &str) -> io::Result<TapInterfaceDesc> {
-        let mut ifreq = ifreq_for(name);
+        let mut ifreq = ifreq_for(name)?;
         let ifindex = {
             let socketfd = unsafe {
                 let socketfd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP);
@@ -74,14 +88,101 @@ impl TapInterfaceDesc {
         Ok(TapInterfaceDesc {
             lower: lower,
             ifreq: ifreq,
+            pool: BufferPool::default(),
+            health: Health::default(),
+            stats: Stats::default(),
+        })
+    }
+
+    /// Takes ownership of `lower`, which is already attached to the interface `name`.
+    /// `lower` is closed if `name` is not a valid interface name.
+    pub fn from_fd(lower: RawFd, name: &str) -> io::Result<TapInterfaceDesc> {
+        let ifreq = match ifreq_for(name) {
+            Ok(ifreq) => ifreq,
+            Err(err) => {
+                unsafe {
+                    libc::close(lower);
+                }
+                return Err(err);
+            }
+        };
+        Ok(TapInterfaceDesc {
+            lower: lower,
+            ifreq: ifreq,
+            pool: BufferPool::default(),
+            health: Health::default(),
+            stats: Stats::default(),
         })
     }
 
-    pub fn attach_interface(&mut self) -> io::Result<()> {
-        self.ifreq.ifr_data = IFF_TAP | IFF_NO_PI;
+    pub fn name(&self) -> String {
+        ifreq_name(&self.ifreq)
+    }
+
+    pub fn attach_interface(&mut self, flags: libc::c_int) -> io::Result<()> {
+        self.ifreq.ifr_data = flags;
         ifreq_ioctl(self.lower, &mut self.ifreq, TUNSETIFF).map(|_| ())
     }
 
+    /// Returns the `IFF_*` flags of the attached interface.
+    pub fn interface_flags(&self) -> io::Result<libc::c_int> {
+        // TUNGETIFF writes a whole struct ifreq, which is larger than ours
+        let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+        unsafe {
+            if libc::ioctl(self.lower, TUNGETIFF, &mut ifreq as *mut libc::ifreq) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(libc::c_int::from(ifreq.ifr_ifru.ifru_flags))
+        }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    pub fn pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    pub fn set_pool(&mut self, pool: BufferPool) {
+        self.pool = pool;
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn health_mut(&mut self) -> &mut Health {
+        &mut self.health
+    }
+
+    pub fn set_queue(&mut self, enabled: bool) -> io::Result<()> {
+        let mut ifreq = ifreq_for("")?;
+        ifreq.ifr_data = if enabled {
+            IFF_ATTACH_QUEUE
+        } else {
+            IFF_DETACH_QUEUE
+        };
+        ifreq_ioctl(self.lower, &mut ifreq, TUNSETQUEUE).map(|_| ())
+    }
+
+    pub fn set_offload(&mut self, flags: libc::c_uint) -> io::Result<()> {
+        unsafe {
+            let hdr_len = VNET_HDR_LEN as libc::c_int;
+            if libc::ioctl(self.lower, TUNSETVNETHDRSZ, &hdr_len as *const libc::c_int) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::ioctl(self.lower, TUNSETOFFLOAD, flags as libc::c_ulong) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
     pub fn interface_mtu(&mut self) -> io::Result<usize> {
         let lower = unsafe {
             let lower = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP);
@@ -110,10 +211,77 @@ impl TapInterfaceDesc {
             if len == -1 {
                 return Err(io::Error::last_os_error());
             }
+            self.stats.rx(len as usize);
             Ok(len as usize)
         }
     }
 
+    pub fn recv_vnet(
+        &mut self,
+        hdr: &mut [u8; VNET_HDR_LEN],
+        buffer: &mut [u8],
+    ) -> io::Result<usize> {
+        let iov = [
+            libc::iovec {
+                iov_base: hdr.as_mut_ptr() as *mut libc::c_void,
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            },
+        ];
+        unsafe {
+            let len = libc::readv(self.lower, iov.as_ptr(), iov.len() as libc::c_int);
+            if len == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let len = (len as usize).saturating_sub(VNET_HDR_LEN);
+            self.stats.rx(len);
+            Ok(len)
+        }
+    }
+
+    pub fn send_vnet(&mut self, hdr: &[u8; VNET_HDR_LEN], buffer: &[u8]) -> io::Result<usize> {
+        let iov = [
+            libc::iovec {
+                iov_base: hdr.as_ptr() as *mut libc::c_void,
+                iov_len: hdr.len(),
+            },
+            libc::iovec {
+                iov_base: buffer.as_ptr() as *mut libc::c_void,
+                iov_len: buffer.len(),
+            },
+        ];
+        unsafe {
+            let len = libc::writev(self.lower, iov.as_ptr(), iov.len() as libc::c_int);
+            if len == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            let len = (len as usize).saturating_sub(VNET_HDR_LEN);
+            self.stats.tx(len);
+            Ok(len)
+        }
+    }
+
+    pub fn send_ready(&self) -> io::Result<()> {
+        fd_writable(self.lower)
+    }
+
+    /// Reads up to `bufs.len()` frames, one `read` each because TAP devices
+    /// have no batch syscall.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        recv_each(bufs, |buf| {
+            let len = self.recv(buf.buffer_mut())?;
+            buf.set_len(len);
+            Ok(())
+        })
+    }
+
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        send_each(frames, |frame| self.send(frame.as_slice()).map(|_| ()))
+    }
+
     pub fn send(&mut self, buffer: &[u8]) -> io::Result<usize> {
         unsafe {
             let len = libc::write(
@@ -122,8 +290,9 @@ impl TapInterfaceDesc {
                 buffer.len(),
             );
             if len == -1 {
-                Err(io::Error::last_os_error()).unwrap()
+                return Err(io::Error::last_os_error());
             }
+            self.stats.tx(len as usize);
             Ok(len as usize)
         }
     }
@@ -1,13 +1,22 @@
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixDatagram;
+use std::slice;
 
-use super::{ifreq, ifreq_for, ifreq_ioctl, SIOCGIFMTU};
+use super::{fd_writable, ifreq, ifreq_for, ifreq_ioctl, SIOCGIFMTU};
+use batch::{recv_mmsg, send_mmsg, FrameBuf, Staging};
+use health::Health;
+use pool::BufferPool;
+use stats::Stats;
 
 #[derive(Debug)]
 pub struct UnixDomainSocketDesc {
     lower: UnixDatagram,
     ifreq: ifreq,
+    staging: Staging,
+    pool: BufferPool,
+    health: Health,
+    stats: Stats,
 }
 
 impl AsRawFd for UnixDomainSocketDesc {
@@ -21,23 +30,107 @@ impl UnixDomainSocketDesc {
         from: UnixDatagram,
         parent: &str,
     ) -> io::Result<UnixDomainSocketDesc> {
+        let ifreq = ifreq_for(parent)?;
         from.set_nonblocking(true)?;
 
         Ok(UnixDomainSocketDesc {
             lower: from,
-            ifreq: ifreq_for(parent),
+            ifreq: ifreq,
+            staging: Staging::default(),
+            pool: BufferPool::default(),
+            health: Health::default(),
+            stats: Stats::default(),
         })
     }
 
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn health_mut(&mut self) -> &mut Health {
+        &mut self.health
+    }
+
     pub fn interface_mtu(&mut self) -> io::Result<usize> {
         ifreq_ioctl(self.lower.as_raw_fd(), &mut self.ifreq, SIOCGIFMTU).map(|mtu| mtu as usize)
     }
 
-    pub fn recv(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
-        self.lower.recv(buffer)
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        recv_mmsg(self.lower.as_raw_fd(), bufs, &mut self.stats)
+    }
+
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        send_mmsg(self.lower.as_raw_fd(), frames, &mut self.stats)
+    }
+
+    pub fn pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    pub fn set_pool(&mut self, pool: BufferPool) {
+        self.pool = pool;
+    }
+
+    pub fn set_batch(&mut self, rx_batch: usize, tx_batch: usize) {
+        self.staging.set_batch(rx_batch, tx_batch);
+    }
+
+    /// Returns the next frame of the RX staging queue, which is refilled with one `recvmmsg`.
+    pub fn recv_staged(&mut self, mtu: usize) -> io::Result<FrameBuf> {
+        let fd = self.lower.as_raw_fd();
+        let stats = &mut self.stats;
+        self.staging
+            .recv(mtu, &self.pool, |bufs| recv_mmsg(fd, bufs, stats))
+    }
+
+    /// Checks whether a TX token can be handed out: without a TX batch the
+    /// socket buffer must have room, with one the frames left over from a
+    /// full socket buffer must have been sent.
+    pub fn send_ready(&mut self) -> io::Result<()> {
+        if !self.staging.tx_batched() {
+            // frames left over from a larger batch go first
+            let fd = self.lower.as_raw_fd();
+            self.flush_staged().and_then(|_| fd_writable(fd))
+        } else if self.staging.tx_full() {
+            self.flush_staged()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Sends `frame` right away without a TX batch, otherwise stages it and
+    /// sends the batch once it is complete. Only staged frames are kept on
+    /// backpressure.
+    pub fn send_staged(&mut self, frame: FrameBuf) -> io::Result<()> {
+        let fd = self.lower.as_raw_fd();
+        let stats = &mut self.stats;
+        if !self.staging.tx_batched() {
+            return send_mmsg(fd, slice::from_ref(&frame), stats).map(|_| ());
+        }
+        self.staging
+            .send(frame, |frames| send_mmsg(fd, frames, stats))
+    }
+
+    pub fn tx_batched(&self) -> bool {
+        self.staging.tx_batched()
+    }
+
+    pub fn rx_pending(&self) -> bool {
+        self.staging.rx_staged()
     }
 
-    pub fn send(&mut self, buffer: &[u8]) -> io::Result<usize> {
-        self.lower.send(buffer)
+    /// Sends the frames of the TX staging queue with `sendmmsg`.
+    pub fn flush_staged(&mut self) -> io::Result<()> {
+        let fd = self.lower.as_raw_fd();
+        let stats = &mut self.stats;
+        self.staging.flush(|frames| send_mmsg(fd, frames, stats))
     }
 }
@@ -2,15 +2,17 @@ use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixDatagram;
 use std::sync::{Arc, RwLock};
-use std::vec::Vec;
 
+use batch::FrameBuf;
+use health::{is_backpressure, to_smoltcp_error};
+use pool::{BufferPool, DEFAULT_POOL_SIZE};
 use smoltcp::phy;
 use smoltcp::phy::{Device, DeviceCapabilities};
 use smoltcp::time::Instant;
-use smoltcp::{Error, Result};
+use smoltcp::Result;
 use uds;
 
-use SMOLTCP_ETHERNET_HEADER;
+use {DeviceState, Stats, SMOLTCP_ETHERNET_HEADER};
 
 /// A socket that captures or transmits the complete frame.
 #[derive(Debug)]
@@ -33,13 +35,96 @@ impl UnixDomainSocket {
         reduce_mtu_by: Option<usize>,
     ) -> io::Result<UnixDomainSocket> {
         let mut lower = uds::UnixDomainSocketDesc::new_from_unix_datagram(from, parent)?;
-        let mtu = lower.interface_mtu()?;
+        let mtu = lower.interface_mtu()? + SMOLTCP_ETHERNET_HEADER;
+        lower.set_pool(BufferPool::new(mtu, DEFAULT_POOL_SIZE));
         Ok(UnixDomainSocket {
             lower: Arc::new(RwLock::new(lower)),
-            mtu: mtu + SMOLTCP_ETHERNET_HEADER,
+            mtu,
             reduce_mtu_by: reduce_mtu_by,
         })
     }
+
+    /// Sets how many frames the `Device` implementation receives with one
+    /// `recvmmsg` and sends with one `sendmmsg`, both default to 1.
+    ///
+    /// With a TX batch larger than 1, frames are staged until the batch is
+    /// complete, thus `tx_flush` has to be called after `Interface::poll`.
+    pub fn set_batch(&mut self, rx_batch: usize, tx_batch: usize) {
+        self.lower.write().unwrap().set_batch(rx_batch, tx_batch);
+    }
+
+    /// Sets how many free frame buffers are kept for reuse by the device and
+    /// its tokens, `DEFAULT_POOL_SIZE` by default.
+    pub fn set_pool_size(&mut self, pool_size: usize) {
+        let pool = BufferPool::new(self.mtu, pool_size);
+        self.lower.write().unwrap().set_pool(pool);
+    }
+
+    /// Sends the frames staged by the TX tokens, which stay staged if the
+    /// socket buffer is full.
+    pub fn tx_flush(&mut self) -> Result<()> {
+        let mut lower = self.lower.write().unwrap();
+        match lower.flush_staged() {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                lower.stats_mut().tx_error(&err);
+                match lower.health_mut().record(err) {
+                    ref err if is_backpressure(err) => Ok(()),
+                    err => Err(to_smoltcp_error(&err)),
+                }
+            }
+        }
+    }
+
+    pub(crate) fn rx_pending(&self) -> bool {
+        self.lower.read().unwrap().rx_pending()
+    }
+
+    /// Receives up to `bufs.len()` but at most 64 frames with one `recvmmsg`
+    /// call, each buffer is set to the length of its frame and should hold the MTU.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        let mut lower = self.lower.write().unwrap();
+        lower.recv_batch(bufs).map_err(|err| {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                lower.stats_mut().rx_would_block += 1;
+            }
+            lower.health_mut().record(err)
+        })
+    }
+
+    /// Sends up to 64 of the frames with one `sendmmsg` call after the staged
+    /// frames and returns how many of them were sent.
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        let mut lower = self.lower.write().unwrap();
+        let result = lower.flush_staged().and_then(|_| lower.send_batch(frames));
+        result.map_err(|err| {
+            lower.stats_mut().tx_error(&err);
+            lower.health_mut().record(err)
+        })
+    }
+
+    /// Returns the frame counters of the device.
+    pub fn stats(&self) -> Stats {
+        let lower = self.lower.read().unwrap();
+        let mut stats = *lower.stats();
+        stats.buffer_allocations = lower.pool().allocations();
+        stats
+    }
+
+    /// Returns the `DeviceState` of the device.
+    pub fn state(&self) -> DeviceState {
+        self.lower.read().unwrap().health().state()
+    }
+
+    /// Returns the error which caused the current `DeviceState`.
+    pub fn last_error(&self) -> Option<io::Error> {
+        self.lower.read().unwrap().health().last_error()
+    }
+
+    /// Resets the device to `DeviceState::Up`.
+    pub fn clear_error(&mut self) {
+        self.lower.write().unwrap().health_mut().clear();
+    }
 }
 
 impl<'a> Device<'a> for UnixDomainSocket {
@@ -54,36 +139,48 @@ impl<'a> Device<'a> for UnixDomainSocket {
 
     fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
         let mut lower = self.lower.write().unwrap();
-        let mut buffer = vec![0; self.mtu];
-        match lower.recv(&mut buffer[..]) {
-            Ok(size) => {
-                buffer.resize(size, 0);
-                let rx = RxToken { buffer };
+        match lower.recv_staged(self.mtu) {
+            Ok(frame) => {
+                let rx = RxToken { frame };
                 let tx = TxToken {
                     lower: self.lower.clone(),
                 };
                 Some((rx, tx))
             }
-            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => None,
-            Err(err) => panic!("{}", err),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                lower.stats_mut().rx_would_block += 1;
+                None
+            }
+            Err(err) => {
+                lower.health_mut().record(err);
+                None
+            }
         }
     }
 
     fn transmit(&'a mut self) -> Option<Self::TxToken> {
-        Some(TxToken {
-            lower: self.lower.clone(),
-        })
+        let mut lower = self.lower.write().unwrap();
+        match lower.send_ready() {
+            Ok(_) => Some(TxToken {
+                lower: self.lower.clone(),
+            }),
+            Err(err) => {
+                lower.stats_mut().tx_error(&err);
+                lower.health_mut().record(err);
+                None
+            }
+        }
     }
 }
 
 #[doc(hidden)]
 pub struct RxToken {
-    buffer: Vec<u8>,
+    frame: FrameBuf,
 }
 
 impl phy::RxToken for RxToken {
     fn consume<R, F: FnOnce(&mut [u8]) -> Result<R>>(mut self, _timestamp: Instant, f: F) -> Result<R> {
-        f(&mut self.buffer[..])
+        f(self.frame.as_mut_slice())
     }
 }
 
@@ -100,17 +197,62 @@ impl phy::TxToken for TxToken {
         f: F,
     ) -> Result<R> {
         let mut lower = self.lower.write().unwrap();
-        let mut buffer = vec![0; len];
-        let result = f(&mut buffer);
-        match lower.send(&buffer[..]) {
+        let mut frame = lower.pool().get(len);
+        frame.set_len(len);
+        let result = f(frame.as_mut_slice());
+        let staged = lower.tx_batched();
+        match lower.send_staged(frame) {
             Ok(_) => result,
-            Err(ref err) => {
-                if err.kind() == io::ErrorKind::WouldBlock {
-                    Err(Error::Exhausted)
-                } else {
-                    Err(Error::Unaddressable)
+            Err(err) => {
+                lower.stats_mut().tx_error(&err);
+                match lower.health_mut().record(err) {
+                    // the frame stays staged for the next flush
+                    ref err if staged && is_backpressure(err) => result,
+                    err => Err(to_smoltcp_error(&err)),
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smoltcp::phy::TxToken as _;
+    use smoltcp::Error;
+
+    fn send(token: TxToken, frame: &[u8]) -> Result<()> {
+        token.consume(Instant::from_millis(0), frame.len(), |buf| {
+            buf.copy_from_slice(frame);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn full_socket_buffer_is_backpressure() {
+        let (a, b) = UnixDatagram::pair().unwrap();
+        let filler = a.try_clone().unwrap();
+        let mut uds = UnixDomainSocket::new_from_unix_datagram(a, "lo", None).unwrap();
+        let frame = [0; 64];
+        let mut sent: u64 = 0;
+        while let Some(token) = uds.transmit() {
+            send(token, &frame).unwrap();
+            sent += 1;
+            assert!(sent < 100_000, "socket buffer never filled");
+        }
+        assert!(sent > 0);
+        assert_eq!(uds.state(), DeviceState::Up);
+        assert_eq!(uds.stats().tx_would_block, 1);
+
+        // a token handed out before the buffer filled up fails without staging
+        b.set_nonblocking(true).unwrap();
+        let mut buf = [0; 64];
+        while b.recv(&mut buf).is_ok() {}
+        let token = uds.transmit().unwrap();
+        filler.set_nonblocking(true).unwrap();
+        while filler.send(&frame).is_ok() {}
+        assert_eq!(send(token, &frame), Err(Error::Exhausted));
+        assert_eq!(uds.state(), DeviceState::Up);
+        assert_eq!(uds.stats().tx_packets, sent);
+    }
+}
@@ -0,0 +1,427 @@
+use libc;
+
+use SMOLTCP_ETHERNET_HEADER;
+
+pub const VNET_HDR_LEN: usize = 10;
+/// Largest IP packet that can be passed as GSO/GRO super-frame.
+pub const VNET_MAX_PACKET: usize = 65535;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_F_DATA_VALID: u8 = 2;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const IPV4_MF: u16 = 0x2000;
+const IPV4_FRAG_OFFSET: u16 = 0x1fff;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+
+/// Offloads to negotiate with the kernel for a TAP or MACVTAP interface
+/// which then passes a virtio-net header along with every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct VnetOffload {
+    /// TCP and UDP checksums are neither verified by smoltcp for received
+    /// packets nor fully computed for sent packets but left to the kernel.
+    pub checksum: bool,
+    /// TCP segments larger than the interface MTU can be sent and received
+    /// (TSO/GRO), this implies `checksum`.
+    pub tso: bool,
+}
+
+impl VnetOffload {
+    pub fn tun_flags(&self) -> libc::c_uint {
+        const TUN_F_CSUM: libc::c_uint = 0x01;
+        const TUN_F_TSO4: libc::c_uint = 0x02;
+        const TUN_F_TSO6: libc::c_uint = 0x04;
+        let mut flags = 0;
+        if self.checksum || self.tso {
+            flags |= TUN_F_CSUM;
+        }
+        if self.tso {
+            flags |= TUN_F_TSO4 | TUN_F_TSO6;
+        }
+        flags
+    }
+}
+
+/// The legacy `struct virtio_net_hdr` in native endianness.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VnetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    pub hdr_len: u16,
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VnetHdr {
+    pub fn from_bytes(b: &[u8; VNET_HDR_LEN]) -> VnetHdr {
+        VnetHdr {
+            flags: b[0],
+            gso_type: b[1],
+            hdr_len: u16::from_ne_bytes([b[2], b[3]]),
+            gso_size: u16::from_ne_bytes([b[4], b[5]]),
+            csum_start: u16::from_ne_bytes([b[6], b[7]]),
+            csum_offset: u16::from_ne_bytes([b[8], b[9]]),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; VNET_HDR_LEN] {
+        let mut b = [0; VNET_HDR_LEN];
+        b[0] = self.flags;
+        b[1] = self.gso_type;
+        b[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        b[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        b[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        b[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        b
+    }
+}
+
+struct L4 {
+    offset: usize,
+    len: usize,
+    protocol: u8,
+    ipv6: bool,
+    pseudo_sum: u32,
+}
+
+fn sum_words(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+fn parse_l4(frame: &[u8], ethernet: bool) -> Option<L4> {
+    let (ethertype, l3) = if ethernet {
+        if frame.len() < SMOLTCP_ETHERNET_HEADER {
+            return None;
+        }
+        (
+            u16::from_be_bytes([frame[12], frame[13]]),
+            SMOLTCP_ETHERNET_HEADER,
+        )
+    } else {
+        match frame.first().map(|b| b >> 4) {
+            Some(4) => (ETHERTYPE_IPV4, 0),
+            Some(6) => (ETHERTYPE_IPV6, 0),
+            _ => return None,
+        }
+    };
+    let ip = &frame[l3..];
+    let (offset, len, protocol, ipv6, addrs) = match ethertype {
+        ETHERTYPE_IPV4 if ip.len() >= 20 => {
+            let ihl = usize::from(ip[0] & 0x0f) * 4;
+            let total = usize::from(u16::from_be_bytes([ip[2], ip[3]]));
+            if ihl < 20 || total < ihl || total > ip.len() {
+                return None;
+            }
+            let frag = u16::from_be_bytes([ip[6], ip[7]]);
+            if frag & (IPV4_MF | IPV4_FRAG_OFFSET) != 0 {
+                return None; // the L4 header or payload is in another fragment
+            }
+            (l3 + ihl, total - ihl, ip[9], false, &ip[12..20])
+        }
+        ETHERTYPE_IPV6 if ip.len() >= 40 => {
+            let payload = usize::from(u16::from_be_bytes([ip[4], ip[5]]));
+            if 40 + payload > ip.len() {
+                return None;
+            }
+            (l3 + 40, payload, ip[6], true, &ip[8..40])
+        }
+        _ => return None,
+    };
+    let pseudo_sum = sum_words(addrs, u32::from(protocol) + len as u32);
+    Some(L4 {
+        offset,
+        len,
+        protocol,
+        ipv6,
+        pseudo_sum,
+    })
+}
+
+/// Prepares an outgoing frame for the kernel: the TCP/UDP checksum field is
+/// set to the pseudo header sum for checksum offload and TCP segments
+/// exceeding `ip_mtu` are marked for segmentation.
+pub fn tx_prepare(
+    frame: &mut [u8],
+    ethernet: bool,
+    offload: VnetOffload,
+    ip_mtu: usize,
+) -> VnetHdr {
+    let mut hdr = VnetHdr {
+        gso_type: VIRTIO_NET_HDR_GSO_NONE,
+        ..VnetHdr::default()
+    };
+    let l4 = match parse_l4(frame, ethernet) {
+        Some(l4) => l4,
+        None => return hdr,
+    };
+    let csum_offset = match l4.protocol {
+        IPPROTO_TCP if l4.len >= 20 => 16,
+        IPPROTO_UDP if l4.len >= 8 => 6,
+        _ => return hdr,
+    };
+    let field = l4.offset + csum_offset;
+    frame[field..field + 2].copy_from_slice(&fold(l4.pseudo_sum).to_be_bytes());
+    hdr.flags = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+    hdr.csum_start = l4.offset as u16;
+    hdr.csum_offset = csum_offset as u16;
+
+    if offload.tso && l4.protocol == IPPROTO_TCP {
+        let tcp_hdr_len = usize::from(frame[l4.offset + 12] >> 4) * 4;
+        let ip_hdr_len = l4.offset - if ethernet { SMOLTCP_ETHERNET_HEADER } else { 0 };
+        let mss = ip_mtu.saturating_sub(ip_hdr_len + tcp_hdr_len);
+        if mss > 0 && l4.len > tcp_hdr_len + mss {
+            hdr.gso_type = if l4.ipv6 {
+                VIRTIO_NET_HDR_GSO_TCPV6
+            } else {
+                VIRTIO_NET_HDR_GSO_TCPV4
+            };
+            hdr.gso_size = mss as u16;
+            hdr.hdr_len = (l4.offset + tcp_hdr_len) as u16;
+        }
+    }
+    hdr
+}
+
+/// Checks the TCP/UDP checksum of a received frame unless the kernel marked
+/// it as already validated or as carrying a partial checksum. IPv4 fragments
+/// are not checked since the checksum covers the reassembled datagram.
+pub fn rx_checksum_ok(frame: &[u8], ethernet: bool, hdr: &VnetHdr) -> bool {
+    if hdr.flags & (VIRTIO_NET_HDR_F_NEEDS_CSUM | VIRTIO_NET_HDR_F_DATA_VALID) != 0 {
+        return true;
+    }
+    let l4 = match parse_l4(frame, ethernet) {
+        Some(l4) => l4,
+        None => return true,
+    };
+    match l4.protocol {
+        IPPROTO_TCP | IPPROTO_UDP => {
+            let segment = &frame[l4.offset..l4.offset + l4.len];
+            if l4.protocol == IPPROTO_UDP
+                && !l4.ipv6
+                && segment.len() >= 8
+                && segment[6..8] == [0, 0]
+            {
+                return true; // no UDP checksum over IPv4
+            }
+            fold(sum_words(segment, l4.pseudo_sum)) == 0xffff
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: [u8; 4] = [10, 0, 0, 1];
+    const DST: [u8; 4] = [10, 0, 0, 2];
+
+    /// Builds an IPv4 packet, behind an Ethernet header if `ethernet` is set,
+    /// carrying `l4` with the given protocol and a valid L4 checksum.
+    fn ipv4_frame(ethernet: bool, protocol: u8, l4: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        if ethernet {
+            frame.extend_from_slice(&[0x02, 0, 0, 0, 0, 2, 0x02, 0, 0, 0, 0, 1, 0x08, 0x00]);
+        }
+        let total = (20 + l4.len()) as u16;
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&total.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 0, 64, protocol, 0, 0]);
+        frame.extend_from_slice(&SRC);
+        frame.extend_from_slice(&DST);
+        let offset = frame.len();
+        frame.extend_from_slice(l4);
+        let csum_offset = if protocol == IPPROTO_TCP { 16 } else { 6 };
+        let l4 = parse_l4(&frame, ethernet).unwrap();
+        let csum = !fold(sum_words(&frame[offset..], l4.pseudo_sum));
+        frame[offset + csum_offset..offset + csum_offset + 2].copy_from_slice(&csum.to_be_bytes());
+        frame
+    }
+
+    fn tcp_segment(payload: usize) -> Vec<u8> {
+        let mut segment = vec![0; 20 + payload];
+        segment[0..2].copy_from_slice(&1234u16.to_be_bytes());
+        segment[2..4].copy_from_slice(&80u16.to_be_bytes());
+        segment[12] = 5 << 4;
+        for (i, b) in segment[20..].iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        segment
+    }
+
+    fn udp_datagram(payload: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0; 8];
+        datagram[4..6].copy_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    #[test]
+    fn vnet_hdr_round_trip() {
+        let hdr = VnetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+            hdr_len: 54,
+            gso_size: 1448,
+            csum_start: 34,
+            csum_offset: 16,
+        };
+        let parsed = VnetHdr::from_bytes(&hdr.to_bytes());
+        assert_eq!(parsed.flags, hdr.flags);
+        assert_eq!(parsed.gso_type, hdr.gso_type);
+        assert_eq!(parsed.hdr_len, hdr.hdr_len);
+        assert_eq!(parsed.gso_size, hdr.gso_size);
+        assert_eq!(parsed.csum_start, hdr.csum_start);
+        assert_eq!(parsed.csum_offset, hdr.csum_offset);
+    }
+
+    #[test]
+    fn offload_flags() {
+        assert_eq!(VnetOffload::default().tun_flags(), 0);
+        let checksum = VnetOffload {
+            checksum: true,
+            tso: false,
+        };
+        assert_eq!(checksum.tun_flags(), 0x01);
+        let tso = VnetOffload {
+            checksum: false,
+            tso: true,
+        };
+        assert_eq!(tso.tun_flags(), 0x07);
+    }
+
+    #[test]
+    fn rx_accepts_valid_and_rejects_corrupt_checksums() {
+        let hdr = VnetHdr::default();
+        for &ethernet in &[true, false] {
+            let tcp = ipv4_frame(ethernet, IPPROTO_TCP, &tcp_segment(100));
+            assert!(rx_checksum_ok(&tcp, ethernet, &hdr));
+            let udp = ipv4_frame(ethernet, IPPROTO_UDP, &udp_datagram(b"hello"));
+            assert!(rx_checksum_ok(&udp, ethernet, &hdr));
+
+            let mut corrupt = tcp.clone();
+            *corrupt.last_mut().unwrap() ^= 0xff;
+            assert!(!rx_checksum_ok(&corrupt, ethernet, &hdr));
+        }
+    }
+
+    #[test]
+    fn rx_trusts_kernel_flags() {
+        let mut frame = ipv4_frame(true, IPPROTO_TCP, &tcp_segment(10));
+        *frame.last_mut().unwrap() ^= 0xff;
+        for &flags in &[VIRTIO_NET_HDR_F_DATA_VALID, VIRTIO_NET_HDR_F_NEEDS_CSUM] {
+            let hdr = VnetHdr {
+                flags,
+                ..VnetHdr::default()
+            };
+            assert!(rx_checksum_ok(&frame, true, &hdr));
+        }
+    }
+
+    #[test]
+    fn rx_accepts_udp_without_checksum() {
+        let mut frame = ipv4_frame(true, IPPROTO_UDP, &udp_datagram(b"hello"));
+        let field = SMOLTCP_ETHERNET_HEADER + 20 + 6;
+        frame[field..field + 2].copy_from_slice(&[0, 0]);
+        assert!(rx_checksum_ok(&frame, true, &VnetHdr::default()));
+    }
+
+    #[test]
+    fn rx_skips_ipv4_fragments() {
+        let flags = SMOLTCP_ETHERNET_HEADER + 6;
+        let mut first = ipv4_frame(true, IPPROTO_UDP, &udp_datagram(&[7; 64]));
+        first[flags] = 0x20; // MF
+        *first.last_mut().unwrap() ^= 0xff;
+        assert!(rx_checksum_ok(&first, true, &VnetHdr::default()));
+
+        let mut last = ipv4_frame(true, IPPROTO_UDP, &udp_datagram(&[7; 64]));
+        last[flags + 1] = 0x10; // offset 128 bytes
+        *last.last_mut().unwrap() ^= 0xff;
+        assert!(rx_checksum_ok(&last, true, &VnetHdr::default()));
+    }
+
+    #[test]
+    fn tx_prepare_sets_partial_checksum() {
+        let offload = VnetOffload {
+            checksum: true,
+            tso: false,
+        };
+        let mut frame = ipv4_frame(true, IPPROTO_TCP, &tcp_segment(100));
+        let hdr = tx_prepare(&mut frame, true, offload, 1500);
+        assert_eq!(hdr.flags, VIRTIO_NET_HDR_F_NEEDS_CSUM);
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_NONE);
+        assert_eq!(hdr.csum_start, 34);
+        assert_eq!(hdr.csum_offset, 16);
+
+        // completing the checksum like the kernel does yields a valid frame
+        let start = usize::from(hdr.csum_start);
+        let field = start + usize::from(hdr.csum_offset);
+        let csum = !fold(sum_words(&frame[start..], 0));
+        frame[field..field + 2].copy_from_slice(&csum.to_be_bytes());
+        assert!(rx_checksum_ok(&frame, true, &VnetHdr::default()));
+    }
+
+    #[test]
+    fn tx_prepare_tun_udp() {
+        let offload = VnetOffload {
+            checksum: true,
+            tso: false,
+        };
+        let mut frame = ipv4_frame(false, IPPROTO_UDP, &udp_datagram(b"hello"));
+        let hdr = tx_prepare(&mut frame, false, offload, 1500);
+        assert_eq!(hdr.flags, VIRTIO_NET_HDR_F_NEEDS_CSUM);
+        assert_eq!(hdr.csum_start, 20);
+        assert_eq!(hdr.csum_offset, 6);
+    }
+
+    #[test]
+    fn tx_prepare_marks_large_segments_for_tso() {
+        let offload = VnetOffload {
+            checksum: false,
+            tso: true,
+        };
+        let mut small = ipv4_frame(true, IPPROTO_TCP, &tcp_segment(1000));
+        let hdr = tx_prepare(&mut small, true, offload, 1500);
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_NONE);
+
+        let mut large = ipv4_frame(true, IPPROTO_TCP, &tcp_segment(4000));
+        let hdr = tx_prepare(&mut large, true, offload, 1500);
+        assert_eq!(hdr.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!(hdr.gso_size, 1460);
+        assert_eq!(hdr.hdr_len, 54);
+    }
+
+    #[test]
+    fn tx_prepare_leaves_other_frames_alone() {
+        let offload = VnetOffload {
+            checksum: true,
+            tso: true,
+        };
+        let mut arp = vec![0; 42];
+        arp[12..14].copy_from_slice(&[0x08, 0x06]);
+        let copy = arp.clone();
+        let hdr = tx_prepare(&mut arp, true, offload, 1500);
+        assert_eq!(hdr.flags, 0);
+        assert_eq!(arp, copy);
+    }
+}
@@ -0,0 +1,62 @@
+use libc;
+use std::io;
+use std::time::Duration;
+
+use any_device::DeviceOps;
+
+/// Blocks until at least one of the devices can receive a frame or `timeout`
+/// expired, and returns the indexes of the devices which became ready. With a
+/// `timeout` of `None` it blocks until a device is ready, with a zero timeout
+/// it only checks. The result is empty on timeout or when a signal arrived.
+///
+/// Frames staged by an earlier batch or left in a ring count as ready without
+/// waiting. A device whose file descriptor reports an error or hang-up is
+/// returned as well, so that its next `receive` can surface the error.
+/// For Netmap the `poll` syncs the rings, which replaces the RXSYNC of
+/// `uses_wait` and the TXSYNC of frames sent since the last `tx_flush`.
+/// `Impaired` devices should not wait longer than their `next_due`.
+pub fn wait(
+    devices: &mut [&mut dyn DeviceOps],
+    timeout: Option<Duration>,
+) -> io::Result<Vec<usize>> {
+    let mut pollfds = Vec::with_capacity(devices.len());
+    let mut pending = false;
+    for device in devices.iter_mut() {
+        device.prepare_poll();
+        pending |= device.rx_pending();
+        pollfds.push(libc::pollfd {
+            fd: device.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+    let timeout_ms = match timeout {
+        _ if pending => 0,
+        None => -1,
+        Some(timeout) => {
+            // rounded up, so that a short timeout does not turn into busy polling
+            let ms = timeout.as_nanos().div_ceil(1_000_000);
+            ms.min(i32::MAX as u128) as libc::c_int
+        }
+    };
+    let res = unsafe {
+        libc::poll(
+            pollfds.as_mut_ptr(),
+            pollfds.len() as libc::nfds_t,
+            timeout_ms,
+        )
+    };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    Ok(devices
+        .iter()
+        .zip(pollfds.iter())
+        .enumerate()
+        .filter(|&(_, (device, pollfd))| device.rx_pending() || res > 0 && pollfd.revents != 0)
+        .map(|(i, _)| i)
+        .collect())
+}
@@ -0,0 +1,408 @@
+use libc;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, RwLock};
+
+use batch::FrameBuf;
+use fd_passing::{recv_fd, send_fd, SharedKind, SharedMeta};
+use health::to_smoltcp_error;
+use smoltcp::phy;
+use smoltcp::phy::{Device, DeviceCapabilities};
+use smoltcp::time::Instant;
+use smoltcp::{Error, Result};
+use xsk;
+
+use {DeviceState, Stats, SMOLTCP_ETHERNET_HEADER};
+
+pub use xsk::{XdpConfig, XdpShared};
+
+/// An AF_XDP socket bound to one queue of a network interface.
+/// smoltcp compatible like `Netmap` (implicit RX sync, TX kick per packet unless `uses_wait`, zc_forward)
+#[derive(Debug)]
+pub struct XdpSocket {
+    lower: Arc<RwLock<xsk::XskDesc>>,
+    mtu: usize,
+    reduce_mtu_by: Option<usize>,
+}
+
+impl AsRawFd for XdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.lower.read().unwrap().as_raw_fd()
+    }
+}
+
+impl XdpSocket {
+    /// Binds to the queue `queue_id` of the interface `name` with a new UMEM.
+    ///
+    /// An XDP program is attached to the interface which redirects all frames
+    /// of the queue to this socket and passes the frames of queues without a
+    /// socket to the kernel. Further sockets of this process on other queues
+    /// of the interface reuse the program, whose mode then stays the one of
+    /// the first socket, and it is detached again when the last of them is
+    /// dropped. Each queue can only be bound by one socket.
+    /// If `uses_wait` is set, then `wait` needs to be used in order to receive
+    /// packets because it calls `poll`. If `wait` is not used, then a value
+    /// of `false` for `uses_wait` will cause issueing wakeup syscalls on receival
+    /// and transmission.
+    /// This requires superuser privileges or the `CAP_NET_ADMIN`, `CAP_NET_RAW`
+    /// and `CAP_BPF` capabilities.
+    pub fn new(
+        name: &str,
+        queue_id: u32,
+        config: XdpConfig,
+        uses_wait: bool,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<XdpSocket> {
+        let lower = xsk::XskDesc::new(name, queue_id, config, uses_wait)?;
+        XdpSocket::from_lower(lower, reduce_mtu_by)
+    }
+
+    /// Binds to the queue `queue_id` of the interface `name` like `new`, but
+    /// uses the UMEM of `share_with`, which allows `zc_forward` between both.
+    ///
+    /// The queue must be a different one than the one of `share_with`, either
+    /// on the same or on another interface. Only `ring_size`, `native_mode`
+    /// and `zero_copy` of `config` are used. The frames which this socket
+    /// holds go back to the shared UMEM when it is dropped.
+    pub fn new_shared_umem(
+        name: &str,
+        queue_id: u32,
+        config: XdpConfig,
+        share_with: &XdpSocket,
+        uses_wait: bool,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<XdpSocket> {
+        let lower = {
+            let other = share_with.lower.read().unwrap();
+            xsk::XskDesc::new_shared_umem(name, queue_id, config, &other, uses_wait)?
+        };
+        XdpSocket::from_lower(lower, reduce_mtu_by)
+    }
+
+    /// Takes over an AF_XDP socket of another process, e.g. one with access
+    /// to the parent interface, together with its UMEM and the BPF link of
+    /// its XDP program. The other process must not use the socket anymore.
+    /// The file descriptors in `shared` are owned by the new device and are
+    /// closed on errors as well.
+    pub fn new_from_shared_fd(
+        shared: XdpShared,
+        parent: &str,
+        uses_wait: bool,
+        reduce_mtu_by: Option<usize>,
+    ) -> io::Result<XdpSocket> {
+        let lower = xsk::XskDesc::from_shared(shared, parent, uses_wait)?;
+        XdpSocket::from_lower(lower, reduce_mtu_by)
+    }
+
+    /// Sends the socket, its UMEM and the BPF link of its XDP program with
+    /// `SCM_RIGHTS` over the blocking `stream`, together with the state of
+    /// the rings, so that another process can take it over with
+    /// `new_from_unix_stream`. Unlike for Netmap, the device is consumed
+    /// because only one process can own the frames of the UMEM. Fails if the
+    /// UMEM is shared with other sockets or frames are still held by tokens.
+    pub fn send_over_unix_stream(self, stream: &UnixStream) -> io::Result<()> {
+        let mut lower = self.lower.write().unwrap();
+        let shared = lower.shared()?;
+        let mut extra = Vec::with_capacity(20 + shared.lent.len());
+        for value in &[
+            shared.config.frame_size,
+            shared.config.frame_count,
+            shared.config.ring_size,
+            shared.fill_target,
+            shared.fill_outstanding,
+        ] {
+            extra.extend_from_slice(&value.to_le_bytes());
+        }
+        extra.extend_from_slice(&shared.lent);
+        let meta = SharedMeta {
+            parent: lower.name(),
+            mtu: self.mtu,
+            reduce_mtu_by: self.reduce_mtu_by,
+            uses_wait: lower.get_uses_wait(),
+            extra,
+        };
+        send_fd(stream, shared.fd, SharedKind::Xdp, &meta)?;
+        send_fd(
+            stream,
+            shared.umem_fd,
+            SharedKind::XdpUmem,
+            &SharedMeta::default(),
+        )?;
+        send_fd(
+            stream,
+            shared.link_fd,
+            SharedKind::XdpLink,
+            &SharedMeta::default(),
+        )
+    }
+
+    /// Takes over an AF_XDP socket which another process sent with
+    /// `send_over_unix_stream`, without the need to access its parent interface.
+    pub fn new_from_unix_stream(stream: &UnixStream) -> io::Result<XdpSocket> {
+        let (fd, meta) = recv_fd(stream, SharedKind::Xdp)?;
+        let close = |fds: &[RawFd]| {
+            for &fd in fds {
+                unsafe {
+                    libc::close(fd);
+                }
+            }
+        };
+        let (umem_fd, _) = recv_fd(stream, SharedKind::XdpUmem).inspect_err(|_| close(&[fd]))?;
+        let (link_fd, _) =
+            recv_fd(stream, SharedKind::XdpLink).inspect_err(|_| close(&[fd, umem_fd]))?;
+        if meta.extra.len() < 20 {
+            close(&[fd, umem_fd, link_fd]);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid XDP socket state",
+            ));
+        }
+        let (values, lent) = meta.extra.split_at(20);
+        let value = |i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&values[4 * i..4 * i + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let shared = XdpShared {
+            fd,
+            umem_fd,
+            link_fd,
+            config: XdpConfig {
+                frame_size: value(0),
+                frame_count: value(1),
+                ring_size: value(2),
+                ..XdpConfig::default()
+            },
+            fill_target: value(3),
+            fill_outstanding: value(4),
+            lent: lent.to_vec(),
+        };
+        let lower = xsk::XskDesc::from_shared(shared, &meta.parent, meta.uses_wait)?;
+        // frames are received in one UMEM frame
+        meta.check_mtu(lower.frame_size())?;
+        Ok(XdpSocket {
+            lower: Arc::new(RwLock::new(lower)),
+            mtu: meta.mtu,
+            reduce_mtu_by: meta.reduce_mtu_by,
+        })
+    }
+
+    fn from_lower(mut lower: xsk::XskDesc, reduce_mtu_by: Option<usize>) -> io::Result<XdpSocket> {
+        let mtu = lower.interface_mtu()? + SMOLTCP_ETHERNET_HEADER;
+        if mtu > lower.frame_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface MTU exceeds XDP frame size",
+            ));
+        }
+        Ok(XdpSocket {
+            lower: Arc::new(RwLock::new(lower)),
+            mtu,
+            reduce_mtu_by,
+        })
+    }
+
+    pub fn tx_flush(&mut self) -> Result<()> {
+        let mut lower = self.lower.write().unwrap();
+        lower.tx_flush()
+    }
+
+    pub(crate) fn prepare_poll(&mut self) {
+        self.lower.write().unwrap().prepare_poll()
+    }
+
+    pub fn set_uses_wait(&mut self, uses_wait: bool) {
+        let mut lower = self.lower.write().unwrap();
+        lower.set_uses_wait(uses_wait);
+    }
+
+    pub fn get_uses_wait(&self) -> bool {
+        let lower = self.lower.read().unwrap();
+        lower.get_uses_wait()
+    }
+
+    pub(crate) fn with_last_rx<R, F>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        self.lower.read().unwrap().with_last_rx(f)
+    }
+
+    /// Transmits the frame last received on `from` without copying it,
+    /// which requires that both sockets share the UMEM (see `new_shared_umem`).
+    pub fn zc_forward(&mut self, from: &mut XdpSocket) -> Result<()> {
+        let mut lower = self.lower.write().unwrap();
+        let mut from_lower = from.lower.write().unwrap();
+        lower.zc_forward(&mut from_lower)
+    }
+
+    /// Copies up to `bufs.len()` frames out of the RX ring, each buffer is set
+    /// to the length of its frame.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        let mut lower = self.lower.write().unwrap();
+        lower.recv_batch(bufs).map_err(|err| {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                lower.stats_mut().rx_would_block += 1;
+            }
+            lower.health_mut().record(err)
+        })
+    }
+
+    /// Sends the frames and returns how many of them were sent, the kernel is
+    /// woken up once for all of them.
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        let mut lower = self.lower.write().unwrap();
+        // errors of the ring operations are already recorded
+        lower.send_batch(frames).inspect_err(|err| {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                lower.stats_mut().tx_would_block += 1;
+            }
+        })
+    }
+
+    /// Whether both sockets use the same UMEM, which `zc_forward` requires.
+    pub fn shares_umem(&self, other: &XdpSocket) -> bool {
+        let lower = self.lower.read().unwrap();
+        let other = other.lower.read().unwrap();
+        lower.shares_umem(&other)
+    }
+
+    /// Receives the next frame on `from` and forwards it without copying,
+    /// if this socket has room in its TX ring. Returns whether a frame was forwarded.
+    pub(crate) fn zc_forward_next(&mut self, from: &mut XdpSocket) -> Result<bool> {
+        let mut lower = self.lower.write().unwrap();
+        if lower.send_ready().is_err() {
+            lower.tx_flush()?;
+            if lower.send_ready().is_err() {
+                lower.stats_mut().tx_would_block += 1;
+                return Ok(false);
+            }
+        }
+        let mut from_lower = from.lower.write().unwrap();
+        match from_lower.recv() {
+            Ok(_) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                from_lower.stats_mut().rx_would_block += 1;
+                return Ok(false);
+            }
+            Err(err) => return Err(to_smoltcp_error(&from_lower.health_mut().record(err))),
+        }
+        lower.zc_forward(&mut from_lower)?;
+        Ok(true)
+    }
+
+    /// Returns the frame counters of the device, including the frames which
+    /// the kernel dropped so far.
+    pub fn stats(&self) -> Stats {
+        let mut lower = self.lower.write().unwrap();
+        let _ = lower.update_kernel_drops();
+        *lower.stats()
+    }
+
+    /// Returns the `DeviceState` of the device.
+    pub fn state(&self) -> DeviceState {
+        self.lower.read().unwrap().health().state()
+    }
+
+    /// Returns the error which caused the current `DeviceState`.
+    pub fn last_error(&self) -> Option<io::Error> {
+        self.lower.read().unwrap().health().last_error()
+    }
+
+    /// Resets the device to `DeviceState::Up`.
+    pub fn clear_error(&mut self) {
+        self.lower.write().unwrap().health_mut().clear();
+    }
+}
+
+impl<'a> Device<'a> for XdpSocket {
+    type RxToken = RxToken;
+    type TxToken = TxToken;
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.max_transmission_unit = self.mtu - self.reduce_mtu_by.unwrap_or(0);
+        caps
+    }
+
+    fn receive(&'a mut self) -> Option<(Self::RxToken, Self::TxToken)> {
+        let mut lower = self.lower.write().unwrap();
+        match lower.recv() {
+            Ok(frame) => {
+                let rx = RxToken {
+                    lower: self.lower.clone(),
+                    frame,
+                };
+                let tx = TxToken {
+                    lower: self.lower.clone(),
+                };
+                Some((rx, tx))
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                lower.stats_mut().rx_would_block += 1;
+                None
+            }
+            Err(err) => {
+                lower.health_mut().record(err);
+                None
+            }
+        }
+    }
+
+    fn transmit(&'a mut self) -> Option<Self::TxToken> {
+        let r = self.lower.write().unwrap().send_ready();
+        match r {
+            Ok(_) => Some(TxToken {
+                lower: self.lower.clone(),
+            }),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                self.lower.write().unwrap().stats_mut().tx_would_block += 1;
+                let _ = self.tx_flush();
+                None
+            }
+            Err(err) => {
+                let mut lower = self.lower.write().unwrap();
+                lower.stats_mut().tx_error(&err);
+                lower.health_mut().record(err);
+                None
+            }
+        }
+    }
+}
+
+#[doc(hidden)]
+pub struct RxToken {
+    lower: Arc<RwLock<xsk::XskDesc>>,
+    frame: xsk::RxFrame,
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, _timestamp: Instant, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        // not used under the lock because f may send a reply with the TX token
+        let frame = self.lower.write().unwrap().take_rx(self.frame);
+        match frame {
+            Some(mut frame) => f(frame.as_mut_slice()),
+            // the frame was released by a later receive
+            None => Err(Error::Illegal),
+        }
+    }
+}
+
+#[doc(hidden)]
+pub struct TxToken {
+    lower: Arc<RwLock<xsk::XskDesc>>,
+}
+
+impl phy::TxToken for TxToken {
+    fn consume<R, F>(self, _timestamp: Instant, len: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let mut lower = self.lower.write().unwrap();
+        lower.send(len, f)
+    }
+}
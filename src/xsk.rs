@@ -0,0 +1,1405 @@
+use libc;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use smoltcp::{Error, Result};
+
+use super::{ifreq, ifreq_for, ifreq_ioctl, ifreq_name, SIOCGIFMTU};
+use batch::{recv_each, FrameBuf};
+use health::{to_io_error, to_smoltcp_error, Health};
+use stats::Stats;
+
+const AF_XDP: libc::c_int = 44;
+const SOL_XDP: libc::c_int = 283;
+
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_STATISTICS: libc::c_int = 7;
+
+const XDP_SHARED_UMEM: u16 = 1 << 0;
+const XDP_COPY: u16 = 1 << 1;
+const XDP_ZEROCOPY: u16 = 1 << 2;
+const XDP_USE_NEED_WAKEUP: u16 = 1 << 3;
+const XDP_RING_NEED_WAKEUP: u32 = 1 << 0;
+
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x8000_0000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x1_0000_0000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x1_8000_0000;
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+const XDP_PASS: i32 = 2;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+/// Size of the zeroed `union bpf_attr` passed to the kernel, enough for all used commands
+const BPF_ATTR_SIZE: usize = 128;
+
+/// Owner of the UMEM frames which are in the free list or held by user space.
+const NO_SOCKET: u32 = 0;
+
+/// Identifies the socket which a UMEM frame was lent to, file descriptors
+/// can not be used because they are reused after close.
+static NEXT_SOCKET_ID: AtomicU32 = AtomicU32::new(1);
+
+/// The XDP programs attached by sockets of this process, one per interface index.
+static PROGRAMS: Mutex<Vec<(u32, Weak<XdpProgram>)>> = Mutex::new(Vec::new());
+
+/// Configuration of the UMEM and rings of an `XdpSocket`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XdpConfig {
+    /// Size of one UMEM frame, must be a power of two of at least 2048.
+    pub frame_size: u32,
+    /// Number of UMEM frames, shared by all sockets using the same UMEM.
+    pub frame_count: u32,
+    /// Number of descriptors in each of the RX, TX, fill and completion rings, a power of two.
+    pub ring_size: u32,
+    /// Attach the XDP program in driver mode instead of the generic (SKB) mode.
+    pub native_mode: bool,
+    /// Request zero-copy mode from the driver (requires `native_mode`).
+    pub zero_copy: bool,
+}
+
+impl Default for XdpConfig {
+    fn default() -> XdpConfig {
+        XdpConfig {
+            frame_size: 4096,
+            frame_count: 4096,
+            ring_size: 1024,
+            native_mode: false,
+            zero_copy: false,
+        }
+    }
+}
+
+impl XdpConfig {
+    fn is_valid(&self) -> bool {
+        self.frame_size >= 2048
+            && self.frame_size.is_power_of_two()
+            && self.ring_size.is_power_of_two()
+            && self.frame_count >= 2 * self.ring_size
+    }
+}
+
+/// What another process needs to take over an AF_XDP socket, see
+/// `XdpSocket::new_from_shared_fd`.
+#[derive(Debug)]
+pub struct XdpShared {
+    /// The socket, bound to its queue.
+    pub fd: RawFd,
+    /// The memfd of the UMEM registered with the socket.
+    pub umem_fd: RawFd,
+    /// The BPF link which keeps the XDP program attached.
+    pub link_fd: RawFd,
+    /// Only `frame_size`, `frame_count` and `ring_size` are used.
+    pub config: XdpConfig,
+    /// How many frames the socket keeps in the fill ring.
+    pub fill_target: u32,
+    /// Frames handed to the fill ring which were not received yet.
+    pub fill_outstanding: u32,
+    /// One bit per UMEM frame which the kernel holds in one of the rings.
+    pub lent: Vec<u8>,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct xdp_ring_offset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+/// Older kernels only fill the first three fields.
+#[repr(C)]
+#[derive(Default)]
+struct xdp_statistics {
+    rx_dropped: u64,
+    rx_invalid_descs: u64,
+    tx_invalid_descs: u64,
+    rx_ring_full: u64,
+    rx_fill_ring_empty_descs: u64,
+    tx_ring_empty_descs: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct xdp_mmap_offsets {
+    rx: xdp_ring_offset,
+    tx: xdp_ring_offset,
+    fr: xdp_ring_offset,
+    cr: xdp_ring_offset,
+}
+
+#[repr(C)]
+struct xdp_umem_reg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+struct sockaddr_xdp {
+    sxdp_family: u16,
+    sxdp_flags: u16,
+    sxdp_ifindex: u32,
+    sxdp_queue_id: u32,
+    sxdp_shared_umem_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct xdp_desc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+fn sockopt<T>(fd: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            SOL_XDP,
+            name,
+            value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The frame memory registered with the kernel, possibly shared by several sockets.
+#[derive(Debug)]
+pub struct Umem {
+    fd: libc::c_int,
+    area: *mut u8,
+    len: usize,
+    frame_size: u32,
+    free: Vec<u64>,
+    /// The socket whose fill or TX ring each frame was handed to, so that
+    /// the frames return to the free list when that socket is closed.
+    lent_to: Vec<u32>,
+}
+
+unsafe impl Send for Umem {}
+
+impl Umem {
+    fn new(config: &XdpConfig) -> io::Result<Umem> {
+        if !config.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid XDP configuration",
+            ));
+        }
+        // in a memfd, so that the UMEM can be passed to another process
+        let fd = unsafe {
+            libc::memfd_create(
+                "usnet_umem\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC,
+            )
+        };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = config.frame_size as usize * config.frame_count as usize;
+        if unsafe { libc::ftruncate(fd, len as libc::off_t) } == -1 {
+            let err = io::Error::last_os_error();
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(err);
+        }
+        Umem::map(fd, config)
+    }
+
+    /// Maps the UMEM memfd `fd` of another process, which is closed on errors.
+    fn from_fd(fd: libc::c_int, config: &XdpConfig) -> io::Result<Umem> {
+        let len = config.frame_size as usize * config.frame_count as usize;
+        let mut stat: libc::stat = unsafe { mem::zeroed() };
+        let checked = if !config.is_valid() {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid XDP configuration",
+            ))
+        } else if unsafe { libc::fstat(fd, &mut stat) } == -1 {
+            Err(io::Error::last_os_error())
+        } else if (stat.st_size as u64) < len as u64 {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "UMEM too small"))
+        } else {
+            Ok(())
+        };
+        if let Err(err) = checked {
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(err);
+        }
+        Umem::map(fd, config)
+    }
+
+    /// Maps the frames of the memfd `fd`, which is closed on errors.
+    fn map(fd: libc::c_int, config: &XdpConfig) -> io::Result<Umem> {
+        let len = config.frame_size as usize * config.frame_count as usize;
+        let area = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            )
+        };
+        if area == libc::MAP_FAILED {
+            let err = io::Error::last_os_error();
+            unsafe {
+                libc::close(fd);
+            }
+            return Err(err);
+        }
+        let free = (0..u64::from(config.frame_count))
+            .map(|i| i * u64::from(config.frame_size))
+            .collect();
+        Ok(Umem {
+            fd,
+            area: area as *mut u8,
+            len,
+            frame_size: config.frame_size,
+            free,
+            lent_to: vec![NO_SOCKET; config.frame_count as usize],
+        })
+    }
+
+    fn frame_base(&self, addr: u64) -> u64 {
+        addr & !(u64::from(self.frame_size) - 1)
+    }
+
+    fn frame_index(&self, addr: u64) -> usize {
+        (addr / u64::from(self.frame_size)) as usize
+    }
+
+    fn lend(&mut self, addr: u64, socket: u32) {
+        let index = self.frame_index(addr);
+        self.lent_to[index] = socket;
+    }
+
+    /// Puts the frame of `addr` back to the free list.
+    fn give_back(&mut self, addr: u64) {
+        let index = self.frame_index(addr);
+        self.lent_to[index] = NO_SOCKET;
+        let base = self.frame_base(addr);
+        self.free.push(base);
+    }
+
+    /// Puts all frames still lent to `socket` back to the free list, which
+    /// must only be done after the kernel stopped using them.
+    fn give_back_all(&mut self, socket: u32) {
+        for index in 0..self.lent_to.len() {
+            if self.lent_to[index] == socket {
+                self.lent_to[index] = NO_SOCKET;
+                self.free.push(index as u64 * u64::from(self.frame_size));
+            }
+        }
+    }
+
+    /// One bit per frame lent to `socket`, for `lend_bitmap` in another process.
+    fn lent_bitmap(&self, socket: u32) -> Vec<u8> {
+        let mut lent = vec![0; self.lent_to.len().div_ceil(8)];
+        for (index, &lent_to) in self.lent_to.iter().enumerate() {
+            if lent_to == socket {
+                lent[index / 8] |= 1 << (index % 8);
+            }
+        }
+        lent
+    }
+
+    /// Lends the frames whose bit is set in `lent` to `socket`, the other
+    /// frames are free.
+    fn lend_bitmap(&mut self, lent: &[u8], socket: u32) {
+        let is_lent = |index: usize| lent[index / 8] & (1 << (index % 8)) != 0;
+        let frame_size = u64::from(self.frame_size);
+        self.free
+            .retain(|&addr| !is_lent((addr / frame_size) as usize));
+        for (index, lent_to) in self.lent_to.iter_mut().enumerate() {
+            if is_lent(index) {
+                *lent_to = socket;
+            }
+        }
+    }
+
+    unsafe fn slice(&self, addr: u64, len: usize) -> &'static mut [u8] {
+        slice::from_raw_parts_mut(self.area.add(addr as usize), len)
+    }
+}
+
+/// A frame of the RX ring, identified by the number of its reception so that
+/// a token can tell whether it is still the last received frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RxFrame {
+    addr: u64,
+    len: u32,
+    seq: u64,
+}
+
+/// A received frame taken over by a token, which keeps the UMEM mapped and
+/// returns the frame to the free list once dropped.
+#[derive(Debug)]
+pub struct XskFrame {
+    umem: Arc<Mutex<Umem>>,
+    addr: u64,
+    data: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for XskFrame {}
+
+impl XskFrame {
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.data, self.len) }
+    }
+}
+
+impl Drop for XskFrame {
+    fn drop(&mut self) {
+        self.umem.lock().unwrap().give_back(self.addr);
+    }
+}
+
+impl Drop for Umem {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.area as *mut libc::c_void, self.len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// A single-producer/single-consumer ring shared with the kernel.
+#[derive(Debug)]
+struct Ring {
+    map: *mut u8,
+    map_len: usize,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const AtomicU32,
+    desc: *mut u8,
+    size: u32,
+}
+
+impl Ring {
+    fn map(
+        fd: libc::c_int,
+        off: &xdp_ring_offset,
+        size: u32,
+        desc_size: usize,
+        pgoff: libc::off_t,
+    ) -> io::Result<Ring> {
+        let map_len = off.desc as usize + size as usize * desc_size;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                pgoff,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let map = map as *mut u8;
+        unsafe {
+            Ok(Ring {
+                map,
+                map_len,
+                producer: map.add(off.producer as usize) as *const AtomicU32,
+                consumer: map.add(off.consumer as usize) as *const AtomicU32,
+                flags: map.add(off.flags as usize) as *const AtomicU32,
+                desc: map.add(off.desc as usize),
+                size,
+            })
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    fn needs_wakeup(&self) -> bool {
+        unsafe { (*self.flags).load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP != 0 }
+    }
+
+    /// Entries that can be consumed from a ring filled by the kernel.
+    fn available(&self) -> u32 {
+        let prod = self.producer().load(Ordering::Acquire);
+        prod.wrapping_sub(self.consumer().load(Ordering::Relaxed))
+    }
+
+    /// Entries that can be produced into a ring drained by the kernel.
+    fn free(&self) -> u32 {
+        let cons = self.consumer().load(Ordering::Acquire);
+        self.size - self.producer().load(Ordering::Relaxed).wrapping_sub(cons)
+    }
+
+    unsafe fn entry<T>(&self, idx: u32) -> *mut T {
+        (self.desc as *mut T).add((idx & (self.size - 1)) as usize)
+    }
+
+    fn pop<T: Copy>(&self) -> Option<T> {
+        if self.available() == 0 {
+            return None;
+        }
+        let cons = self.consumer().load(Ordering::Relaxed);
+        let value = unsafe { ptr::read(self.entry::<T>(cons)) };
+        self.consumer()
+            .store(cons.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    fn push<T>(&self, value: T) -> bool {
+        if self.free() == 0 {
+            return false;
+        }
+        let prod = self.producer().load(Ordering::Relaxed);
+        unsafe { ptr::write(self.entry::<T>(prod), value) };
+        self.producer()
+            .store(prod.wrapping_add(1), Ordering::Release);
+        true
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.map_len);
+        }
+    }
+}
+
+fn bpf(cmd: libc::c_long, attr: &mut [u8; BPF_ATTR_SIZE]) -> io::Result<libc::c_int> {
+    let res = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr.as_mut_ptr(), BPF_ATTR_SIZE) };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(res as libc::c_int)
+}
+
+fn put_u32(attr: &mut [u8; BPF_ATTR_SIZE], offset: usize, value: u32) {
+    attr[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn put_u64(attr: &mut [u8; BPF_ATTR_SIZE], offset: usize, value: u64) {
+    attr[offset..offset + 8].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn bpf_insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> [u8; 8] {
+    let mut insn = [0; 8];
+    insn[0] = code;
+    insn[1] = (src << 4) | (dst & 0x0f);
+    insn[2..4].copy_from_slice(&off.to_ne_bytes());
+    insn[4..8].copy_from_slice(&imm.to_ne_bytes());
+    insn
+}
+
+/// An XSKMAP and the XDP program redirecting all frames of a queue to the
+/// socket stored for that queue (or passing them to the kernel stack),
+/// attached through a BPF link which detaches the program when closed.
+/// All sockets of this process on the same interface share one program.
+#[derive(Debug)]
+struct XdpProgram {
+    map_fd: libc::c_int,
+    prog_fd: libc::c_int,
+    link_fd: libc::c_int,
+}
+
+impl XdpProgram {
+    fn attach(ifindex: u32, native_mode: bool) -> io::Result<XdpProgram> {
+        let mut prog = XdpProgram {
+            map_fd: -1,
+            prog_fd: -1,
+            link_fd: -1,
+        };
+        let mut attr = [0; BPF_ATTR_SIZE];
+        put_u32(&mut attr, 0, BPF_MAP_TYPE_XSKMAP);
+        put_u32(&mut attr, 4, 4); // key_size
+        put_u32(&mut attr, 8, 4); // value_size
+        put_u32(&mut attr, 12, 64); // max_entries (queues)
+        prog.map_fd = bpf(BPF_MAP_CREATE, &mut attr)?;
+
+        let insns = [
+            // r2 = ((struct xdp_md *) r1)->rx_queue_index
+            bpf_insn(0x61, 2, 1, 16, 0),
+            // r1 = map (BPF_PSEUDO_MAP_FD)
+            bpf_insn(0x18, 1, 1, 0, prog.map_fd),
+            bpf_insn(0, 0, 0, 0, 0),
+            // r3 = XDP_PASS as fallback action if no socket is in the map
+            bpf_insn(0xb7, 3, 0, 0, XDP_PASS),
+            bpf_insn(0x85, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+            bpf_insn(0x95, 0, 0, 0, 0),
+        ];
+        let license = b"GPL\0";
+        let mut attr = [0; BPF_ATTR_SIZE];
+        put_u32(&mut attr, 0, BPF_PROG_TYPE_XDP);
+        put_u32(&mut attr, 4, insns.len() as u32);
+        put_u64(&mut attr, 8, insns.as_ptr() as u64);
+        put_u64(&mut attr, 16, license.as_ptr() as u64);
+        prog.prog_fd = bpf(BPF_PROG_LOAD, &mut attr)?;
+
+        let mut attr = [0; BPF_ATTR_SIZE];
+        put_u32(&mut attr, 0, prog.prog_fd as u32);
+        put_u32(&mut attr, 4, ifindex);
+        put_u32(&mut attr, 8, BPF_XDP);
+        let mode = if native_mode {
+            XDP_FLAGS_DRV_MODE
+        } else {
+            XDP_FLAGS_SKB_MODE
+        };
+        put_u32(&mut attr, 12, mode);
+        prog.link_fd = bpf(BPF_LINK_CREATE, &mut attr)?;
+        Ok(prog)
+    }
+
+    /// Returns the program which another socket of this process attached to
+    /// the interface, or attaches a new one with the given mode.
+    fn shared(ifindex: u32, native_mode: bool) -> io::Result<Arc<XdpProgram>> {
+        let mut programs = PROGRAMS.lock().unwrap();
+        programs.retain(|(_, prog)| prog.strong_count() > 0);
+        let attached = programs
+            .iter()
+            .find(|&&(index, _)| index == ifindex)
+            .and_then(|(_, prog)| prog.upgrade());
+        if let Some(prog) = attached {
+            return Ok(prog);
+        }
+        let prog = Arc::new(XdpProgram::attach(ifindex, native_mode)?);
+        programs.push((ifindex, Arc::downgrade(&prog)));
+        Ok(prog)
+    }
+
+    fn insert(&self, queue_id: u32, xsk_fd: libc::c_int) -> io::Result<()> {
+        let key = queue_id;
+        let value = xsk_fd as u32;
+        let mut attr = [0; BPF_ATTR_SIZE];
+        put_u32(&mut attr, 0, self.map_fd as u32);
+        put_u64(&mut attr, 8, &key as *const u32 as u64);
+        put_u64(&mut attr, 16, &value as *const u32 as u64);
+        bpf(BPF_MAP_UPDATE_ELEM, &mut attr).map(|_| ())
+    }
+}
+
+impl Drop for XdpProgram {
+    fn drop(&mut self) {
+        for fd in &[self.link_fd, self.prog_fd, self.map_fd] {
+            if *fd != -1 {
+                unsafe {
+                    libc::close(*fd);
+                }
+            }
+        }
+    }
+}
+
+/// Maps the RX, TX, fill and completion rings of the socket `fd`.
+fn map_rings(fd: libc::c_int, size: u32) -> io::Result<(Ring, Ring, Ring, Ring)> {
+    let mut off = xdp_mmap_offsets::default();
+    let mut optlen = mem::size_of::<xdp_mmap_offsets>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            SOL_XDP,
+            XDP_MMAP_OFFSETS,
+            &mut off as *mut xdp_mmap_offsets as *mut libc::c_void,
+            &mut optlen,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let desc_len = mem::size_of::<xdp_desc>();
+    Ok((
+        Ring::map(fd, &off.rx, size, desc_len, XDP_PGOFF_RX_RING)?,
+        Ring::map(fd, &off.tx, size, desc_len, XDP_PGOFF_TX_RING)?,
+        Ring::map(fd, &off.fr, size, 8, XDP_UMEM_PGOFF_FILL_RING)?,
+        Ring::map(fd, &off.cr, size, 8, XDP_UMEM_PGOFF_COMPLETION_RING)?,
+    ))
+}
+
+#[derive(Debug)]
+pub struct XskDesc {
+    rx: Ring,
+    tx: Ring,
+    fill: Ring,
+    comp: Ring,
+    prog: Option<Arc<XdpProgram>>,
+    fd: libc::c_int,
+    id: u32,
+    umem: Arc<Mutex<Umem>>,
+    zc_rx_frame: Option<RxFrame>,
+    rx_seq: u64,
+    fill_target: u32,
+    fill_outstanding: u32,
+    ifreq: ifreq,
+    uses_wait: bool,
+    health: Health,
+    stats: Stats,
+}
+
+unsafe impl Send for XskDesc {}
+unsafe impl Sync for XskDesc {}
+
+impl AsRawFd for XskDesc {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl XskDesc {
+    pub fn new(
+        name: &str,
+        queue_id: u32,
+        config: XdpConfig,
+        uses_wait: bool,
+    ) -> io::Result<XskDesc> {
+        let umem = Arc::new(Mutex::new(Umem::new(&config)?));
+        XskDesc::open(name, queue_id, config, umem, None, uses_wait)
+    }
+
+    pub fn new_shared_umem(
+        name: &str,
+        queue_id: u32,
+        config: XdpConfig,
+        share_with: &XskDesc,
+        uses_wait: bool,
+    ) -> io::Result<XskDesc> {
+        let umem = share_with.umem.clone();
+        XskDesc::open(name, queue_id, config, umem, Some(share_with.fd), uses_wait)
+    }
+
+    fn open(
+        name: &str,
+        queue_id: u32,
+        config: XdpConfig,
+        umem: Arc<Mutex<Umem>>,
+        shared_fd: Option<libc::c_int>,
+        uses_wait: bool,
+    ) -> io::Result<XskDesc> {
+        let ifreq = ifreq_for(name)?;
+        let ifname = name.to_string() + "\0";
+        let ifindex = unsafe { libc::if_nametoindex(ifname.as_ptr() as *const libc::c_char) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { libc::socket(AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let close_on_err = |err: io::Error| {
+            unsafe {
+                libc::close(fd);
+            }
+            err
+        };
+
+        if shared_fd.is_none() {
+            let u = umem.lock().unwrap();
+            let reg = xdp_umem_reg {
+                addr: u.area as u64,
+                len: u.len as u64,
+                chunk_size: u.frame_size,
+                headroom: 0,
+                flags: 0,
+                tx_metadata_len: 0,
+            };
+            sockopt(fd, XDP_UMEM_REG, &reg).map_err(close_on_err)?;
+        }
+        let size = config.ring_size;
+        sockopt(fd, XDP_UMEM_FILL_RING, &size).map_err(close_on_err)?;
+        sockopt(fd, XDP_UMEM_COMPLETION_RING, &size).map_err(close_on_err)?;
+        sockopt(fd, XDP_RX_RING, &size).map_err(close_on_err)?;
+        sockopt(fd, XDP_TX_RING, &size).map_err(close_on_err)?;
+
+        let (rx, tx, fill, comp) = map_rings(fd, size).map_err(close_on_err)?;
+
+        let mut desc = XskDesc {
+            rx,
+            tx,
+            fill,
+            comp,
+            prog: None,
+            fd,
+            id: NEXT_SOCKET_ID.fetch_add(1, Ordering::Relaxed),
+            umem,
+            zc_rx_frame: None,
+            rx_seq: 0,
+            fill_target: 0,
+            fill_outstanding: 0,
+            ifreq,
+            uses_wait,
+            health: Health::default(),
+            stats: Stats::default(),
+        };
+        // half of the frames still available go to the kernel for RX
+        desc.fill_target = {
+            let u = desc.umem.lock().unwrap();
+            (u.free.len() as u32 / 2).min(size)
+        };
+        desc.refill();
+
+        // sockets sharing the UMEM inherit the flags of its owner
+        let flags = match shared_fd {
+            Some(_) => XDP_SHARED_UMEM,
+            None if config.zero_copy => XDP_USE_NEED_WAKEUP | XDP_ZEROCOPY,
+            None => XDP_USE_NEED_WAKEUP | XDP_COPY,
+        };
+        let addr = sockaddr_xdp {
+            sxdp_family: AF_XDP as u16,
+            sxdp_flags: flags,
+            sxdp_ifindex: ifindex,
+            sxdp_queue_id: queue_id,
+            sxdp_shared_umem_fd: shared_fd.unwrap_or(0) as u32,
+        };
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const sockaddr_xdp as *const libc::sockaddr,
+                mem::size_of::<sockaddr_xdp>() as libc::socklen_t,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let prog = XdpProgram::shared(ifindex, config.native_mode)?;
+        prog.insert(queue_id, fd)?;
+        desc.prog = Some(prog);
+        Ok(desc)
+    }
+
+    /// Takes over the socket of another process as described by `shared`.
+    /// Takes ownership of its file descriptors, which are closed on errors too.
+    pub fn from_shared(shared: XdpShared, parent: &str, uses_wait: bool) -> io::Result<XskDesc> {
+        let XdpShared {
+            fd,
+            umem_fd,
+            link_fd,
+            config,
+            fill_target,
+            fill_outstanding,
+            lent,
+        } = shared;
+        // the link keeps the XDP program of the other process attached
+        let prog = Arc::new(XdpProgram {
+            map_fd: -1,
+            prog_fd: -1,
+            link_fd,
+        });
+        let close_on_err = |err: io::Error| {
+            unsafe {
+                libc::close(fd);
+            }
+            err
+        };
+        let lent_frames: u32 = lent.iter().map(|byte| byte.count_ones()).sum();
+        let valid = config.is_valid()
+            && lent.len() == (config.frame_count as usize).div_ceil(8)
+            && fill_outstanding <= lent_frames
+            && fill_target <= config.ring_size;
+        let ifreq = if valid {
+            ifreq_for(parent)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid XDP socket state",
+            ))
+        };
+        let ifreq = ifreq.map_err(|err| {
+            unsafe {
+                libc::close(umem_fd);
+            }
+            close_on_err(err)
+        })?;
+        let mut umem = Umem::from_fd(umem_fd, &config).map_err(close_on_err)?;
+        let (rx, tx, fill, comp) = map_rings(fd, config.ring_size).map_err(close_on_err)?;
+        let id = NEXT_SOCKET_ID.fetch_add(1, Ordering::Relaxed);
+        umem.lend_bitmap(&lent, id);
+        Ok(XskDesc {
+            rx,
+            tx,
+            fill,
+            comp,
+            prog: Some(prog),
+            fd,
+            id,
+            umem: Arc::new(Mutex::new(umem)),
+            zc_rx_frame: None,
+            rx_seq: 0,
+            fill_target,
+            fill_outstanding,
+            ifreq,
+            uses_wait,
+            health: Health::default(),
+            stats: Stats::default(),
+        })
+    }
+
+    /// Describes the socket for another process, which takes it over with
+    /// `from_shared`. Fails if the UMEM is shared with other sockets.
+    pub fn shared(&mut self) -> io::Result<XdpShared> {
+        if Arc::strong_count(&self.umem) > 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "UMEM shared with other sockets",
+            ));
+        }
+        // frames held by user space are free for the other process
+        self.release_rx();
+        self.reclaim();
+        let umem = self.umem.lock().unwrap();
+        Ok(XdpShared {
+            fd: self.fd,
+            umem_fd: umem.fd,
+            link_fd: self.prog.as_ref().map_or(-1, |prog| prog.link_fd),
+            config: XdpConfig {
+                frame_size: umem.frame_size,
+                frame_count: umem.lent_to.len() as u32,
+                ring_size: self.rx.size,
+                ..XdpConfig::default()
+            },
+            fill_target: self.fill_target,
+            fill_outstanding: self.fill_outstanding,
+            lent: umem.lent_bitmap(self.id),
+        })
+    }
+
+    pub fn name(&self) -> String {
+        ifreq_name(&self.ifreq)
+    }
+
+    /// Moves frames from the completion ring to the free list.
+    fn reclaim(&mut self) {
+        let mut umem = self.umem.lock().unwrap();
+        while let Some(addr) = self.comp.pop::<u64>() {
+            umem.give_back(addr);
+        }
+    }
+
+    /// Hands free frames to the kernel for RX until the fill target is reached.
+    fn refill(&mut self) {
+        let mut umem = self.umem.lock().unwrap();
+        while self.fill_outstanding < self.fill_target {
+            let addr = match umem.free.pop() {
+                Some(addr) => addr,
+                None => break,
+            };
+            if !self.fill.push(addr) {
+                umem.free.push(addr);
+                break;
+            }
+            umem.lend(addr, self.id);
+            self.fill_outstanding += 1;
+        }
+    }
+
+    fn kick(&mut self) -> Result<()> {
+        self.stats.tx_flushes += 1;
+        let res =
+            unsafe { libc::sendto(self.fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
+        if res == -1 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                // the kernel is still busy with earlier descriptors
+                Some(libc::EAGAIN) | Some(libc::EBUSY) => {}
+                _ => return Err(to_smoltcp_error(&self.health.record(err))),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut Stats {
+        &mut self.stats
+    }
+
+    /// Sets the kernel drops of the stats to the frames which the kernel
+    /// dropped for this socket, including those for a full RX ring.
+    pub fn update_kernel_drops(&mut self) -> io::Result<()> {
+        let mut stats = xdp_statistics::default();
+        let mut optlen = mem::size_of::<xdp_statistics>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                self.fd,
+                SOL_XDP,
+                XDP_STATISTICS,
+                &mut stats as *mut xdp_statistics as *mut libc::c_void,
+                &mut optlen,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        self.stats.kernel_drops = stats.rx_dropped + stats.rx_ring_full;
+        Ok(())
+    }
+
+    pub fn health(&self) -> &Health {
+        &self.health
+    }
+
+    pub fn health_mut(&mut self) -> &mut Health {
+        &mut self.health
+    }
+
+    pub fn tx_flush(&mut self) -> Result<()> {
+        self.kick()
+    }
+
+    pub fn set_uses_wait(&mut self, uses_wait: bool) {
+        self.uses_wait = uses_wait;
+    }
+
+    pub fn get_uses_wait(&self) -> bool {
+        self.uses_wait
+    }
+
+    pub fn interface_mtu(&mut self) -> io::Result<usize> {
+        let lower = unsafe {
+            let lower = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, libc::IPPROTO_IP);
+            if lower == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            lower
+        };
+
+        let mtu = ifreq_ioctl(lower, &mut self.ifreq, SIOCGIFMTU).map(|mtu| mtu as usize);
+
+        unsafe {
+            libc::close(lower);
+        }
+
+        mtu
+    }
+
+    pub fn frame_size(&self) -> usize {
+        self.umem.lock().unwrap().frame_size as usize
+    }
+
+    fn next_rx(&mut self) -> Option<RxFrame> {
+        let desc = self.rx.pop::<xdp_desc>()?;
+        self.fill_outstanding -= 1;
+        self.rx_seq += 1;
+        let frame = RxFrame {
+            addr: desc.addr,
+            len: desc.len,
+            seq: self.rx_seq,
+        };
+        // read or zero copy forward can only work with this frame before next receive
+        self.zc_rx_frame = Some(frame);
+        self.stats.rx(desc.len as usize);
+        Some(frame)
+    }
+
+    fn release_rx(&mut self) {
+        if let Some(frame) = self.zc_rx_frame.take() {
+            // the previous frame was not taken or forwarded and can be reused for RX
+            self.umem.lock().unwrap().give_back(frame.addr);
+        }
+    }
+
+    /// Takes over `frame` for a token if it is still the last received frame,
+    /// so that it stays valid without holding the descriptor.
+    pub fn take_rx(&mut self, frame: RxFrame) -> Option<XskFrame> {
+        if self.zc_rx_frame != Some(frame) {
+            return None;
+        }
+        self.zc_rx_frame = None;
+        let mut umem = self.umem.lock().unwrap();
+        // held by user space now, not by this socket
+        umem.lend(frame.addr, NO_SOCKET);
+        Some(XskFrame {
+            umem: self.umem.clone(),
+            addr: frame.addr,
+            data: unsafe { umem.area.add(frame.addr as usize) },
+            len: frame.len as usize,
+        })
+    }
+
+    /// Releases the last received frame and refills the fill ring before a
+    /// `poll` on the socket, which could not receive into an empty fill ring.
+    pub fn prepare_poll(&mut self) {
+        self.release_rx();
+        self.reclaim();
+        self.refill();
+    }
+
+    pub fn recv(&mut self) -> io::Result<RxFrame> {
+        self.release_rx();
+        self.reclaim();
+        self.refill();
+        if let Some(frame) = self.next_rx() {
+            return Ok(frame);
+        }
+        if self.uses_wait {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "needs wait()"));
+        }
+        if self.fill.needs_wakeup() {
+            let res = unsafe {
+                libc::recvfrom(
+                    self.fd,
+                    ptr::null_mut(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+            if res == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::WouldBlock {
+                    return Err(err);
+                }
+            }
+        }
+        match self.next_rx() {
+            Some(frame) => Ok(frame),
+            None => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "next call may have success",
+            )),
+        }
+    }
+
+    pub fn send_ready(&mut self) -> io::Result<()> {
+        self.reclaim();
+        if self.tx.free() == 0 || self.umem.lock().unwrap().free.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "tx ring full"));
+        }
+        Ok(())
+    }
+
+    fn after_tx(&mut self) -> Result<()> {
+        if !self.uses_wait || self.tx.needs_wakeup() || self.tx.free() == 0 {
+            self.kick()?;
+        }
+        Ok(())
+    }
+
+    pub fn send<R, F>(&mut self, packet_size: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> Result<R>,
+    {
+        let result = self.fill_tx(packet_size, f)?;
+        self.after_tx()?;
+        result
+    }
+
+    /// Copies up to `bufs.len()` frames out of the RX ring, their UMEM frames
+    /// are reused for RX right away.
+    pub fn recv_batch(&mut self, bufs: &mut [FrameBuf]) -> io::Result<usize> {
+        let received = recv_each(bufs, |buf| {
+            self.recv()?;
+            self.with_last_rx(|frame| buf.copy_from(frame));
+            Ok(())
+        });
+        self.release_rx();
+        received
+    }
+
+    /// Queues the frames on the TX ring and wakes up the kernel once.
+    pub fn send_batch(&mut self, frames: &[FrameBuf]) -> io::Result<usize> {
+        let mut sent = 0;
+        for frame in frames {
+            let result = self.fill_tx(frame.len(), |buf| buf.copy_from_slice(frame.as_slice()));
+            match result {
+                Ok(()) => sent += 1,
+                Err(_) if sent > 0 => break,
+                Err(err) => return Err(to_io_error(err)),
+            }
+        }
+        self.kick().map_err(to_io_error)?;
+        Ok(sent)
+    }
+
+    /// Lets `f` write the frame into a free UMEM frame and queues it on the TX
+    /// ring without waking up the kernel, an error means that no frame was queued.
+    fn fill_tx<R, F>(&mut self, packet_size: usize, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        if packet_size > self.frame_size() {
+            return Err(Error::Truncated);
+        }
+        if self.send_ready().is_err() {
+            self.kick()?;
+            if self.send_ready().is_err() {
+                return Err(Error::Exhausted);
+            }
+        }
+        let (addr, slice) = {
+            // sockets sharing the UMEM may have taken the free frames meanwhile
+            let mut umem = self.umem.lock().unwrap();
+            let addr = match umem.free.pop() {
+                Some(addr) => addr,
+                None => return Err(Error::Exhausted),
+            };
+            umem.lend(addr, self.id);
+            (addr, unsafe { umem.slice(addr, packet_size) })
+        };
+        let result = f(slice); // invoke closure
+        self.tx.push(xdp_desc {
+            addr,
+            len: packet_size as u32,
+            options: 0,
+        });
+        self.stats.tx(packet_size);
+        Ok(result)
+    }
+
+    pub fn shares_umem(&self, other: &XskDesc) -> bool {
+        Arc::ptr_eq(&self.umem, &other.umem)
+    }
+
+    /// Passes the frame last received to `f`, e.g. to record it before it is
+    /// forwarded with `zc_forward`.
+    pub fn with_last_rx<R, F>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let frame = self.zc_rx_frame?;
+        let umem = self.umem.lock().unwrap();
+        Some(f(unsafe { umem.slice(frame.addr, frame.len as usize) }))
+    }
+
+    pub fn zc_forward(&mut self, from: &mut XskDesc) -> Result<()> {
+        if !self.shares_umem(from) {
+            return Err(Error::Illegal);
+        }
+        let RxFrame { addr, len, .. } = match from.zc_rx_frame {
+            Some(frame) => frame,
+            None => return Err(Error::Illegal),
+        };
+        self.reclaim();
+        if self.tx.free() == 0 {
+            self.kick()?;
+            if self.tx.free() == 0 {
+                return Err(Error::Exhausted);
+            }
+        }
+        // the frame now belongs to the TX ring and returns via the completion ring
+        from.zc_rx_frame = None;
+        self.umem.lock().unwrap().lend(addr, self.id);
+        self.tx.push(xdp_desc {
+            addr,
+            len,
+            options: 0,
+        });
+        self.stats.tx(len as usize);
+        self.stats.zc_forwards += 1;
+        self.after_tx()
+    }
+}
+
+impl Drop for XskDesc {
+    fn drop(&mut self) {
+        {
+            // no other socket may attach while the last one detaches the program
+            let _programs = PROGRAMS.lock().unwrap();
+            self.prog = None;
+        }
+        // the kernel stops using the frames of the rings once the socket is
+        // closed, then they can return to a UMEM shared with other sockets
+        let mut umem = self.umem.lock().unwrap();
+        unsafe {
+            libc::close(self.fd);
+        }
+        umem.give_back_all(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ring in anonymous memory, with the producer, consumer and flags in
+    /// separate cache lines like the kernel lays them out.
+    fn ring(size: u32, desc_size: usize) -> Ring {
+        let off = xdp_ring_offset {
+            producer: 0,
+            consumer: 64,
+            flags: 128,
+            desc: 192,
+        };
+        let map_len = off.desc as usize + size as usize * desc_size;
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(map, libc::MAP_FAILED);
+        let map = map as *mut u8;
+        unsafe {
+            Ring {
+                map,
+                map_len,
+                producer: map.add(off.producer as usize) as *const AtomicU32,
+                consumer: map.add(off.consumer as usize) as *const AtomicU32,
+                flags: map.add(off.flags as usize) as *const AtomicU32,
+                desc: map.add(off.desc as usize),
+                size,
+            }
+        }
+    }
+
+    #[test]
+    fn ring_is_filled_and_drained_in_order() {
+        let ring = ring(4, 8);
+        assert_eq!((ring.available(), ring.free()), (0, 4));
+        assert_eq!(ring.pop::<u64>(), None);
+        for i in 0..4u64 {
+            assert!(ring.push(i));
+        }
+        assert!(!ring.push(4u64));
+        assert_eq!((ring.available(), ring.free()), (4, 0));
+        assert_eq!(ring.pop::<u64>(), Some(0));
+        assert_eq!((ring.available(), ring.free()), (3, 1));
+        assert!(ring.push(4u64));
+        let drained: Vec<u64> = (0..4).filter_map(|_| ring.pop()).collect();
+        assert_eq!(drained, vec![1, 2, 3, 4]);
+        assert_eq!(ring.pop::<u64>(), None);
+        assert!(!ring.needs_wakeup());
+    }
+
+    #[test]
+    fn ring_indices_wrap_around() {
+        let ring = ring(4, 8);
+        // the indices run freely and are only masked for the entries
+        let start = u32::MAX - 1;
+        ring.producer().store(start, Ordering::Relaxed);
+        ring.consumer().store(start, Ordering::Relaxed);
+        for i in 0..4u64 {
+            assert!(ring.push(i));
+        }
+        assert_eq!(ring.producer().load(Ordering::Relaxed), 2);
+        assert_eq!((ring.available(), ring.free()), (4, 0));
+        assert!(!ring.push(4u64));
+        unsafe {
+            assert_eq!(*ring.entry::<u64>(start), 0);
+            assert_eq!(*ring.entry::<u64>(u32::MAX), 1);
+            assert_eq!(*ring.entry::<u64>(0), 2);
+        }
+        let drained: Vec<u64> = (0..4).filter_map(|_| ring.pop()).collect();
+        assert_eq!(drained, vec![0, 1, 2, 3]);
+        assert_eq!(ring.consumer().load(Ordering::Relaxed), 2);
+        assert_eq!((ring.available(), ring.free()), (0, 4));
+    }
+
+    #[test]
+    fn ring_entries_use_the_descriptor_size() {
+        let ring = ring(2, mem::size_of::<xdp_desc>());
+        let desc = xdp_desc {
+            addr: 4096,
+            len: 60,
+            options: 0,
+        };
+        assert!(ring.push(desc));
+        assert!(ring.push(xdp_desc { addr: 8192, ..desc }));
+        assert!(!ring.push(desc));
+        let first = ring.pop::<xdp_desc>().unwrap();
+        assert_eq!((first.addr, first.len), (4096, 60));
+        assert_eq!(ring.pop::<xdp_desc>().unwrap().addr, 8192);
+    }
+
+    #[test]
+    fn frames_lent_to_a_closed_socket_return_to_the_umem() {
+        let config = XdpConfig {
+            frame_count: 8,
+            ring_size: 4,
+            ..XdpConfig::default()
+        };
+        let mut umem = Umem::new(&config).unwrap();
+        let a = umem.free.pop().unwrap();
+        let b = umem.free.pop().unwrap();
+        let c = umem.free.pop().unwrap();
+        umem.lend(a, 1);
+        umem.lend(b, 2);
+        umem.lend(c, 1);
+        umem.give_back(a + 100);
+        assert_eq!(umem.free.len(), 6);
+        umem.give_back_all(1);
+        assert_eq!(umem.free.len(), 7);
+        assert!(umem.free.contains(&a) && umem.free.contains(&c));
+        assert!(!umem.free.contains(&b));
+        umem.give_back_all(1);
+        assert_eq!(umem.free.len(), 7);
+    }
+
+    #[test]
+    fn lent_frames_are_taken_over_with_the_umem() {
+        let config = XdpConfig {
+            frame_size: 2048,
+            frame_count: 16,
+            ring_size: 4,
+            ..XdpConfig::default()
+        };
+        let mut umem = Umem::new(&config).unwrap();
+        for addr in &[0, 2048, 9 * 2048] {
+            umem.lend(*addr, 1);
+        }
+        umem.lend(4 * 2048, 2);
+        let lent = umem.lent_bitmap(1);
+        assert_eq!(lent, vec![0b0000_0011, 0b0000_0010]);
+
+        let too_large = XdpConfig {
+            frame_count: 32,
+            ..config
+        };
+        let fd = unsafe { libc::dup(umem.fd) };
+        let err = Umem::from_fd(fd, &too_large).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let fd = unsafe { libc::dup(umem.fd) };
+        let mut other = Umem::from_fd(fd, &config).unwrap();
+        other.lend_bitmap(&lent, 7);
+        assert_eq!(other.free.len(), 13);
+        assert!(!other.free.contains(&(9 * 2048)));
+        assert!(other.free.contains(&(4 * 2048)));
+        assert_eq!(other.lent_bitmap(7), lent);
+        // both map the same frames
+        unsafe { umem.slice(2048, 4)[0] = 42 };
+        assert_eq!(unsafe { other.slice(2048, 4)[0] }, 42);
+    }
+}